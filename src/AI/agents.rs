@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+
+use log::info;
+use rand::{seq::SliceRandom, Rng, RngCore};
+use serde::Deserialize;
+
+use crate::{
+    domain::{spendable, try_pay, NoCoin, Network, NodeId, SignedTransaction},
+    price::Quote,
+};
+
+const DEFAULT_FEE: f32 = 0.1;
+
+/// Ammounts are kept to whole cents of NoCoin.
+fn cents(ammount: f64) -> NoCoin {
+    NoCoin(((ammount * 100.).round() / 100.) as f32)
+}
+
+/// What an agent knows when it's its turn.
+pub struct Market<'a> {
+    pub own: NodeId,
+    /// Confirmed balance of the node's accounts less what their pending payments already spend.
+    pub available: NoCoin,
+    pub peers: &'a [NodeId],
+    /// Peers known to sell something, payers like to shop there.
+    pub merchants: &'a [NodeId],
+    pub quote: Option<Quote>,
+}
+
+impl Market<'_> {
+    fn random_peer(&self, rng: &mut dyn RngCore) -> Option<NodeId> {
+        self.peers.choose(rng).copied()
+    }
+
+    /// Payment of `ammount` to `to`, if there is anyone to pay and the node can afford it.
+    fn pay(&self, to: Option<NodeId>, ammount: NoCoin, fee: NoCoin) -> Option<Order> {
+        let to = to?;
+        (ammount.0 > 0. && ammount + fee <= self.available && to != self.own).then_some(Order { to, ammount, fee })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub to: NodeId,
+    pub ammount: NoCoin,
+    pub fee: NoCoin,
+}
+
+/// Behaviour of one agent. It is asked for orders every turn, the node signs
+/// and gossips them, so a strategy never touches keys or the network itself.
+pub trait Strategy: Send {
+    fn act(&mut self, market: &Market, rng: &mut dyn RngCore) -> Vec<Order>;
+}
+
+/// Pays a random ammount now and then, to a merchant or to anybody.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RandomPayer {
+    /// Chance of paying on each turn.
+    pub chance: f64,
+    pub max_ammount: f64,
+    /// Chance the payment goes to a merchant, when there are any.
+    pub shopping: f64,
+    pub fee: NoCoin,
+}
+
+impl Default for RandomPayer {
+    fn default() -> Self {
+        Self {
+            chance: 0.3,
+            max_ammount: 2.,
+            shopping: 0.5,
+            fee: NoCoin(DEFAULT_FEE),
+        }
+    }
+}
+
+impl Strategy for RandomPayer {
+    fn act(&mut self, market: &Market, rng: &mut dyn RngCore) -> Vec<Order> {
+        if !rng.gen_bool(self.chance.clamp(0., 1.)) {
+            return vec![];
+        }
+        let to = match market.merchants.iter().filter(|m| **m != market.own).collect::<Vec<_>>() {
+            merchants if !merchants.is_empty() && rng.gen_bool(self.shopping.clamp(0., 1.)) => {
+                merchants.choose(rng).map(|m| **m)
+            }
+            _ => market.random_peer(rng),
+        };
+        let ammount = cents(rng.gen_range(0.0..self.max_ammount.max(0.01)));
+        market.pay(to, ammount, self.fee).into_iter().collect()
+    }
+}
+
+/// Receives payments and passes a part of everything it earned since its last
+/// turn to a random peer, its supplier. The rest is its margin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Merchant {
+    pub payout: f64,
+    pub fee: NoCoin,
+    #[serde(skip)]
+    last_available: Option<NoCoin>,
+}
+
+impl Default for Merchant {
+    fn default() -> Self {
+        Self {
+            payout: 0.6,
+            fee: NoCoin(DEFAULT_FEE),
+            last_available: None,
+        }
+    }
+}
+
+impl Strategy for Merchant {
+    fn act(&mut self, market: &Market, rng: &mut dyn RngCore) -> Vec<Order> {
+        let earned = market.available.0 - self.last_available.unwrap_or(market.available).0;
+        let order = market.pay(market.random_peer(rng), cents(earned as f64 * self.payout), self.fee);
+        let spent = order.as_ref().map(|o| o.ammount + o.fee).unwrap_or(NoCoin(0.));
+        self.last_available = Some(market.available - spent);
+        order.into_iter().collect()
+    }
+}
+
+/// Never spends below its reserve and rarely spends a small part of what is above it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Saver {
+    pub reserve: NoCoin,
+    pub chance: f64,
+    pub spend_share: f64,
+    pub fee: NoCoin,
+}
+
+impl Default for Saver {
+    fn default() -> Self {
+        Self {
+            reserve: NoCoin(50.),
+            chance: 0.1,
+            spend_share: 0.1,
+            fee: NoCoin(DEFAULT_FEE),
+        }
+    }
+}
+
+impl Strategy for Saver {
+    fn act(&mut self, market: &Market, rng: &mut dyn RngCore) -> Vec<Order> {
+        let spare = (market.available - self.reserve - self.fee).0;
+        if spare <= 0. || !rng.gen_bool(self.chance.clamp(0., 1.)) {
+            return vec![];
+        }
+        let ammount = cents(spare as f64 * self.spend_share);
+        market.pay(market.random_peer(rng), ammount, self.fee).into_iter().collect()
+    }
+}
+
+/// Watches NoCoin's price from the oracle. When it is dearer than its recent
+/// average by `threshold`, sells `share` of its coins to a random peer, who pays
+/// in dollars off the chain. When it is cheap the speculator holds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Speculator {
+    /// Turns the average is taken over.
+    pub window: usize,
+    pub threshold: f64,
+    pub share: f64,
+    pub fee: NoCoin,
+    #[serde(skip)]
+    prices: VecDeque<f64>,
+}
+
+impl Default for Speculator {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            threshold: 0.02,
+            share: 0.5,
+            fee: NoCoin(DEFAULT_FEE),
+            prices: VecDeque::new(),
+        }
+    }
+}
+
+impl Strategy for Speculator {
+    fn act(&mut self, market: &Market, rng: &mut dyn RngCore) -> Vec<Order> {
+        let Some(quote) = market.quote else {
+            return vec![];
+        };
+        let full = self.prices.len() >= self.window.max(1);
+        let average = self.prices.iter().sum::<f64>() / self.prices.len().max(1) as f64;
+        self.prices.push_back(quote.nocoin_usd);
+        if self.prices.len() > self.window.max(1) {
+            self.prices.pop_front();
+        }
+        if !full || quote.nocoin_usd <= average * (1. + self.threshold) {
+            return vec![];
+        }
+        let ammount = cents((market.available - self.fee).0 as f64 * self.share);
+        market.pay(market.random_peer(rng), ammount, self.fee).into_iter().collect()
+    }
+}
+
+/// One agent of a node's mix, as configured in `NOCOIN_AGENTS` or a scenario.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AgentSpec {
+    RandomPayer(RandomPayer),
+    Merchant(Merchant),
+    Saver(Saver),
+    Speculator(Speculator),
+}
+
+impl AgentSpec {
+    pub fn is_merchant(&self) -> bool {
+        matches!(self, AgentSpec::Merchant(_))
+    }
+
+    pub fn strategy(&self) -> Box<dyn Strategy> {
+        match self.clone() {
+            AgentSpec::RandomPayer(agent) => Box::new(agent),
+            AgentSpec::Merchant(agent) => Box::new(agent),
+            AgentSpec::Saver(agent) => Box::new(agent),
+            AgentSpec::Speculator(agent) => Box::new(agent),
+        }
+    }
+}
+
+/// Every agent of the node takes its turn, their payments are signed by the
+/// node's wallet, or the node itself without one, and returned for gossiping. Orders the node can't make are skipped.
+pub fn trade(
+    network: &mut Network,
+    agents: &mut [Box<dyn Strategy>],
+    merchants: &[NodeId],
+    quote: Option<Quote>,
+    rng: &mut dyn RngCore,
+) -> Vec<SignedTransaction> {
+    let peers: Vec<_> = network.other_nodes().map(|n| n.id).collect();
+    let mut signed = vec![];
+    for agent in agents.iter_mut() {
+        let market = Market {
+            own: network.user.node.id,
+            available: spendable(network),
+            peers: &peers,
+            merchants,
+            quote,
+        };
+        for Order { to, ammount, fee } in agent.act(&market, rng) {
+            match try_pay(network, to, ammount, fee, None) {
+                Ok(payments) => signed.extend(payments.into_iter().map(|(_, payment)| payment)),
+                Err(e) => info!("Agent couldn't pay {:?} to {:?}: {}", ammount, to, e),
+            }
+        }
+    }
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    fn market<'a>(available: f32, peers: &'a [NodeId], merchants: &'a [NodeId], nocoin_usd: f64) -> Market<'a> {
+        Market {
+            own: NodeId(1),
+            available: NoCoin(available),
+            peers,
+            merchants,
+            quote: Some(Quote { bitcoin_usd: nocoin_usd * 1000., nocoin_usd }),
+        }
+    }
+
+    #[test]
+    fn payers_shop_at_merchants_within_their_means() {
+        let peers = [NodeId(2), NodeId(3), NodeId(4)];
+        let mut payer = RandomPayer { chance: 1., shopping: 0.8, ..RandomPayer::default() };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        let orders: Vec<_> = (0..1_000)
+            .flat_map(|_| payer.act(&market(100., &peers, &[NodeId(4)], 1.), &mut rng))
+            .collect();
+        let broke = payer.act(&market(0.05, &peers, &[], 1.), &mut rng);
+
+        let shopped = orders.iter().filter(|o| o.to == NodeId(4)).count() as f64 / orders.len() as f64;
+        assert!((shopped - (0.8 + 0.2 / 3.)).abs() < 0.05, "Shopped {}", shopped);
+        assert!(orders.iter().all(|o| o.ammount.0 > 0. && o.ammount.0 <= 2.));
+        assert!(broke.is_empty());
+    }
+
+    #[test]
+    fn merchant_passes_on_part_of_its_income() {
+        let peers = [NodeId(2)];
+        let mut merchant = Merchant::default();
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        let first = merchant.act(&market(10., &peers, &[], 1.), &mut rng);
+        let paid = merchant.act(&market(20., &peers, &[], 1.), &mut rng);
+
+        assert!(first.is_empty());
+        assert_eq!(paid, vec![Order { to: NodeId(2), ammount: NoCoin(6.), fee: NoCoin(DEFAULT_FEE) }]);
+        assert!(merchant.act(&market(13.9, &peers, &[], 1.), &mut rng).is_empty());
+    }
+
+    #[test]
+    fn saver_keeps_its_reserve() {
+        let peers = [NodeId(2)];
+        let mut saver = Saver { chance: 1., ..Saver::default() };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        assert!(saver.act(&market(50., &peers, &[], 1.), &mut rng).is_empty());
+        let orders = saver.act(&market(80.1, &peers, &[], 1.), &mut rng);
+
+        assert_eq!(orders[0].ammount, NoCoin(3.));
+    }
+
+    #[test]
+    fn speculator_sells_into_rallies() {
+        let peers = [NodeId(2)];
+        let mut speculator = Speculator { window: 3, ..Speculator::default() };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut act = |price| speculator.act(&market(10.1, &peers, &[], price), &mut rng);
+
+        let quiet: Vec<_> = [1., 1., 1., 1.01, 0.5].into_iter().flat_map(&mut act).collect();
+        let rally = act(1.2);
+
+        assert!(quiet.is_empty());
+        assert_eq!(rally, vec![Order { to: NodeId(2), ammount: NoCoin(5.), fee: NoCoin(DEFAULT_FEE) }]);
+    }
+
+    #[test]
+    fn mix_reads_from_json() {
+        let mix: Vec<AgentSpec> = serde_json::from_str(
+            r#"[{"kind": "random_payer", "chance": 0.5}, {"kind": "merchant"}, {"kind": "speculator", "window": 5}]"#,
+        )
+        .unwrap();
+
+        assert!(matches!(&mix[0], AgentSpec::RandomPayer(p) if p.chance == 0.5 && p.max_ammount == 2.));
+        assert!(mix[1].is_merchant());
+        assert!(serde_json::from_str::<Vec<AgentSpec>>(r#"[{"kind": "saver", "chance": 0.5, "typo": 1}]"#).is_err());
+    }
+}
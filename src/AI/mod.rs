@@ -1,18 +1,220 @@
-use std::{net::SocketAddr, sync::Arc};
+mod agents;
 
-use anyhow::{anyhow, Result};
+use std::{fs, io::Write, net::SocketAddr, os::unix::fs::OpenOptionsExt, path::PathBuf, sync::Arc};
 
-use log::info;
+use anyhow::{anyhow, bail, Result};
+
+use log::{info, warn};
+use rand::{rngs::{OsRng, StdRng}, RngCore, SeedableRng};
 use tokio::{sync::Mutex, try_join};
 
 use crate::{
-    domain::{try_adopt_network, try_start_new_network, Network, try_adopt_pending_transactions, generate_key, try_mine_any_async, try_add_block, create_mined_block, create_mining_reward, ProvenTransaction, NodeId},
-    web::{get_chain, register_node, run, get_pending_transactions, send_new_block},
+    domain::{try_adopt_network, try_start_new_network, Network, try_adopt_pending_transactions, try_mine_any_async, try_add_block, create_mined_block, create_mining_reward, ProvenTransaction, NodeId, record_heartbeat, record_missed_heartbeat, Reputation, ReputationConfig, seal, Node, ChainParams, Keystore, KdfParams, PrivKey, PubKey, HdWallet, mineable_transactions, Draft},
+    price::{
+        serve_ticker, FixedFeed, HttpFeed, Model, NoCoinPrice, Peg, PriceFeed, PriceOracle,
+        SyntheticFeed,
+    },
+    transport::{HttpTransport, PeerTransport, TcpTransport, Transport},
+    web::{register_node, run, AdminToken},
 };
 
+pub use agents::{trade, AgentSpec, Strategy};
+
+const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+const AGENT_INTERVAL_SECS: u64 = 15;
+const KEYSTORE_DIR: &str = "keys";
+const PASSPHRASE_VAR: &str = "NOCOIN_PASSPHRASE";
+const SEED_PHRASE_VAR: &str = "NOCOIN_SEED_PHRASE";
+const ADMIN_TOKEN_VAR: &str = "NOCOIN_ADMIN_TOKEN";
+const PRICE_FEED_VAR: &str = "NOCOIN_PRICE_FEED";
+const PRICE_SEED_VAR: &str = "NOCOIN_PRICE_SEED";
+const PRICE_MODEL_VAR: &str = "NOCOIN_PRICE_MODEL";
+const AGENTS_VAR: &str = "NOCOIN_AGENTS";
+const MERCHANTS_VAR: &str = "NOCOIN_MERCHANTS";
+const BITCOIN_START_USD: f64 = 30_000.;
+const BITCOIN_MODEL: Model = Model::Gbm { drift: 0., volatility: 0.02 };
+const NOCOIN_PEG: Peg = Peg::Correlated { ratio: 0.001, correlation: 0.8, volatility: 0.02 };
+
+fn keystore_path(addr: SocketAddr) -> PathBuf {
+    PathBuf::from(KEYSTORE_DIR).join(format!("node-{}.json", addr.port()))
+}
+
+fn wallet_path(addr: SocketAddr) -> PathBuf {
+    PathBuf::from(KEYSTORE_DIR).join(format!("wallet-{}.json", addr.port()))
+}
+
+fn admin_token_path(addr: SocketAddr) -> PathBuf {
+    PathBuf::from(KEYSTORE_DIR).join(format!("admin-{}.token", addr.port()))
+}
+
+/// Taken from `NOCOIN_ADMIN_TOKEN`, otherwise generated once and kept next to the keys,
+/// readable only by the owner.
+fn load_admin_token(addr: SocketAddr) -> Result<AdminToken> {
+    if let Ok(token) = std::env::var(ADMIN_TOKEN_VAR) {
+        return Ok(AdminToken(token));
+    }
+    let path = admin_token_path(addr);
+    if path.exists() {
+        return Ok(AdminToken(fs::read_to_string(&path)?.trim().to_owned()));
+    }
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    fs::create_dir_all(KEYSTORE_DIR)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(token.as_bytes())?;
+    info!("Created admin token in {:?}", path);
+    Ok(AdminToken(token))
+}
+
+fn price_seed() -> Result<u64> {
+    match std::env::var(PRICE_SEED_VAR) {
+        Ok(seed) => Ok(seed.parse()?),
+        Err(_) => Ok(0),
+    }
+}
+
+/// `NOCOIN_PRICE_MODEL` is a JSON model like `{"kind": "random_walk", "step": 500}`,
+/// by default a geometric Brownian motion.
+fn price_model() -> Result<Model> {
+    match std::env::var(PRICE_MODEL_VAR) {
+        Ok(model) => serde_json::from_str(&model).map_err(|e| anyhow!("Invalid {}: {}", PRICE_MODEL_VAR, e)),
+        Err(_) => Ok(BITCOIN_MODEL),
+    }
+}
+
+/// `NOCOIN_PRICE_FEED` is `live` for blockchain.info, `fixed:<usd>`, url of another
+/// ticker like `mock-ticker`, or by default prices generated from `NOCOIN_PRICE_SEED`
+/// and `NOCOIN_PRICE_MODEL`.
+fn load_price_oracle() -> Result<PriceOracle> {
+    let seed = price_seed()?;
+    let feed: Arc<dyn PriceFeed> = match std::env::var(PRICE_FEED_VAR).as_deref() {
+        Ok("live") => Arc::new(HttpFeed::default()),
+        Ok(url) if url.starts_with("http") => Arc::new(HttpFeed::new(url)),
+        Ok(fixed) if fixed.starts_with("fixed:") => Arc::new(FixedFeed(fixed["fixed:".len()..].parse()?)),
+        Ok("synthetic") | Err(_) => Arc::new(SyntheticFeed::new(seed, BITCOIN_START_USD, price_model()?)),
+        Ok(other) => return Err(anyhow!("Unknown price feed {:?}", other)),
+    };
+    Ok(PriceOracle::new(feed, NoCoinPrice::new(seed, NOCOIN_PEG)))
+}
+
+/// `NOCOIN_AGENTS` is a JSON list of agents like `[{"kind": "random_payer", "chance": 0.5}]`,
+/// without it the node only mines.
+fn load_agents() -> Result<Vec<AgentSpec>> {
+    match std::env::var(AGENTS_VAR) {
+        Ok(agents) => serde_json::from_str(&agents).map_err(|e| anyhow!("Invalid {}: {}", AGENTS_VAR, e)),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// `NOCOIN_MERCHANTS` lists ids of merchant nodes separated by commas.
+fn load_merchants() -> Result<Vec<NodeId>> {
+    match std::env::var(MERCHANTS_VAR) {
+        Ok(merchants) => merchants
+            .split(',')
+            .map(|id| Ok(NodeId(id.trim().parse()?)))
+            .collect(),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Serves generated bitcoin prices in blockchain.info's format, so nodes can
+/// point `NOCOIN_PRICE_FEED` at it without the internet.
+pub async fn serve_mock_ticker(addr: SocketAddr) -> Result<()> {
+    let feed = SyntheticFeed::new(price_seed()?, BITCOIN_START_USD, price_model()?);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Serving mock ticker on http://{}/ticker", addr);
+    serve_ticker(listener, Arc::new(feed)).await
+}
+
+/// Without `NOCOIN_PASSPHRASE` only keystores sealed with an empty passphrase open.
+fn passphrase() -> String {
+    std::env::var(PASSPHRASE_VAR).unwrap_or_else(|_| {
+        warn!("{} is not set, only an unprotected keystore can be opened", PASSPHRASE_VAR);
+        String::new()
+    })
+}
+
+/// Keys and wallets are never created unprotected.
+fn new_passphrase() -> Result<String> {
+    let passphrase = passphrase();
+    if passphrase.is_empty() {
+        bail!("Set {} to a passphrase protecting the new keys", PASSPHRASE_VAR)
+    }
+    Ok(passphrase)
+}
+
+/// Node keeps its key between restarts, a new one is generated only on first start.
+fn load_identity(addr: SocketAddr) -> Result<(PrivKey, PubKey)> {
+    let path = keystore_path(addr);
+    let mut keystore = if path.exists() {
+        let mut keystore = Keystore::load(&path)?;
+        keystore.unlock(passphrase().as_bytes())?;
+        keystore
+    } else {
+        let algorithm = ChainParams::default().signature_algorithm;
+        Keystore::create(&path, new_passphrase()?.as_bytes(), algorithm, KdfParams::default())?
+    };
+    info!("Using key {:?} from {:?}", keystore.key_id(), path);
+    let private = keystore.private_key()?;
+    let public = keystore.public_key().clone();
+    keystore.lock();
+    Ok((private, public))
+}
+
+/// Wallet is restored from `NOCOIN_SEED_PHRASE` if it's set and there is no wallet yet.
+fn load_wallet(addr: SocketAddr) -> Result<HdWallet> {
+    let path = wallet_path(addr);
+    let algorithm = ChainParams::default().signature_algorithm;
+    if path.exists() {
+        return HdWallet::load(&path, passphrase().as_bytes());
+    }
+    let wallet = match std::env::var(SEED_PHRASE_VAR) {
+        Ok(phrase) => HdWallet::restore(&phrase, algorithm)?,
+        Err(_) => {
+            let wallet = HdWallet::generate(algorithm)?;
+            warn!("Created new wallet, write down its seed phrase: {}", wallet.seed_phrase()?);
+            wallet
+        }
+    };
+    wallet.save(&path, new_passphrase()?.as_bytes(), KdfParams::default())?;
+    Ok(wallet)
+}
+
+async fn open_wallet(addr: SocketAddr, network: Arc<Mutex<Network>>) -> Result<()> {
+    let mut wallet = load_wallet(addr)?;
+    let mut network = network.lock().await;
+    wallet.scan(&network.blockchain)?;
+    wallet.save(&wallet_path(addr), passphrase().as_bytes(), KdfParams::default())?;
+    info!(
+        "Wallet has {} accounts with {:?} in total",
+        wallet.accounts().len(),
+        wallet.balance(&network.blockchain)
+    );
+    network.user.wallet = Some(wallet);
+    Ok(())
+}
+
+pub fn rotate_key(addr: SocketAddr) -> Result<()> {
+    let path = keystore_path(addr);
+    let mut keystore = Keystore::load(&path)?;
+    let retired = keystore.key_id().clone();
+    let current = keystore.rotate(new_passphrase()?.as_bytes())?;
+    info!("Rotated key {:?} to {:?} in {:?}", retired, current, path);
+    Ok(())
+}
+
+/// Registration always goes over HTTP, so does the initial download of the chain.
 async fn initialize_network(client: reqwest::Client, addr: SocketAddr) -> Result<Network> {
-    let (private, public) = generate_key()?;
-    match register_node(client, &addr, &public).await {
+    let bootstrap = HttpTransport::new(client.clone());
+    let (private, public) = load_identity(addr)?;
+    let own_node = Node { id: NodeId(addr.port().into()), addr, pub_key: public.clone() };
+    let registration = seal(own_node.id, &private, own_node)?;
+    match register_node(client, &registration).await {
         Ok(nodes) => {
             info!(
                 "Node succesfully registered. Received {} nodes.",
@@ -21,10 +223,10 @@ async fn initialize_network(client: reqwest::Client, addr: SocketAddr) -> Result
             let node_to_talk = nodes
                 .first()
                 .ok_or(anyhow!("Received empty nodes from register"))?;
-            let blockchain = get_chain(node_to_talk).await?;
+            let blockchain = bootstrap.get_chain(node_to_talk).await?;
             info!("Received blockchain: {:?}", blockchain);
-            let mut network = try_adopt_network(addr, private, public, nodes, blockchain)?;
-            let transactions = get_pending_transactions(network.nodes.first().unwrap()).await?;
+            let mut network = try_adopt_network(addr, private, public, nodes, blockchain, ChainParams::default())?;
+            let transactions = bootstrap.get_pending_transactions(network.nodes.first().unwrap()).await?;
             info!("Received pending transactions: {:?}", transactions);
             try_adopt_pending_transactions(&mut network, transactions)?;
             Ok(network)
@@ -39,33 +241,72 @@ async fn initialize_network(client: reqwest::Client, addr: SocketAddr) -> Result
     }
 }
 
-async fn mining_neccesities(network: Arc<Mutex<Network>>) -> (Vec<ProvenTransaction>, NodeId) {
-    let network = network.lock().await;
-    (network.transactions_poll.clone(), network.user.node.id.clone())
+/// Rewards go to the wallet when there is one, fees always go to the node.
+fn reward_address(network: &mut Network) -> Result<NodeId> {
+    match network.user.wallet.as_mut() {
+        Some(wallet) => wallet.receive_address(&network.blockchain),
+        None => Ok(network.user.node.id),
+    }
+}
+
+async fn mining_neccesities(network: Arc<Mutex<Network>>) -> (Draft, Vec<ProvenTransaction>, NodeId, NodeId, u8) {
+    let mut network = network.lock().await;
+    let reward_to = reward_address(&mut network).unwrap_or_else(|e| {
+        info!("Couldn't derive wallet account, rewarding the node. Reason: {}", e);
+        network.user.node.id
+    });
+    (
+        Draft::on(network.blockchain.last_block()),
+        mineable_transactions(&network),
+        network.user.node.id,
+        reward_to,
+        network.params.mining_difficulty,
+    )
 }
 
 
-async fn mine_from_time_to_time(client: reqwest::Client, network: Arc<Mutex<Network>>) -> Result<()> {
+async fn mine_from_time_to_time(transport: Arc<dyn Transport>, network: Arc<Mutex<Network>>) -> Result<()> {
     tokio::task::spawn(async move {
         loop {
-            let (polled_transactions, user_id) = mining_neccesities(network.clone()).await;
-            let reward = create_mining_reward(user_id);
+            let (draft, polled_transactions, user_id, reward_to, difficulty) = mining_neccesities(network.clone()).await;
+            let reward = create_mining_reward(reward_to);
             //mining should be interrupted, if some other node mines a block
             let mining_result = async {
-                try_mine_any_async(5, &polled_transactions, &reward, user_id).await
+                try_mine_any_async(&draft, difficulty, &polled_transactions, &reward, user_id).await
             }.await;
             match mining_result {
                 Ok((hash, nonce, transactions)) => {
                     info!("Successfully mined block, nonce: {:?}", nonce);
+                    let network_handle = network.clone();
                     let mut network = network.lock().await;
-                    let last_block = network.blockchain.last_block();
-                    let mined_block = create_mined_block(last_block, hash, nonce, &transactions, user_id);
+                    let mined_block = create_mined_block(draft, hash, nonce, &transactions, user_id);
                     match try_add_block(&mut network, mined_block) {
                         Ok(()) => {
+                            if let Some(wallet) = network.user.wallet.as_ref() {
+                                info!("Wallet balance: {:?}", wallet.balance(&network.blockchain));
+                            }
                             let added_block = network.blockchain.last_block().clone();
                             let other_nodes: Vec<_> = network.other_nodes().cloned().collect();
+                            let announcement = seal(user_id, &network.user.priv_key, added_block);
                             drop(network);
-                            send_new_block(client.clone(), other_nodes, &added_block).await;
+                            let unreachable = match announcement {
+                                Ok(announcement) => transport.broadcast_block(&other_nodes, &announcement)
+                                    .await
+                                    .unwrap_or_else(|e| {
+                                        info!("Couldn't send mined block, reason: {}", e);
+                                        vec![]
+                                    }),
+                                Err(e) => {
+                                    info!("Couldn't sign mined block, reason: {}", e);
+                                    vec![]
+                                }
+                            };
+                            let mut network = network_handle.lock().await;
+                            for id in unreachable {
+                                if let Some(evicted) = record_missed_heartbeat(&mut network, &id) {
+                                    info!("Evicted unreachable node {:?}", evicted.id);
+                                }
+                            }
                         },
                         Err(e) => info!("Couldn't add mined block, reason: {}", e),
                     }
@@ -79,13 +320,114 @@ async fn mine_from_time_to_time(client: reqwest::Client, network: Arc<Mutex<Netw
     Ok(())
 }
 
-pub async fn start(addr: SocketAddr) -> Result<()> {
+async fn ping_from_time_to_time(transport: Arc<dyn Transport>, network: Arc<Mutex<Network>>) -> Result<()> {
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(HEARTBEAT_INTERVAL_SECS)).await;
+        let other_nodes: Vec<_> = network.lock().await.other_nodes().cloned().collect();
+        let pings = other_nodes
+            .iter()
+            .map(|node| async { (node.id, transport.ping(node).await) });
+        let results = futures::future::join_all(pings).await;
+        let mut network = network.lock().await;
+        for (id, result) in results {
+            match result {
+                Ok(()) => record_heartbeat(&mut network, &id),
+                Err(e) => match record_missed_heartbeat(&mut network, &id) {
+                    Some(evicted) => info!("Evicted unresponsive node {:?}", evicted.id),
+                    None => info!(
+                        "Node {:?} didn't answer ping ({} missed): {}",
+                        id,
+                        network.liveness.missed_heartbeats(&id),
+                        e
+                    ),
+                },
+            }
+        }
+    }
+}
+
+async fn trade_from_time_to_time(
+    transport: Arc<dyn Transport>,
+    network: Arc<Mutex<Network>>,
+    prices: Arc<PriceOracle>,
+    agents: Vec<AgentSpec>,
+    merchants: Vec<NodeId>,
+) -> Result<()> {
+    if agents.is_empty() {
+        return Ok(());
+    }
+    let mut strategies: Vec<_> = agents.iter().map(AgentSpec::strategy).collect();
+    let mut rng = StdRng::from_entropy();
+    info!("Running {} agents", strategies.len());
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(AGENT_INTERVAL_SECS)).await;
+        let quote = prices
+            .quote()
+            .await
+            .map_err(|e| info!("Agents trade without a price: {}", e))
+            .ok();
+        let mut network = network.lock().await;
+        let payments = trade(&mut network, &mut strategies, &merchants, quote, &mut rng);
+        let other_nodes: Vec<_> = network.other_nodes().cloned().collect();
+        drop(network);
+        for payment in payments {
+            if let Err(e) = transport.broadcast_transaction(&other_nodes, &payment).await {
+                info!("Couldn't send agent's payment, reason: {}", e);
+            }
+        }
+    }
+}
+
+async fn leave_network(transport: Arc<dyn Transport>, network: Arc<Mutex<Network>>) -> Result<()> {
+    let network = network.lock().await;
+    let other_nodes: Vec<_> = network.other_nodes().cloned().collect();
+    info!("Leaving network, announcing to {} nodes", other_nodes.len());
+    let leaving = seal(network.user.node.id, &network.user.priv_key, network.user.node.id)?;
+    transport.announce_leave(&other_nodes, &leaving).await
+}
+
+pub async fn start(addr: SocketAddr, peer_transport: PeerTransport) -> Result<()> {
     let client = reqwest::Client::new();
     let network  = Arc::new(Mutex::new(initialize_network(client.clone(), addr).await?));
+    open_wallet(addr, network.clone()).await?;
+
+    let reputation = Arc::new(std::sync::Mutex::new(Reputation::new(ReputationConfig::default())));
+    let tcp = match peer_transport {
+        PeerTransport::Http => None,
+        PeerTransport::Tcp => Some(TcpTransport::new(network.clone(), reputation.clone())),
+    };
+    let transport: Arc<dyn Transport> = match &tcp {
+        Some(tcp) => Arc::new(tcp.clone()),
+        None => Arc::new(HttpTransport::new(client)),
+    };
 
-    let run_server = run(addr, network.clone());
-    let mining = mine_from_time_to_time(client.clone(), network.clone());
+    let prices = Arc::new(load_price_oracle()?);
+    let run_server = run(
+        addr,
+        network.clone(),
+        reputation,
+        transport.clone(),
+        load_admin_token(addr)?,
+        prices.clone(),
+    );
+    let listen_tcp = async {
+        match &tcp {
+            Some(tcp) => tcp.listen(addr).await,
+            None => Ok(()),
+        }
+    };
+    let mining = mine_from_time_to_time(transport.clone(), network.clone());
+    let heartbeats = ping_from_time_to_time(transport.clone(), network.clone());
+    let trading = trade_from_time_to_time(
+        transport.clone(),
+        network.clone(),
+        prices,
+        load_agents()?,
+        load_merchants()?,
+    );
 
-    try_join!(run_server, mining)?;
-    Ok(())    
+    tokio::select! {
+        result = async { try_join!(run_server, listen_tcp, mining, heartbeats, trading) } => result.map(|_| ()),
+        _ = tokio::signal::ctrl_c() => leave_network(transport, network).await,
+    }
 }
@@ -3,15 +3,20 @@ use std::ops::{Add, AddAssign, Sub};
 use serde::{Deserialize, Serialize};
 
 use super::{
+    envelope::unix_timestamp,
     mining::{prove_mined_block, try_mine_any, BlockHash},
     network::NodeId,
-    transaction::ProvenTransaction,
+    transaction::{ProvenTransaction, Transaction},
 };
 
 use anyhow::{anyhow, bail, Result};
 
 pub const MAX_TRANSACTION_COUNT: usize = 10;
-const GENESIS_DIFFICULTY: u8 = 3;
+pub const GENESIS_DIFFICULTY: u8 = 3;
+/// A block's timestamp mustn't be below the median of this many blocks before it.
+const MEDIAN_TIME_SPAN: usize = 11;
+/// Nor this many seconds ahead of the local clock.
+const MAX_FUTURE_DRIFT_SECS: usize = 2 * 60 * 60;
 
 #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy, Debug)]
 pub struct NoCoin(pub f32);
@@ -57,14 +62,41 @@ pub struct BlockHeader {
     pub difficulty: u8,
 }
 
+/// Header fields the proof of work covers, they are fixed before mining starts.
+#[derive(Serialize, Debug, Clone)]
+pub struct Draft {
+    pub index: BlockIndex,
+    pub prev_hash: BlockHash,
+    pub timestamp: usize,
+}
+
+impl Draft {
+    /// Next block on top of `parent`.
+    pub fn on(parent: &Block) -> Self {
+        Self {
+            index: parent.header.index.next_index(),
+            prev_hash: parent.header.hash.clone(),
+            timestamp: current_timestamp(),
+        }
+    }
+
+    pub fn of(header: &BlockHeader) -> Self {
+        Self {
+            index: header.index.clone(),
+            prev_hash: header.prev_hash.clone(),
+            timestamp: header.timestamp,
+        }
+    }
+}
+
 impl BlockHeader {
-    pub fn new(prev_block: &Block, hash: BlockHash) -> Self {
+    pub fn new(draft: Draft, hash: BlockHash) -> Self {
         let difficulty = hash.0.chars().take_while(|&c| c == '0').count() as u8;
         Self {
-            index: prev_block.header.index.next_index(),
-            prev_hash: prev_block.header.hash.clone(),
+            index: draft.index,
+            prev_hash: draft.prev_hash,
             hash,
-            timestamp: current_timestamp(),
+            timestamp: draft.timestamp,
             difficulty,
         }
     }
@@ -83,28 +115,47 @@ pub struct Block {
 
 //Should be VerifiedBlockchain some day.
 //Currently get_chain is received with 'validated' transactions which doesn't have to be true
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Blockchain(pub Vec<Block>);
 
 impl Blockchain {
     pub fn last_block(&self) -> &Block {
         self.0.last().unwrap()
     }
+
+    pub fn height(&self) -> usize {
+        self.0.len().saturating_sub(1)
+    }
+
+    pub fn contains(&self, transaction: &Transaction) -> bool {
+        self.0.iter().flat_map(|b| &b.transactions.0).any(|t| t.transaction.0 == *transaction)
+    }
+
+    /// Whether `hashes` are those of the first blocks of the chain, so that
+    /// whatever was derived from them still holds.
+    pub fn starts_with(&self, hashes: &[BlockHash]) -> bool {
+        hashes.len() <= self.0.len() && hashes.iter().zip(&self.0).all(|(hash, block)| *hash == block.header.hash)
+    }
 }
 
 fn current_timestamp() -> usize {
-    std::time::Instant::now().elapsed().as_secs() as usize
+    unix_timestamp() as usize
 }
 
 pub fn genesis_block() -> Block {
-    let (hash, nonce) = try_mine_any(GENESIS_DIFFICULTY, &vec![])
+    let draft = Draft {
+        index: BlockIndex(0),
+        prev_hash: BlockHash::default(),
+        timestamp: current_timestamp(),
+    };
+    let (hash, nonce) = try_mine_any(&draft, GENESIS_DIFFICULTY, &[])
         .expect("Couldn't create genesis block. Aborting.");
     Block {
         header: BlockHeader {
-            index: BlockIndex(0),
-            prev_hash: BlockHash::default(),
+            index: draft.index,
+            prev_hash: draft.prev_hash,
             hash,
-            timestamp: current_timestamp(),
+            timestamp: draft.timestamp,
             difficulty: GENESIS_DIFFICULTY,
         },
         mined_by: NodeId(0),
@@ -113,6 +164,23 @@ pub fn genesis_block() -> Block {
     }
 }
 
+/// The miner picks the timestamp, it must be at least the median of the last
+/// blocks of `below`, which `block` extends, and not far ahead of `now`.
+pub(super) fn ensure_timely(below: &[Block], block: &Block, now: usize) -> Result<()> {
+    let timestamp = block.header.timestamp;
+    if timestamp > now + MAX_FUTURE_DRIFT_SECS {
+        bail!("Block {} is stamped {}, too far ahead of {}", block.header.index.0, timestamp, now)
+    }
+    let mut recent: Vec<_> = below.iter().rev().take(MEDIAN_TIME_SPAN).map(|b| b.header.timestamp).collect();
+    recent.sort_unstable();
+    match recent.get(recent.len() / 2) {
+        Some(median) if timestamp < *median => {
+            bail!("Block {} is stamped {}, before the median {} of the blocks below", block.header.index.0, timestamp, median)
+        }
+        _ => Ok(()),
+    }
+}
+
 fn has_valid_genesis_block(blockchain: &Blockchain) -> Result<()> {
     let genesis_block = blockchain
         .0
@@ -133,16 +201,13 @@ fn has_valid_genesis_block(blockchain: &Blockchain) -> Result<()> {
             genesis_block.header.difficulty
         )
     }
-    let _ = prove_mined_block(
-        &genesis_block.transactions.0,
-        genesis_block.header.difficulty,
-        genesis_block.nonce,
-    )?;
+    prove_mined_block(genesis_block, GENESIS_DIFFICULTY)?;
     Ok(())
 }
 
-pub fn verify_blockchain(blockchain: Blockchain) -> Result<Blockchain> {
-    let _ = has_valid_genesis_block(&blockchain)?;
+/// Blocks after genesis must be mined with at least `mining_difficulty`.
+pub fn verify_blockchain(blockchain: Blockchain, mining_difficulty: u8) -> Result<Blockchain> {
+    has_valid_genesis_block(&blockchain)?;
     let chain = &blockchain.0;
     for i in 1..blockchain.0.len() {
         let previous_hash = &chain[i - 1].header.hash;
@@ -153,14 +218,11 @@ pub fn verify_blockchain(blockchain: Blockchain) -> Result<Blockchain> {
                 block_to_verify
             )
         }
-        let _ = prove_mined_block(
-            &block_to_verify.transactions.0,
-            block_to_verify.header.difficulty,
-            block_to_verify.nonce,
-        )
-        .map_err(|_| {
+        ensure_timely(&chain[..i], block_to_verify, current_timestamp())?;
+        prove_mined_block(block_to_verify, mining_difficulty).map_err(|e| {
             anyhow!(
-                "Block is fake. Hash doesn't match its nonce. Invalid block: {:?}",
+                "Block is fake: {}. Invalid block: {:?}",
+                e,
                 block_to_verify
             )
         })?;
@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    blockchain::{Block, Blockchain, NoCoin},
+    mining::BlockHash,
+    network::{try_add_transaction, Network, NodeId},
+    serialization::serialize,
+    transaction::{approve, map_to_affordable, SignedTransaction, Transaction, TransactionId},
+};
+
+pub const MAX_CODE_LEN: usize = 1024;
+pub const MAX_STACK_DEPTH: usize = 256;
+pub const MAX_GAS: u64 = 100_000;
+/// Fee of a contract transaction buys gas at this price, unused gas isn't refunded.
+pub const GAS_PRICE: NoCoin = NoCoin(0.0001);
+
+const DEPLOY_GAS_PER_INSTR: u64 = 5;
+const LOAD_GAS: u64 = 10;
+const STORE_GAS: u64 = 50;
+const LOG_GAS: u64 = 10;
+
+/// Bytecode of the contract VM. It works on a stack of integers, all
+/// arithmetic wraps, so every node gets the same result.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Instr {
+    Push(i64),
+    Pop,
+    /// Copies the item this deep, 0 is the top.
+    Dup(u8),
+    /// Swaps the top with the item this deep.
+    Swap(u8),
+    Add,
+    Sub,
+    Mul,
+    /// Division and remainder by zero revert.
+    Div,
+    Mod,
+    Lt,
+    Gt,
+    Eq,
+    IsZero,
+    And,
+    Or,
+    Jump(u32),
+    /// Pops a condition, jumps when it isn't zero.
+    JumpIf(u32),
+    /// Pops a key, pushes its value, 0 for keys never stored.
+    Load,
+    /// Pops a value, then a key.
+    Store,
+    Caller,
+    /// Coins sent with the call, in thousandths of NoCoin.
+    CallValue,
+    /// Pushes the argument with this index, reverts if there is none.
+    Input(u8),
+    InputCount,
+    Height,
+    /// Pops this many items into the receipt's log, deepest first.
+    Log(u8),
+    Stop,
+    Revert,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ContractAction {
+    /// Contract lands at `contract_address` of the sender and the code.
+    Deploy { code: Vec<Instr> },
+    Call { input: Vec<i64> },
+}
+
+/// Outcome of a contract transaction. Failed calls leave storage untouched,
+/// their fee is still paid.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Receipt {
+    pub transaction: TransactionId,
+    pub contract: NodeId,
+    pub height: usize,
+    pub success: bool,
+    pub gas_used: u64,
+    pub logs: Vec<Vec<i64>>,
+    pub error: Option<String>,
+}
+
+pub fn contract_address(deployer: &NodeId, code: &[Instr]) -> Result<NodeId> {
+    let mut data = b"nocoin contract".to_vec();
+    data.extend(serialize(&(deployer, code))?);
+    Ok(NodeId::derived(&Sha256::digest(data)))
+}
+
+pub fn gas_limit(fee: NoCoin) -> u64 {
+    ((fee.0 / GAS_PRICE.0) as u64).min(MAX_GAS)
+}
+
+fn deploy_gas(code: &[Instr]) -> u64 {
+    code.len() as u64 * DEPLOY_GAS_PER_INSTR
+}
+
+fn find_code<'a>(blockchain: &'a Blockchain, address: &NodeId) -> Option<&'a [Instr]> {
+    blockchain
+        .0
+        .iter()
+        .flat_map(|b| b.transactions.0.iter())
+        .map(|t| &t.transaction.0)
+        .filter(|t| t.to == *address)
+        .find_map(|t| match &t.contract {
+            Some(ContractAction::Deploy { code }) => Some(code.as_slice()),
+            _ => None,
+        })
+}
+
+/// Checks what can be checked before execution, whether a call succeeds is
+/// only known once its block is applied.
+pub(super) fn validate_action(network: &Network, transaction: &Transaction) -> Result<()> {
+    let (Some(action), Some(sender)) = (&transaction.contract, &transaction.from) else {
+        return Ok(());
+    };
+    if transaction.multisig.is_some() || transaction.script.is_some() {
+        bail!("Contract transaction can't register an account too")
+    }
+    match action {
+        ContractAction::Deploy { code } => {
+            if code.len() > MAX_CODE_LEN {
+                bail!("Contract has {} instructions, limit is {}", code.len(), MAX_CODE_LEN)
+            }
+            if contract_address(sender, code)? != transaction.to {
+                bail!("Contract must be deployed to {:?}", contract_address(sender, code)?)
+            }
+            if find_code(&network.blockchain, &transaction.to).is_some() {
+                bail!("Contract {:?} is already deployed", transaction.to)
+            }
+            if deploy_gas(code) > gas_limit(transaction.fee) {
+                bail!(
+                    "Deploying needs {} gas, fee buys only {}",
+                    deploy_gas(code),
+                    gas_limit(transaction.fee)
+                )
+            }
+        }
+        ContractAction::Call { .. } => {
+            if find_code(&network.blockchain, &transaction.to).is_none() {
+                bail!("No contract deployed at {:?}", transaction.to)
+            }
+        }
+    }
+    Ok(())
+}
+
+struct CallContext<'a> {
+    caller: NodeId,
+    value: NoCoin,
+    input: &'a [i64],
+    height: usize,
+}
+
+struct Vm<'a> {
+    code: &'a [Instr],
+    context: CallContext<'a>,
+    storage: BTreeMap<i64, i64>,
+    stack: Vec<i64>,
+    logs: Vec<Vec<i64>>,
+    gas_limit: u64,
+    gas: u64,
+}
+
+impl<'a> Vm<'a> {
+    fn charge(&mut self, gas: u64) -> Result<()> {
+        self.gas += gas;
+        if self.gas > self.gas_limit {
+            self.gas = self.gas_limit;
+            bail!("Out of gas")
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: i64) -> Result<()> {
+        if self.stack.len() == MAX_STACK_DEPTH {
+            bail!("Stack overflow")
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64> {
+        self.stack.pop().ok_or(anyhow!("Stack underflow"))
+    }
+
+    fn binary(&mut self, op: fn(i64, i64) -> Option<i64>) -> Result<()> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let result = op(a, b).ok_or(anyhow!("Division by zero"))?;
+        self.push(result)
+    }
+
+    fn depth(&self, depth: u8) -> Result<usize> {
+        self.stack
+            .len()
+            .checked_sub(depth as usize + 1)
+            .ok_or(anyhow!("Stack underflow"))
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut pc = 0;
+        while let Some(instr) = self.code.get(pc) {
+            self.charge(1)?;
+            pc += 1;
+            match instr {
+                Instr::Push(value) => self.push(*value)?,
+                Instr::Pop => {
+                    self.pop()?;
+                }
+                Instr::Dup(depth) => {
+                    let value = self.stack[self.depth(*depth)?];
+                    self.push(value)?
+                }
+                Instr::Swap(depth) => {
+                    let other = self.depth(*depth)?;
+                    let top = self.stack.len() - 1;
+                    self.stack.swap(top, other)
+                }
+                Instr::Add => self.binary(|a, b| Some(a.wrapping_add(b)))?,
+                Instr::Sub => self.binary(|a, b| Some(a.wrapping_sub(b)))?,
+                Instr::Mul => self.binary(|a, b| Some(a.wrapping_mul(b)))?,
+                Instr::Div => self.binary(|a, b| a.checked_div(b).or((b == -1).then(|| a.wrapping_neg())))?,
+                Instr::Mod => self.binary(|a, b| a.checked_rem(b).or((b == -1).then_some(0)))?,
+                Instr::Lt => self.binary(|a, b| Some((a < b) as i64))?,
+                Instr::Gt => self.binary(|a, b| Some((a > b) as i64))?,
+                Instr::Eq => self.binary(|a, b| Some((a == b) as i64))?,
+                Instr::And => self.binary(|a, b| Some((a != 0 && b != 0) as i64))?,
+                Instr::Or => self.binary(|a, b| Some((a != 0 || b != 0) as i64))?,
+                Instr::IsZero => {
+                    let value = self.pop()?;
+                    self.push((value == 0) as i64)?
+                }
+                Instr::Jump(target) => pc = *target as usize,
+                Instr::JumpIf(target) => {
+                    if self.pop()? != 0 {
+                        pc = *target as usize
+                    }
+                }
+                Instr::Load => {
+                    self.charge(LOAD_GAS)?;
+                    let key = self.pop()?;
+                    let value = self.storage.get(&key).copied().unwrap_or(0);
+                    self.push(value)?
+                }
+                Instr::Store => {
+                    self.charge(STORE_GAS)?;
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    if value == 0 {
+                        self.storage.remove(&key);
+                    } else {
+                        self.storage.insert(key, value);
+                    }
+                }
+                Instr::Caller => self.push(self.context.caller.0 as i64)?,
+                Instr::CallValue => self.push((self.context.value.0 as f64 * 1000.).round() as i64)?,
+                Instr::Input(index) => {
+                    let value = *self
+                        .context
+                        .input
+                        .get(*index as usize)
+                        .ok_or(anyhow!("Call has no argument {}", index))?;
+                    self.push(value)?
+                }
+                Instr::InputCount => self.push(self.context.input.len() as i64)?,
+                Instr::Height => self.push(self.context.height as i64)?,
+                Instr::Log(count) => {
+                    self.charge(LOG_GAS + *count as u64)?;
+                    let from = self
+                        .stack
+                        .len()
+                        .checked_sub(*count as usize)
+                        .ok_or(anyhow!("Stack underflow"))?;
+                    let entry = self.stack.split_off(from);
+                    self.logs.push(entry)
+                }
+                Instr::Stop => return Ok(()),
+                Instr::Revert => bail!("Reverted at {}", pc - 1),
+            }
+        }
+        if pc > self.code.len() {
+            bail!("Jump to {} is outside of the code", pc)
+        }
+        Ok(())
+    }
+}
+
+/// Gas and logs of an execution, `error` is set when it failed.
+struct Outcome {
+    gas_used: u64,
+    logs: Vec<Vec<i64>>,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn failed(gas_used: u64, error: anyhow::Error) -> Self {
+        Self {
+            gas_used,
+            logs: vec![],
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+struct Contract {
+    code: Vec<Instr>,
+    storage: BTreeMap<i64, i64>,
+}
+
+/// Contract code and storage as of the chain tip, built by executing every
+/// contract transaction in chain order. Catches up with the chain lazily and
+/// starts over if the blocks it applied were replaced.
+#[derive(Default)]
+pub struct ContractLedger {
+    contracts: HashMap<NodeId, Contract>,
+    receipts: HashMap<TransactionId, Receipt>,
+    applied: Vec<BlockHash>,
+}
+
+impl ContractLedger {
+    pub fn sync(&mut self, blockchain: &Blockchain) -> Result<()> {
+        if !blockchain.starts_with(&self.applied) {
+            *self = Self::default();
+        }
+        for block in &blockchain.0[self.applied.len()..] {
+            self.apply_block(block)?;
+            self.applied.push(block.header.hash.clone());
+        }
+        Ok(())
+    }
+
+    fn apply_block(&mut self, block: &Block) -> Result<()> {
+        for proven in &block.transactions.0 {
+            let transaction = &proven.transaction.0;
+            let (Some(action), Some(sender)) = (&transaction.contract, transaction.from) else {
+                continue;
+            };
+            let id = transaction.id()?;
+            let outcome = match action {
+                ContractAction::Deploy { code } => self.deploy(sender, transaction, code),
+                ContractAction::Call { input } => self.call(sender, transaction, input, block.header.index.0),
+            };
+            self.receipts.insert(
+                id.clone(),
+                Receipt {
+                    transaction: id,
+                    contract: transaction.to,
+                    height: block.header.index.0,
+                    success: outcome.error.is_none(),
+                    gas_used: outcome.gas_used,
+                    logs: outcome.logs,
+                    error: outcome.error,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn deploy(
+        &mut self,
+        sender: NodeId,
+        transaction: &Transaction,
+        code: &[Instr],
+    ) -> Outcome {
+        let gas = deploy_gas(code);
+        let failed = |e| Outcome::failed(gas.min(gas_limit(transaction.fee)), e);
+        if gas > gas_limit(transaction.fee) {
+            return failed(anyhow!("Out of gas"));
+        }
+        if code.len() > MAX_CODE_LEN || contract_address(&sender, code).ok() != Some(transaction.to) {
+            return failed(anyhow!("Invalid deployment"));
+        }
+        if self.contracts.contains_key(&transaction.to) {
+            return failed(anyhow!("Contract is already deployed"));
+        }
+        self.contracts.insert(
+            transaction.to,
+            Contract {
+                code: code.to_vec(),
+                storage: BTreeMap::new(),
+            },
+        );
+        Outcome {
+            gas_used: gas,
+            logs: vec![],
+            error: None,
+        }
+    }
+
+    fn call(
+        &mut self,
+        sender: NodeId,
+        transaction: &Transaction,
+        input: &[i64],
+        height: usize,
+    ) -> Outcome {
+        let Some(contract) = self.contracts.get_mut(&transaction.to) else {
+            return Outcome::failed(0, anyhow!("No contract at {:?}", transaction.to));
+        };
+        let mut vm = Vm {
+            code: &contract.code,
+            context: CallContext {
+                caller: sender,
+                value: transaction.ammount,
+                input,
+                height,
+            },
+            storage: contract.storage.clone(),
+            stack: vec![],
+            logs: vec![],
+            gas_limit: gas_limit(transaction.fee),
+            gas: 0,
+        };
+        match vm.run() {
+            Ok(()) => {
+                contract.storage = vm.storage;
+                Outcome {
+                    gas_used: vm.gas,
+                    logs: vm.logs,
+                    error: None,
+                }
+            }
+            Err(e) => Outcome::failed(vm.gas, e),
+        }
+    }
+
+    pub fn storage(&self, contract: &NodeId, key: i64) -> Option<i64> {
+        self.contracts
+            .get(contract)
+            .map(|c| c.storage.get(&key).copied().unwrap_or(0))
+    }
+
+    pub fn receipt(&self, id: &TransactionId) -> Option<&Receipt> {
+        self.receipts.get(id)
+    }
+
+    /// Hash of every contract's code and storage, equal on nodes which agree on the state.
+    pub fn state_root(&self) -> Result<String> {
+        let mut addresses: Vec<_> = self.contracts.keys().collect();
+        addresses.sort_by_key(|a| a.0);
+        let mut sha256 = Sha256::new();
+        for address in addresses {
+            let contract = &self.contracts[address];
+            sha256.update(serialize(&(address, &contract.code, &contract.storage))?);
+        }
+        Ok(format!("{:x}", sha256.finalize()))
+    }
+}
+
+/// Node sends a deploy or call signed with its own key, `to` is ignored for deploys.
+pub fn try_send_contract(
+    network: &mut Network,
+    to: NodeId,
+    action: ContractAction,
+    ammount: NoCoin,
+    fee: NoCoin,
+) -> Result<(TransactionId, SignedTransaction)> {
+    let sender = network.user.node.id;
+    let to = match &action {
+        ContractAction::Deploy { code } => contract_address(&sender, code)?,
+        ContractAction::Call { .. } => to,
+    };
+    let transaction = Transaction {
+        contract: Some(action),
+        ..Transaction::new(Some(sender), to, fee, ammount)
+    };
+    let proven = approve(map_to_affordable(network, transaction)?, &network.user.priv_key)?;
+    let signed = SignedTransaction {
+        transaction: proven.transaction.0,
+        proof: proven.proof.ok_or(anyhow!("Contract transaction wasn't signed"))?,
+    };
+    let id = try_add_transaction(network, signed.transaction.clone(), signed.proof.clone())?;
+    Ok((id, signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::testing::{mine, network, token_contract};
+
+    use super::*;
+    use Instr::*;
+
+    fn send(network: &mut Network, to: NodeId, action: ContractAction) -> TransactionId {
+        try_send_contract(network, to, action, NoCoin(0.), NoCoin(1.)).unwrap().0
+    }
+
+    #[test]
+    fn incremental_and_replayed_ledgers_agree() {
+        let mut author = network(8100);
+        mine(&mut author);
+        let owner = author.user.node.id;
+        let address = contract_address(&owner, &token_contract()).unwrap();
+        send(&mut author, address, ContractAction::Deploy { code: token_contract() });
+        mine(&mut author);
+        let mint = send(&mut author, address, ContractAction::Call { input: vec![0, 100] });
+        mine(&mut author);
+        let transfer = send(&mut author, address, ContractAction::Call { input: vec![1, 7, 30] });
+        let overdraft = send(&mut author, address, ContractAction::Call { input: vec![1, 7, 500] });
+        mine(&mut author);
+
+        let mut incremental = ContractLedger::default();
+        let mut replayed = ContractLedger::default();
+        let mut received = Blockchain(vec![]);
+        for block in &author.blockchain.0 {
+            received.0.push(block.clone());
+            incremental.sync(&received).unwrap();
+        }
+        replayed.sync(&author.blockchain).unwrap();
+
+        assert_eq!(incremental.state_root().unwrap(), replayed.state_root().unwrap());
+        assert_eq!(replayed.storage(&address, owner.0 as i64), Some(70));
+        assert_eq!(replayed.storage(&address, 7), Some(30));
+        assert!(replayed.receipt(&mint).unwrap().success);
+        let transfer = replayed.receipt(&transfer).unwrap();
+        assert_eq!(transfer.logs, vec![vec![owner.0 as i64, 7, 30]]);
+        let overdraft = replayed.receipt(&overdraft).unwrap();
+        assert!(!overdraft.success);
+        assert_eq!(incremental.receipt(&overdraft.transaction.clone()), Some(overdraft));
+    }
+
+    #[test]
+    fn failed_calls_leave_storage_untouched() {
+        let mut network = network(8100);
+        mine(&mut network);
+        let spinner = vec![Push(1), Push(5), Store, Jump(0)];
+        let address = contract_address(&network.user.node.id, &spinner).unwrap();
+        send(&mut network, address, ContractAction::Deploy { code: spinner });
+        mine(&mut network);
+        let call = send(&mut network, address, ContractAction::Call { input: vec![] });
+        mine(&mut network);
+        let mut ledger = ContractLedger::default();
+
+        ledger.sync(&network.blockchain).unwrap();
+
+        let receipt = ledger.receipt(&call).unwrap();
+        assert!(!receipt.success);
+        assert_eq!(receipt.gas_used, gas_limit(NoCoin(1.)));
+        assert_eq!(ledger.storage(&address, 1), Some(0));
+        assert!(try_send_contract(&mut network, NodeId(1), ContractAction::Call { input: vec![] }, NoCoin(0.), NoCoin(1.)).is_err());
+    }
+}
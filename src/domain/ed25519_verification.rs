@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use secrets::SecretVec;
+
+use super::signature::{secret_copy, PrivKey, PubKey, SignatureAlgorithm, SignatureScheme};
+
+pub struct Ed25519;
+
+fn ed25519_key_pair(key: SigningKey) -> (PrivKey, PubKey) {
+    let public = PubKey {
+        algorithm: SignatureAlgorithm::Ed25519,
+        bytes: key.verifying_key().to_bytes().to_vec(),
+    };
+    let private = PrivKey {
+        algorithm: SignatureAlgorithm::Ed25519,
+        secret: secret_copy(key.as_bytes()),
+    };
+    (private, public)
+}
+
+fn signing_key(key: &PrivKey) -> Result<SigningKey> {
+    if key.algorithm != SignatureAlgorithm::Ed25519 {
+        return Err(anyhow!("{:?} key can't sign with Ed25519", key.algorithm));
+    }
+    let secret = key.secret.borrow();
+    let bytes = (*secret)
+        .try_into()
+        .map_err(|_| anyhow!("Ed25519 secret must have 32 bytes, has {}", secret.len()))?;
+    Ok(SigningKey::from_bytes(bytes))
+}
+
+impl SignatureScheme for Ed25519 {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::Ed25519
+    }
+
+    fn generate_key(&self) -> Result<(PrivKey, PubKey)> {
+        Ok(ed25519_key_pair(SigningKey::generate(&mut OsRng)))
+    }
+
+    fn sign_digest(&self, digest: &[u8], key: &PrivKey) -> Result<Vec<u8>> {
+        Ok(signing_key(key)?.sign(digest).to_bytes().to_vec())
+    }
+
+    fn verify_digest(&self, digest: &[u8], signature: &[u8], key: &PubKey) -> Result<()> {
+        let bytes = key
+            .bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Ed25519 key must have 32 bytes, has {}", key.bytes.len()))?;
+        let public = VerifyingKey::from_bytes(bytes)?;
+        let signature = ed25519_dalek::Signature::from_slice(signature)?;
+        public.verify(digest, &signature).map_err(|e| e.into())
+    }
+
+    fn export_secret(&self, key: &PrivKey) -> Result<SecretVec<u8>> {
+        Ok(secret_copy(signing_key(key)?.as_bytes()))
+    }
+
+    fn import_secret(&self, secret: &[u8]) -> Result<(PrivKey, PubKey)> {
+        let bytes = secret
+            .try_into()
+            .map_err(|_| anyhow!("Ed25519 secret must have 32 bytes, has {}", secret.len()))?;
+        Ok(ed25519_key_pair(SigningKey::from_bytes(bytes)))
+    }
+
+    fn derive_key(&self, seed: &[u8; 32]) -> Result<(PrivKey, PubKey)> {
+        Ok(ed25519_key_pair(SigningKey::from_bytes(seed)))
+    }
+}
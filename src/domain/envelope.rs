@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    network::{Network, NodeId},
+    reputation::{InvalidSignature, OutOfSync},
+    serialization::serialize,
+    signature::{sign, verify, PrivKey, PubKey, Signature},
+};
+
+/// How far a message timestamp may drift from the receiver's clock.
+pub const MAX_MESSAGE_AGE_SECS: u64 = 60;
+
+/// Inter-node message signed by its sender. The signature covers the sender id,
+/// timestamp, nonce and payload, so none of them can be swapped in transit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope<T> {
+    pub sender: NodeId,
+    pub timestamp: u64,
+    pub nonce: u64,
+    pub payload: T,
+    pub signature: Signature,
+}
+
+#[derive(Default)]
+pub struct ReplayGuard {
+    seen: HashMap<(NodeId, u64), u64>,
+}
+
+impl ReplayGuard {
+    fn remember(&mut self, sender: NodeId, nonce: u64, timestamp: u64, now: u64) -> Result<()> {
+        self.seen
+            .retain(|_, seen_at| seen_at.saturating_add(MAX_MESSAGE_AGE_SECS) >= now);
+        if self.seen.insert((sender, nonce), timestamp).is_some() {
+            bail!("Message {} from {:?} was already received", nonce, sender)
+        }
+        Ok(())
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn signed_content<T: Serialize>(sender: &NodeId, timestamp: u64, nonce: u64, payload: &T) -> Result<Vec<u8>> {
+    serialize(&(sender, timestamp, nonce, payload))
+}
+
+pub fn seal<T: Serialize>(sender: NodeId, key: &PrivKey, payload: T) -> Result<Envelope<T>> {
+    let timestamp = unix_timestamp();
+    let nonce = rand::random();
+    let signature = sign(&signed_content(&sender, timestamp, nonce, &payload)?, key)?;
+    Ok(Envelope {
+        sender,
+        timestamp,
+        nonce,
+        payload,
+        signature,
+    })
+}
+
+/// Verifies the envelope against the sender's known key, falling back to the
+/// `presented` one for senders which are not part of the network yet.
+pub fn open<T: Serialize>(
+    network: &mut Network,
+    envelope: Envelope<T>,
+    presented: Option<&PubKey>,
+) -> Result<T> {
+    let now = unix_timestamp();
+    if envelope.timestamp.abs_diff(now) > MAX_MESSAGE_AGE_SECS {
+        bail!(
+            "Message from {:?} is stale, sent at {} but now is {}",
+            envelope.sender,
+            envelope.timestamp,
+            now
+        )
+    }
+    let key = network
+        .nodes
+        .iter()
+        .find(|n| n.id == envelope.sender)
+        .map(|n| &n.pub_key)
+        .or(presented)
+        .ok_or_else(|| OutOfSync(format!("Unknown sender {:?}", envelope.sender)))?;
+    let content = signed_content(
+        &envelope.sender,
+        envelope.timestamp,
+        envelope.nonce,
+        &envelope.payload,
+    )?;
+    verify(&content, &envelope.signature, key).context(InvalidSignature)?;
+    network
+        .replay_guard
+        .remember(envelope.sender, envelope.nonce, envelope.timestamp, now)?;
+    Ok(envelope.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::domain::{
+        network::{try_acknowledge_node, Node},
+        signature::{generate_key, SignatureAlgorithm},
+        testing::network,
+    };
+
+    use super::*;
+
+    #[test]
+    fn opens_sealed_message_once() {
+        let mut network = network(8100);
+        let envelope = seal(network.user.node.id, &network.user.priv_key, 42u32).unwrap();
+
+        assert_eq!(open(&mut network, envelope.clone(), None).unwrap(), 42);
+        assert!(open(&mut network, envelope, None).is_err(), "Replay was accepted");
+    }
+
+    #[test]
+    fn rejects_tampered_and_unknown_senders() {
+        let mut network = network(8100);
+        let (stranger_key, stranger_pub) = generate_key(SignatureAlgorithm::default()).unwrap();
+
+        let mut tampered = seal(network.user.node.id, &network.user.priv_key, 42u32).unwrap();
+        tampered.payload = 43;
+        let error = open(&mut network, tampered, None).unwrap_err();
+        assert!(error.downcast_ref::<InvalidSignature>().is_some());
+
+        let impersonation = seal(network.user.node.id, &stranger_key, 42u32).unwrap();
+        assert!(open(&mut network, impersonation, Some(&stranger_pub)).is_err());
+
+        let unknown = seal(NodeId(9000), &stranger_key, 42u32).unwrap();
+        assert!(open(&mut network, unknown.clone(), None).is_err());
+        assert!(open(&mut network, unknown, Some(&stranger_pub)).is_ok());
+    }
+
+    #[test]
+    fn rejects_stale_messages() {
+        let mut network = network(8100);
+        let sender = network.user.node.id;
+        let timestamp = unix_timestamp() - MAX_MESSAGE_AGE_SECS - 1;
+        let content = signed_content(&sender, timestamp, 7, &42u32).unwrap();
+        let envelope = Envelope {
+            sender,
+            timestamp,
+            nonce: 7,
+            payload: 42u32,
+            signature: sign(&content, &network.user.priv_key).unwrap(),
+        };
+
+        assert!(open(&mut network, envelope, None).is_err());
+    }
+
+    #[test]
+    fn acknowledges_only_self_signed_registrations() {
+        let mut network = network(8100);
+        let (private, pub_key) = generate_key(SignatureAlgorithm::default()).unwrap();
+        let (stranger_key, _) = generate_key(SignatureAlgorithm::default()).unwrap();
+        let newcomer = Node { id: NodeId(8102), addr: SocketAddr::from(([127, 0, 0, 1], 8102)), pub_key };
+
+        let forwarded = seal(network.user.node.id, &network.user.priv_key, newcomer.clone()).unwrap();
+        let forged = seal(newcomer.id, &stranger_key, newcomer.clone()).unwrap();
+        let registration = seal(newcomer.id, &private, newcomer.clone()).unwrap();
+
+        assert!(try_acknowledge_node(&mut network, forwarded).is_err());
+        assert!(try_acknowledge_node(&mut network, forged).is_err());
+        try_acknowledge_node(&mut network, registration).unwrap();
+        assert!(network.nodes.iter().any(|n| n.id == newcomer.id));
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{
+    blockchain::{ensure_timely, Block, NoCoin},
+    envelope::unix_timestamp,
+    mining::BlockHash,
+    network::{add_to_poll, Network, NodeId},
+    reputation::OutOfSync,
+    transaction::{map_to_affordable, verify_transaction, ProvenTransaction},
+    wallet::calculate_wallet,
+};
+
+/// How far below the tip blocks of other branches are still kept.
+const KEPT_DEPTH: usize = 100;
+
+/// Blocks the node heard of which aren't on its chain. Once a branch of them
+/// grows longer than the chain it replaces the chain's end.
+#[derive(Default)]
+pub struct Forks {
+    blocks: Vec<Block>,
+}
+
+impl Forks {
+    fn find(&self, height: usize, hash: &BlockHash) -> Option<&Block> {
+        self.blocks
+            .iter()
+            .find(|b| b.header.index.0 == height && b.header.hash == *hash)
+    }
+
+    fn remove(&mut self, blocks: &[Block]) {
+        self.blocks.retain(|b| !blocks.iter().any(|x| is_same(x, b)));
+    }
+}
+
+fn is_same(a: &Block, b: &Block) -> bool {
+    a.header.index.0 == b.header.index.0 && a.header.hash == b.header.hash
+}
+
+pub(super) fn extends(tip: &Block, block: &Block) -> bool {
+    block.header.index.0 == tip.header.index.0 + 1 && block.header.prev_hash == tip.header.hash
+}
+
+/// Keeps a block which doesn't extend the tip and switches to its branch if
+/// the branch became the longest one.
+pub(super) fn try_add_side_block(network: &mut Network, block: Block) -> Result<()> {
+    let height = block.header.index.0;
+    let on_chain = network.blockchain.0.get(height).is_some_and(|b| is_same(b, &block));
+    if on_chain || network.forks.find(height, &block.header.hash).is_some() {
+        bail!(OutOfSync(format!("Block {} {:?} is already known", height, block.header.hash)))
+    }
+    let (fork, branch) = branch(network, block)?;
+    if height <= network.blockchain.height() {
+        network.forks.blocks.push(branch.last().unwrap().clone());
+        prune(network);
+        return Ok(());
+    }
+    reorganize(network, fork, branch)
+}
+
+/// Height at which the block's branch leaves the chain and the blocks of the
+/// branch from there up to the block.
+fn branch(network: &Network, block: Block) -> Result<(usize, Vec<Block>)> {
+    let mut branch = vec![block];
+    loop {
+        let lowest = branch.last().unwrap();
+        let parent_height = lowest
+            .header
+            .index
+            .0
+            .checked_sub(1)
+            .ok_or(anyhow!("Only the genesis block has no parent"))?;
+        let parent = &lowest.header.prev_hash;
+        if network.blockchain.0.get(parent_height).is_some_and(|b| b.header.hash == *parent) {
+            branch.reverse();
+            return Ok((parent_height, branch));
+        }
+        let parent = network.forks.find(parent_height, parent).ok_or_else(|| {
+            OutOfSync(format!(
+                "Block {} {:?} doesn't extend any known block",
+                lowest.header.index.0, lowest.header.hash
+            ))
+        })?;
+        branch.push(parent.clone());
+    }
+}
+
+/// Blocks of other branches weren't checked against the node's poll, their
+/// transactions are checked against the chain below them instead. Payments of
+/// one sender in the block must be affordable together.
+fn verify_transactions(network: &Network, block: &Block) -> Result<()> {
+    let mut spent: HashMap<NodeId, NoCoin> = HashMap::new();
+    for proven in &block.transactions.0 {
+        let transaction = proven.transaction.0.clone();
+        if let Some(sender) = transaction.from {
+            if network.blockchain.contains(&transaction) {
+                bail!("{:?} replays a mined payment in block {}", sender, block.header.index.0)
+            }
+            let total = spent.entry(sender).or_insert(NoCoin(0.));
+            *total += transaction.ammount + transaction.fee;
+            if calculate_wallet(&sender, &network.blockchain) < *total {
+                bail!("{:?} spends more than it has in block {}", sender, block.header.index.0)
+            }
+        }
+        match proven.proof.clone() {
+            Some(proof) => verify_transaction(network, transaction, proof).map(|_| ())?,
+            None if transaction.from.is_some() => {
+                bail!("Spending from {:?} in block {} isn't signed", transaction.from, block.header.index.0)
+            }
+            None => map_to_affordable(network, transaction).map(|_| ())?,
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the chain above `fork` with `branch`. Transactions of the dropped
+/// blocks go back to the poll unless the branch spent the same coins.
+fn reorganize(network: &mut Network, fork: usize, branch: Vec<Block>) -> Result<()> {
+    let dropped = network.blockchain.0.split_off(fork + 1);
+    let now = unix_timestamp() as usize;
+    for (i, block) in branch.iter().enumerate() {
+        let verified = ensure_timely(&network.blockchain.0, block, now).and_then(|_| verify_transactions(network, block));
+        if let Err(e) = verified {
+            network.blockchain.0.truncate(fork + 1);
+            network.blockchain.0.extend(dropped);
+            network.forks.remove(&branch[i..]);
+            bail!("Branch from block {} is invalid at block {}: {}", fork + 1, block.header.index.0, e)
+        }
+        network.blockchain.0.push(block.clone());
+    }
+    network.forks.remove(&branch);
+    let included: Vec<_> = branch.iter().flat_map(|b| &b.transactions.0).map(|t| &t.transaction.0).collect();
+    let returned: Vec<ProvenTransaction> = dropped
+        .iter()
+        .flat_map(|b| b.transactions.0.iter().cloned())
+        .chain(std::mem::take(&mut network.transactions_poll))
+        .filter(|t| !included.contains(&&t.transaction.0))
+        .collect();
+    for proven in returned {
+        let Some(proof) = proven.proof else {
+            continue;
+        };
+        if let Ok(proven) = verify_transaction(network, proven.transaction.0, proof) {
+            let _ = add_to_poll(network, proven);
+        }
+    }
+    network.forks.blocks.extend(dropped);
+    prune(network);
+    Ok(())
+}
+
+pub(super) fn prune(network: &mut Network) {
+    let lowest = network.blockchain.height().saturating_sub(KEPT_DEPTH);
+    network.forks.blocks.retain(|b| b.header.index.0 > lowest);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        blockchain::NoCoin,
+        network::{try_add_block, try_send_payment, NodeId},
+        reputation::Misbehavior,
+        testing::{funded_network, mine_on},
+        transaction::{approve, create_mining_reward, AffordableTransaction, Transaction},
+        wallet::calculate_wallet,
+    };
+
+    use super::*;
+
+    fn sweep(network: &Network, to: NodeId, ammount: f32) -> ProvenTransaction {
+        let transaction = Transaction::new(Some(network.user.node.id), to, NoCoin(1.), NoCoin(ammount));
+        approve(AffordableTransaction(transaction), &network.user.priv_key).unwrap()
+    }
+
+    #[test]
+    fn longer_branch_reverses_payment() {
+        let mut network = funded_network();
+        let (merchant, stash) = (NodeId(8101), NodeId(8102));
+        let fork = network.blockchain.last_block().clone();
+        try_send_payment(&mut network, merchant, NoCoin(9.), NoCoin(1.), None).unwrap();
+        let paid = mine_on(&fork, network.transactions_poll.clone(), NodeId(8103));
+        try_add_block(&mut network, paid).unwrap();
+        let hidden = mine_on(&fork, vec![sweep(&network, stash, 9.)], stash);
+        let longer = mine_on(&hidden, vec![], stash);
+
+        try_add_block(&mut network, hidden.clone()).unwrap();
+        assert_eq!(calculate_wallet(&merchant, &network.blockchain), NoCoin(9.));
+        try_add_block(&mut network, longer).unwrap();
+
+        assert_eq!(network.blockchain.height(), 3);
+        assert_eq!(calculate_wallet(&merchant, &network.blockchain), NoCoin(0.));
+        assert_eq!(calculate_wallet(&stash, &network.blockchain), NoCoin(9. + 1. + 20.));
+        assert!(network.transactions_poll.is_empty());
+        assert!(try_add_block(&mut network, hidden).is_err());
+    }
+
+    #[test]
+    fn longer_branch_cannot_spend_without_signature() {
+        let mut network = funded_network();
+        let (victim, thief) = (network.user.node.id, NodeId(8102));
+        let fork = network.blockchain.last_block().clone();
+        try_add_block(&mut network, mine_on(&fork, vec![], NodeId(8101))).unwrap();
+        let unsigned = Transaction::new(Some(victim), thief, NoCoin(1.), NoCoin(9.));
+        let stolen = ProvenTransaction { transaction: AffordableTransaction(unsigned), proof: None };
+        let hidden = mine_on(&fork, vec![stolen], thief);
+        let longer = mine_on(&hidden, vec![], thief);
+
+        try_add_block(&mut network, hidden).unwrap();
+        assert!(try_add_block(&mut network, longer).is_err());
+
+        assert_eq!(network.blockchain.height(), 2);
+        assert_eq!(calculate_wallet(&victim, &network.blockchain), NoCoin(10.));
+        assert_eq!(calculate_wallet(&thief, &network.blockchain), NoCoin(0.));
+    }
+
+    #[test]
+    fn orphan_blocks_are_not_held_against_the_sender() {
+        let mut network = funded_network();
+        let parent = mine_on(network.blockchain.last_block(), vec![], NodeId(8101));
+        let orphan = mine_on(&parent, vec![], NodeId(8101));
+
+        let e = try_add_block(&mut network, orphan).unwrap_err();
+
+        assert_eq!(Misbehavior::of(&e, Misbehavior::InvalidBlock), None);
+    }
+
+    #[test]
+    fn forged_blocks_are_rejected() {
+        let mut network = funded_network();
+        let tip = network.blockchain.last_block().clone();
+        let mut replayed = mine_on(&tip, vec![], NodeId(8101));
+        replayed.header.index = replayed.header.index.next_index();
+        let mut cheap = mine_on(&tip, vec![], NodeId(8101));
+        cheap.header.difficulty = 0;
+        network.params.mining_difficulty = 2;
+
+        assert!(try_add_block(&mut network, replayed).is_err());
+        assert!(try_add_block(&mut network, cheap).is_err());
+
+        assert_eq!(network.blockchain.last_block().header.hash, tip.header.hash);
+        assert!(network.forks.blocks.is_empty());
+    }
+
+    #[test]
+    fn branch_blocks_mint_and_spend_only_once() {
+        let mut network = funded_network();
+        let fork = network.blockchain.last_block().clone();
+        let honest = mine_on(&fork, vec![], NodeId(8101));
+        try_add_block(&mut network, honest.clone()).unwrap();
+        let minting = mine_on(&fork, vec![create_mining_reward(NodeId(8102))], NodeId(8102));
+        let double = vec![sweep(&network, NodeId(8102), 5.), sweep(&network, NodeId(8103), 5.)];
+        let spending = mine_on(&fork, double, NodeId(8102));
+
+        try_add_block(&mut network, spending.clone()).unwrap();
+        assert!(try_add_block(&mut network, mine_on(&spending, vec![], NodeId(8102))).is_err());
+        assert!(try_add_block(&mut network, minting).is_err());
+
+        assert_eq!(network.blockchain.last_block().header.hash, honest.header.hash);
+    }
+
+    #[test]
+    fn invalid_branch_keeps_the_chain() {
+        let mut network = funded_network();
+        let fork = network.blockchain.last_block().clone();
+        let honest = mine_on(&fork, vec![], NodeId(8101));
+        try_add_block(&mut network, honest.clone()).unwrap();
+        let overspending = mine_on(&fork, vec![sweep(&network, NodeId(8102), 50.)], NodeId(8102));
+        let longer = mine_on(&overspending, vec![], NodeId(8102));
+        let orphan = mine_on(&longer, vec![], NodeId(8102));
+
+        try_add_block(&mut network, overspending).unwrap();
+        assert!(try_add_block(&mut network, longer).is_err());
+        assert!(try_add_block(&mut network, orphan).is_err());
+
+        assert_eq!(network.blockchain.height(), 2);
+        assert_eq!(network.blockchain.last_block().header.hash, honest.header.hash);
+        assert!(network.forks.blocks.is_empty());
+    }
+}
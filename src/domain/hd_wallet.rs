@@ -0,0 +1,388 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use secrets::{SecretBox, SecretVec};
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+
+use super::{
+    blockchain::{Blockchain, NoCoin},
+    keystore::{write_file, KdfParams, Sealed},
+    network::{Network, NodeId},
+    serialization::serialize,
+    signature::{secret_copy, PrivKey, PubKey, SignatureAlgorithm},
+    transaction::{approve, map_to_affordable, pending_spends, Lock, ProvenTransaction, Transaction},
+    wallet::calculate_wallet,
+};
+
+pub const WALLET_VERSION: u8 = 1;
+/// Restoring stops after this many consecutive addresses without history.
+pub const GAP_LIMIT: u32 = 20;
+const ENTROPY_LEN: usize = 16;
+
+pub struct Account {
+    pub address: NodeId,
+    pub pub_key: PubKey,
+    priv_key: PrivKey,
+}
+
+/// Deterministic wallet, every account key is derived from one seed phrase,
+/// so the phrase alone is enough to get all the accounts back.
+pub struct HdWallet {
+    entropy: SecretVec<u8>,
+    seed: SecretBox<[u8; 64]>,
+    algorithm: SignatureAlgorithm,
+    accounts: Vec<Account>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalletFile {
+    version: u8,
+    algorithm: SignatureAlgorithm,
+    accounts: u32,
+    #[serde(flatten)]
+    sealed: Sealed,
+}
+
+fn associated_data(version: u8, algorithm: SignatureAlgorithm) -> Result<Vec<u8>> {
+    serialize(&(version, algorithm))
+}
+
+fn used_addresses(blockchain: &Blockchain) -> HashSet<NodeId> {
+    blockchain
+        .0
+        .iter()
+        .flat_map(|b| b.transactions.0.iter())
+        .flat_map(|t| t.transaction.0.from.into_iter().chain([t.transaction.0.to]))
+        .collect()
+}
+
+impl HdWallet {
+    pub fn generate(algorithm: SignatureAlgorithm) -> Result<Self> {
+        let mut entropy = SecretVec::<u8>::zero(ENTROPY_LEN);
+        OsRng.fill_bytes(&mut entropy.borrow_mut());
+        Self::from_entropy(entropy, algorithm, 1)
+    }
+
+    /// Only the first account is derived, `scan` finds the ones with history.
+    pub fn restore(seed_phrase: &str, algorithm: SignatureAlgorithm) -> Result<Self> {
+        let mnemonic = Mnemonic::parse(seed_phrase).map_err(|e| anyhow!("Invalid seed phrase: {}", e))?;
+        let (entropy, len) = mnemonic.to_entropy_array();
+        Self::from_entropy(secret_copy(&entropy[..len]), algorithm, 1)
+    }
+
+    fn from_entropy(entropy: SecretVec<u8>, algorithm: SignatureAlgorithm, accounts: u32) -> Result<Self> {
+        let mnemonic = Mnemonic::from_entropy(&entropy.borrow())?;
+        let seed = SecretBox::new(|seed: &mut [u8; 64]| *seed = mnemonic.to_seed(""));
+        let mut wallet = Self {
+            entropy,
+            seed,
+            algorithm,
+            accounts: vec![],
+        };
+        for _ in 0..accounts {
+            wallet.new_account()?;
+        }
+        Ok(wallet)
+    }
+
+    pub fn seed_phrase(&self) -> Result<String> {
+        Ok(Mnemonic::from_entropy(&self.entropy.borrow())?.to_string())
+    }
+
+    fn derive(&self, index: u32) -> Result<Account> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(&*self.seed.borrow())?;
+        mac.update(b"nocoin account");
+        mac.update(&index.to_be_bytes());
+        let child = SecretBox::new(|child: &mut [u8; 32]| {
+            child.copy_from_slice(&mac.finalize().into_bytes()[..32])
+        });
+        let (priv_key, pub_key) = self.algorithm.scheme().derive_key(&child.borrow())?;
+        Ok(Account {
+            address: NodeId::of_key(&pub_key),
+            pub_key,
+            priv_key,
+        })
+    }
+
+    pub fn new_account(&mut self) -> Result<&Account> {
+        let account = self.derive(self.accounts.len() as u32)?;
+        self.accounts.push(account);
+        Ok(self.accounts.last().unwrap())
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.accounts
+    }
+
+    /// First account which never appeared on the chain, a new one when all of them did.
+    pub fn receive_address(&mut self, blockchain: &Blockchain) -> Result<NodeId> {
+        let used = used_addresses(blockchain);
+        match self.accounts.iter().find(|a| !used.contains(&a.address)) {
+            Some(account) => Ok(account.address),
+            None => Ok(self.new_account()?.address),
+        }
+    }
+
+    /// Derives accounts until `GAP_LIMIT` addresses in a row never appear on the chain,
+    /// keeps the ones already known even if they are unused.
+    pub fn scan(&mut self, blockchain: &Blockchain) -> Result<()> {
+        let used = used_addresses(blockchain);
+        let known = self.accounts.len();
+        let mut unused_in_row = 0;
+        let mut index = 0;
+        while unused_in_row < GAP_LIMIT {
+            if index as usize == self.accounts.len() {
+                self.new_account()?;
+            }
+            if used.contains(&self.accounts[index as usize].address) {
+                unused_in_row = 0;
+            } else {
+                unused_in_row += 1;
+            }
+            index += 1;
+        }
+        let last_used = self
+            .accounts
+            .iter()
+            .rposition(|a| used.contains(&a.address))
+            .unwrap_or(0);
+        self.accounts.truncate(known.max(last_used + 1));
+        Ok(())
+    }
+
+    pub fn balances(&self, blockchain: &Blockchain) -> Vec<(NodeId, NoCoin)> {
+        self.accounts
+            .iter()
+            .map(|a| (a.address, calculate_wallet(&a.address, blockchain)))
+            .collect()
+    }
+
+    pub fn balance(&self, blockchain: &Blockchain) -> NoCoin {
+        self.balances(blockchain)
+            .into_iter()
+            .fold(NoCoin(0.), |sum, (_, balance)| sum + balance)
+    }
+
+    /// Pays from the richest accounts first, one transaction per account used,
+    /// each of them paying `fee`. Payments still pending are already spent.
+    pub fn create_payment(
+        &self,
+        network: &Network,
+        recipient: NodeId,
+        ammount: NoCoin,
+        fee: NoCoin,
+        lock: Option<Lock>,
+    ) -> Result<Vec<ProvenTransaction>> {
+        let mut funded: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|a| (a, calculate_wallet(&a.address, &network.blockchain) - pending_spends(network, &a.address)))
+            .filter(|(_, balance)| *balance > fee)
+            .collect();
+        funded.sort_by(|a, b| b.1 .0.total_cmp(&a.1 .0));
+
+        let mut remaining = ammount;
+        let mut payments = vec![];
+        for (account, balance) in funded {
+            if remaining.0 <= 0. {
+                break;
+            }
+            let spendable = balance - fee;
+            let part = if spendable < remaining { spendable } else { remaining };
+            let transaction = Transaction {
+                lock,
+                ..Transaction::from_key(&account.pub_key, recipient, fee, part)
+            };
+            let affordable = map_to_affordable(network, transaction)?;
+            payments.push(approve(affordable, &account.priv_key)?);
+            remaining = remaining - part;
+        }
+        if remaining.0 > 0. {
+            bail!(
+                "Wallet is {:?} short of paying {:?} with fee {:?} per account",
+                remaining,
+                ammount,
+                fee
+            )
+        }
+        Ok(payments)
+    }
+
+    pub fn save(&self, path: &Path, passphrase: &[u8], kdf: KdfParams) -> Result<()> {
+        let aad = associated_data(WALLET_VERSION, self.algorithm)?;
+        let file = WalletFile {
+            version: WALLET_VERSION,
+            algorithm: self.algorithm,
+            accounts: self.accounts.len() as u32,
+            sealed: Sealed::seal(passphrase, kdf, &aad, &self.entropy.borrow())?,
+        };
+        write_file(path, &file)
+    }
+
+    pub fn load(path: &Path, passphrase: &[u8]) -> Result<Self> {
+        let file: WalletFile = serde_json::from_slice(&fs::read(path)?)?;
+        if file.version != WALLET_VERSION {
+            bail!(
+                "Wallet {:?} has version {}, expected {}",
+                path,
+                file.version,
+                WALLET_VERSION
+            )
+        }
+        let aad = associated_data(file.version, file.algorithm)?;
+        let entropy = file.sealed.open(passphrase, &aad)?;
+        Self::from_entropy(entropy, file.algorithm, file.accounts.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        blockchain::{Block, BlocksTransactions},
+        network::{spendable, try_pay},
+        testing::network,
+        transaction::create_mining_reward,
+    };
+
+    use super::*;
+
+    fn pay_rewards(network: &mut Network, to: &[NodeId]) {
+        let mut block: Block = network.blockchain.last_block().clone();
+        block.transactions = BlocksTransactions(to.iter().map(|id| create_mining_reward(*id)).collect());
+        network.blockchain.0.push(block);
+    }
+
+    #[test]
+    fn restores_accounts_from_seed_phrase() {
+        let mut network = network(8100);
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::Ed25519).unwrap();
+        for _ in 0..3 {
+            wallet.new_account().unwrap();
+        }
+        let addresses: Vec<_> = wallet.accounts().iter().map(|a| a.address).collect();
+        pay_rewards(&mut network, &[addresses[0], addresses[3]]);
+
+        let mut restored = HdWallet::restore(&wallet.seed_phrase().unwrap(), SignatureAlgorithm::Ed25519).unwrap();
+        restored.scan(&network.blockchain).unwrap();
+
+        let restored_addresses: Vec<_> = restored.accounts().iter().map(|a| a.address).collect();
+        assert_eq!(restored_addresses, addresses);
+        assert!(addresses.iter().all(|a| a.is_derived()));
+        assert_eq!(restored.balance(&network.blockchain), NoCoin(20.));
+    }
+
+    #[test]
+    fn seed_phrase_always_derives_same_accounts() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+        let derive = |algorithm| {
+            let mut wallet = HdWallet::restore(phrase, algorithm).unwrap();
+            wallet.new_account().unwrap();
+            wallet.accounts().iter().map(|a| a.address).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            derive(SignatureAlgorithm::Ed25519),
+            vec![NodeId(9872729352579232809), NodeId(12675625714032643343)]
+        );
+        assert_eq!(
+            derive(SignatureAlgorithm::RsaPkcs1v15Sha256),
+            vec![NodeId(12069133471350076397), NodeId(13222513930195404351)]
+        );
+    }
+
+    #[test]
+    fn receives_on_next_unused_account() {
+        let mut network = network(8100);
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::Ed25519).unwrap();
+        let first = wallet.receive_address(&network.blockchain).unwrap();
+        pay_rewards(&mut network, &[first]);
+
+        let second = wallet.receive_address(&network.blockchain).unwrap();
+
+        assert_eq!(first, wallet.accounts()[0].address);
+        assert_eq!(second, wallet.accounts()[1].address);
+        assert_eq!(wallet.receive_address(&network.blockchain).unwrap(), second);
+    }
+
+    #[test]
+    fn pays_from_several_accounts() {
+        let mut network = network(8100);
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::default()).unwrap();
+        wallet.new_account().unwrap();
+        let addresses: Vec<_> = wallet.accounts().iter().map(|a| a.address).collect();
+        pay_rewards(&mut network, &addresses);
+
+        let payments = wallet
+            .create_payment(&network, NodeId(8101), NoCoin(15.), NoCoin(1.), None)
+            .unwrap();
+
+        assert_eq!(payments.len(), 2);
+        let paid = payments.iter().fold(NoCoin(0.), |sum, p| sum + p.transaction.0.ammount);
+        assert_eq!(paid, NoCoin(15.));
+        for payment in payments {
+            let proof = payment.proof.unwrap();
+            crate::domain::transaction::verify_transaction(&network, payment.transaction.0, proof).unwrap();
+        }
+        assert!(wallet
+            .create_payment(&network, NodeId(8101), NoCoin(19.), NoCoin(1.), None)
+            .is_err());
+    }
+
+    #[test]
+    fn node_pays_through_its_wallet() {
+        let mut network = network(8100);
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::default()).unwrap();
+        wallet.new_account().unwrap();
+        let addresses: Vec<_> = wallet.accounts().iter().map(|a| a.address).collect();
+        pay_rewards(&mut network, &addresses);
+        network.user.wallet = Some(wallet);
+
+        let sent = try_pay(&mut network, NodeId(8101), NoCoin(15.), NoCoin(1.), None).unwrap();
+
+        assert_eq!(sent.len(), 2);
+        assert_eq!(network.transactions_poll.len(), 2);
+        assert!(network.transactions_poll.iter().all(|t| addresses.contains(&t.transaction.0.from.unwrap())));
+        assert!(try_pay(&mut network, NodeId(8101), NoCoin(4.), NoCoin(1.), None).is_err());
+    }
+
+    #[test]
+    fn spends_count_against_every_account() {
+        let mut network = network(8100);
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::default()).unwrap();
+        wallet.new_account().unwrap();
+        let addresses: Vec<_> = wallet.accounts().iter().map(|a| a.address).collect();
+        pay_rewards(&mut network, &addresses);
+        let own = spendable(&network);
+        network.user.wallet = Some(wallet);
+
+        try_pay(&mut network, NodeId(8101), NoCoin(5.), NoCoin(1.), None).unwrap();
+
+        assert_eq!(own, NoCoin(0.));
+        assert_eq!(spendable(&network), NoCoin(14.));
+    }
+
+    #[test]
+    fn survives_save_and_load() {
+        let path = std::env::temp_dir().join(format!("nocoin-wallet-{}.json", std::process::id()));
+        let mut wallet = HdWallet::generate(SignatureAlgorithm::Ed25519).unwrap();
+        wallet.new_account().unwrap();
+        let cheap = KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+
+        wallet.save(&path, b"secret", cheap).unwrap();
+        let loaded = HdWallet::load(&path, b"secret").unwrap();
+
+        assert_eq!(loaded.seed_phrase().unwrap(), wallet.seed_phrase().unwrap());
+        assert_eq!(loaded.accounts()[1].address, wallet.accounts()[1].address);
+        assert!(HdWallet::load(&path, b"wrong").is_err());
+        let _ = fs::remove_file(path);
+    }
+}
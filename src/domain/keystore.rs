@@ -0,0 +1,347 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use secrets::{SecretBox, SecretVec};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use super::{
+    serialization::serialize,
+    signature::{generate_key, secret_copy, PrivKey, PubKey, SignatureAlgorithm},
+};
+
+pub const KEYSTORE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// Short fingerprint of a public key, names the key in files and logs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyId(pub String);
+
+impl KeyId {
+    pub fn of(key: &PubKey) -> Self {
+        let digest = Sha256::digest(&key.bytes);
+        KeyId(digest[..8].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// Argon2id cost of turning a passphrase into the file encryption key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Secret encrypted with a key derived from a passphrase.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Sealed {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KeyFile {
+    version: u8,
+    key_id: KeyId,
+    public_key: PubKey,
+    #[serde(flatten)]
+    sealed: Sealed,
+}
+
+/// Private key kept encrypted on disk. While unlocked, the decrypted key lives
+/// only in guarded memory which is zeroed when the keystore gets locked.
+pub struct Keystore {
+    path: PathBuf,
+    file: KeyFile,
+    unlocked: Option<SecretVec<u8>>,
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], kdf: &KdfParams) -> Result<SecretBox<[u8; 32]>> {
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| anyhow!("Invalid key derivation params: {}", e))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    let mut key = SecretBox::<[u8; 32]>::zero();
+    argon2
+        .hash_password_into(passphrase, salt, &mut *key.borrow_mut())
+        .map_err(|e| anyhow!("Couldn't derive key from passphrase: {}", e))?;
+    Ok(key)
+}
+
+impl Sealed {
+    /// `associated_data` isn't encrypted but is authenticated together with
+    /// the secret, so headers of a file can't be edited.
+    pub(super) fn seal(passphrase: &[u8], kdf: KdfParams, associated_data: &[u8], secret: &[u8]) -> Result<Self> {
+        let mut salt = vec![0; SALT_LEN];
+        let mut nonce = vec![0; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let key = derive_key(passphrase, &salt, &kdf)?;
+        let cipher = XChaCha20Poly1305::new(&(*key.borrow()).into());
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: secret,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| anyhow!("Couldn't encrypt secret"))?;
+        Ok(Self {
+            kdf,
+            salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub(super) fn open(&self, passphrase: &[u8], associated_data: &[u8]) -> Result<SecretVec<u8>> {
+        if self.nonce.len() != NONCE_LEN {
+            bail!("Nonce must have {} bytes", NONCE_LEN)
+        }
+        let key = derive_key(passphrase, &self.salt, &self.kdf)?;
+        let cipher = XChaCha20Poly1305::new(&(*key.borrow()).into());
+        let plain = cipher
+            .decrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted file"))?;
+        Ok(secret_copy(&plain))
+    }
+}
+
+/// Header fields are authenticated together with the ciphertext, so a file
+/// can't be edited to claim another key.
+fn associated_data(version: u8, key_id: &KeyId, public_key: &PubKey) -> Result<Vec<u8>> {
+    serialize(&(version, key_id, public_key))
+}
+
+fn encrypt(passphrase: &[u8], kdf: KdfParams, public_key: &PubKey, secret: &SecretVec<u8>) -> Result<KeyFile> {
+    let key_id = KeyId::of(public_key);
+    let aad = associated_data(KEYSTORE_VERSION, &key_id, public_key)?;
+    let sealed = Sealed::seal(passphrase, kdf, &aad, &secret.borrow())?;
+    Ok(KeyFile {
+        version: KEYSTORE_VERSION,
+        key_id,
+        public_key: public_key.clone(),
+        sealed,
+    })
+}
+
+fn decrypt(file: &KeyFile, passphrase: &[u8]) -> Result<SecretVec<u8>> {
+    let aad = associated_data(file.version, &file.key_id, &file.public_key)?;
+    file.sealed
+        .open(passphrase, &aad)
+        .with_context(|| format!("Couldn't unlock key {:?}", file.key_id))
+}
+
+pub(super) fn write_file<T: Serialize>(path: &Path, file: &T) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_vec_pretty(file)?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&temporary, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+impl Keystore {
+    /// Generates a new key and saves it encrypted, the returned keystore is unlocked.
+    pub fn create(
+        path: impl Into<PathBuf>,
+        passphrase: &[u8],
+        algorithm: SignatureAlgorithm,
+        kdf: KdfParams,
+    ) -> Result<Self> {
+        let path = path.into();
+        if path.exists() {
+            bail!("Keystore {:?} already exists", path)
+        }
+        let (private, public) = generate_key(algorithm)?;
+        let secret = algorithm.scheme().export_secret(&private)?;
+        let file = encrypt(passphrase, kdf, &public, &secret)?;
+        write_file(&path, &file)?;
+        Ok(Self {
+            path,
+            file,
+            unlocked: Some(secret),
+        })
+    }
+
+    /// Reads the keystore, it stays locked until `unlock` is called.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let bytes = fs::read(&path).with_context(|| format!("Couldn't read keystore {:?}", path))?;
+        let file: KeyFile = serde_json::from_slice(&bytes)?;
+        if file.version != KEYSTORE_VERSION {
+            bail!(
+                "Keystore {:?} has version {}, expected {}",
+                path,
+                file.version,
+                KEYSTORE_VERSION
+            )
+        }
+        if file.key_id != KeyId::of(&file.public_key) {
+            bail!("Keystore {:?} names a key it doesn't contain", path)
+        }
+        Ok(Self {
+            path,
+            file,
+            unlocked: None,
+        })
+    }
+
+    pub fn unlock(&mut self, passphrase: &[u8]) -> Result<()> {
+        let secret = decrypt(&self.file, passphrase)?;
+        let (_, public) = self.algorithm().scheme().import_secret(&secret.borrow())?;
+        if public != self.file.public_key {
+            bail!("Keystore {:?} holds a key not matching its public key", self.path)
+        }
+        self.unlocked = Some(secret);
+        Ok(())
+    }
+
+    pub fn lock(&mut self) {
+        self.unlocked = None;
+    }
+
+    pub fn key_id(&self) -> &KeyId {
+        &self.file.key_id
+    }
+
+    pub fn public_key(&self) -> &PubKey {
+        &self.file.public_key
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.file.public_key.algorithm
+    }
+
+    pub fn private_key(&self) -> Result<PrivKey> {
+        let secret = self
+            .unlocked
+            .as_ref()
+            .ok_or(anyhow!("Keystore {:?} is locked", self.key_id()))?;
+        let (private, _) = self.algorithm().scheme().import_secret(&secret.borrow())?;
+        Ok(private)
+    }
+
+    /// Replaces the key with a fresh one of the same algorithm. The previous
+    /// file is kept next to the new one, named after the retired key id.
+    pub fn rotate(&mut self, passphrase: &[u8]) -> Result<KeyId> {
+        decrypt(&self.file, passphrase)?;
+        let (private, public) = generate_key(self.algorithm())?;
+        let secret = self.algorithm().scheme().export_secret(&private)?;
+        let file = encrypt(passphrase, self.file.sealed.kdf, &public, &secret)?;
+        let retired = self.path.with_extension(format!("{}.retired", self.key_id().0));
+        fs::copy(&self.path, retired)?;
+        write_file(&self.path, &file)?;
+        self.file = file;
+        self.unlocked = Some(secret);
+        Ok(self.file.key_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::signature::{sign, verify};
+
+    use super::*;
+
+    const CHEAP: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn keystore_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nocoin-keystore-{}", std::process::id()));
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn keeps_identity_across_loads() {
+        let path = keystore_path("identity.json");
+        let created = Keystore::create(&path, b"secret", SignatureAlgorithm::Ed25519, CHEAP).unwrap();
+        let signature = sign(b"data", &created.private_key().unwrap()).unwrap();
+
+        let mut loaded = Keystore::load(&path).unwrap();
+        assert!(loaded.private_key().is_err(), "Loaded keystore must be locked");
+        assert!(loaded.unlock(b"wrong").is_err());
+        loaded.unlock(b"secret").unwrap();
+
+        assert_eq!(loaded.key_id(), created.key_id());
+        let resigned = sign(b"data", &loaded.private_key().unwrap()).unwrap();
+        verify(b"data", &resigned, created.public_key()).unwrap();
+        verify(b"data", &signature, loaded.public_key()).unwrap();
+        loaded.lock();
+        assert!(loaded.private_key().is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_files() {
+        let path = keystore_path("tampered.json");
+        Keystore::create(&path, b"secret", SignatureAlgorithm::RsaPkcs1v15Sha256, CHEAP).unwrap();
+        let mut file: KeyFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+
+        file.version = KEYSTORE_VERSION + 1;
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        assert!(Keystore::load(&path).is_err());
+
+        file.version = KEYSTORE_VERSION;
+        file.sealed.kdf.iterations += 1;
+        fs::write(&path, serde_json::to_vec(&file).unwrap()).unwrap();
+        assert!(Keystore::load(&path).unwrap().unlock(b"secret").is_err());
+    }
+
+    #[test]
+    fn rotation_retires_old_key() {
+        let path = keystore_path("rotated.json");
+        let mut keystore = Keystore::create(&path, b"secret", SignatureAlgorithm::Ed25519, CHEAP).unwrap();
+        let old_id = keystore.key_id().clone();
+
+        assert!(keystore.rotate(b"wrong").is_err());
+        let new_id = keystore.rotate(b"secret").unwrap();
+
+        assert_ne!(new_id, old_id);
+        let mut reloaded = Keystore::load(&path).unwrap();
+        reloaded.unlock(b"secret").unwrap();
+        assert_eq!(reloaded.key_id(), &new_id);
+        let retired = Keystore::load(path.with_extension(format!("{}.retired", old_id.0))).unwrap();
+        assert_eq!(retired.key_id(), &old_id);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::network::{Network, Node, NodeId};
+
+pub const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+#[derive(Default)]
+pub struct Liveness {
+    missed_heartbeats: HashMap<NodeId, u32>,
+}
+
+impl Liveness {
+    pub fn missed_heartbeats(&self, id: &NodeId) -> u32 {
+        self.missed_heartbeats.get(id).copied().unwrap_or(0)
+    }
+}
+
+pub fn record_heartbeat(network: &mut Network, id: &NodeId) {
+    network.liveness.missed_heartbeats.remove(id);
+}
+
+/// Counts a failed ping or message delivery. Once the node misses
+/// `MAX_MISSED_HEARTBEATS` in a row it is evicted and returned.
+pub fn record_missed_heartbeat(network: &mut Network, id: &NodeId) -> Option<Node> {
+    if *id == network.user.node.id {
+        return None;
+    }
+    let missed = network.liveness.missed_heartbeats.entry(*id).or_insert(0);
+    *missed += 1;
+    if *missed >= MAX_MISSED_HEARTBEATS {
+        remove_node(network, id).ok()
+    } else {
+        None
+    }
+}
+
+pub fn remove_node(network: &mut Network, id: &NodeId) -> Result<Node> {
+    if *id == network.user.node.id {
+        return Err(anyhow!("Node can't remove itself from the network"));
+    }
+    let position = network
+        .nodes
+        .iter()
+        .position(|n| n.id == *id)
+        .ok_or(anyhow!("No node with id: {:?}", id))?;
+    network.liveness.missed_heartbeats.remove(id);
+    Ok(network.nodes.remove(position))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::domain::{
+        network::{acknowledge_node, try_start_new_network},
+        signature::{generate_key, SignatureAlgorithm},
+    };
+
+    use super::*;
+
+    fn network_with_peer() -> (Network, NodeId) {
+        let (private, public) = generate_key(SignatureAlgorithm::default()).unwrap();
+        let mut network =
+            try_start_new_network(SocketAddr::from(([127, 0, 0, 1], 8100)), private, public.clone())
+                .unwrap();
+        let peer = Node {
+            id: NodeId(8101),
+            addr: SocketAddr::from(([127, 0, 0, 1], 8101)),
+            pub_key: public,
+        };
+        acknowledge_node(&mut network, peer).unwrap();
+        (network, NodeId(8101))
+    }
+
+    #[test]
+    fn evicts_after_missed_heartbeats() {
+        let (mut network, peer) = network_with_peer();
+
+        for _ in 1..MAX_MISSED_HEARTBEATS {
+            assert!(record_missed_heartbeat(&mut network, &peer).is_none());
+        }
+        assert_eq!(network.liveness.missed_heartbeats(&peer), MAX_MISSED_HEARTBEATS - 1);
+
+        let evicted = record_missed_heartbeat(&mut network, &peer);
+
+        assert_eq!(evicted.map(|n| n.id), Some(peer));
+        assert!(network.nodes.iter().all(|n| n.id != peer));
+        assert_eq!(network.liveness.missed_heartbeats(&peer), 0);
+    }
+
+    #[test]
+    fn heartbeat_resets_missed_count() {
+        let (mut network, peer) = network_with_peer();
+
+        for _ in 0..MAX_MISSED_HEARTBEATS * 2 {
+            record_missed_heartbeat(&mut network, &peer);
+            record_heartbeat(&mut network, &peer);
+        }
+
+        assert!(network.nodes.iter().any(|n| n.id == peer));
+    }
+
+    #[test]
+    fn never_removes_itself() {
+        let (mut network, _) = network_with_peer();
+        let own_id = network.user.node.id;
+
+        for _ in 0..MAX_MISSED_HEARTBEATS {
+            assert!(record_missed_heartbeat(&mut network, &own_id).is_none());
+        }
+        assert!(remove_node(&mut network, &own_id).is_err());
+    }
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use super::{
-    blockchain::{Nonce, MAX_TRANSACTION_COUNT},
+    blockchain::{Block, Draft, Nonce, MAX_TRANSACTION_COUNT},
     serialization::serialize,
     transaction::{ProvenTransaction}, network::NodeId,
 };
@@ -21,21 +21,12 @@ impl Default for BlockHash {
     }
 }
 
-impl BlockHash {
-    pub fn try_new(bytes: String) -> Result<Self> {
-        if bytes.len() != HASH_LEN {
-            bail!("Hash must be {} bits long", HASH_LEN)
-        } else {
-            Ok(Self(bytes))
-        }
-    }
-}
-
 pub async fn try_mine_any_async<'a>(
+    draft: &Draft,
     difficulty: u8,
-    transactions: &'a Vec<ProvenTransaction>,
+    transactions: &'a [ProvenTransaction],
     mining_reward: &'a ProvenTransaction,
-    miner_id: NodeId,
+    _miner_id: NodeId,
 ) -> Result<(BlockHash, Nonce, Vec<&'a ProvenTransaction>)> {
     let mut split_pull: Vec<Vec<&ProvenTransaction>> =
         Vec::with_capacity(transactions.len() / MAX_TRANSACTION_COUNT);
@@ -54,7 +45,7 @@ pub async fn try_mine_any_async<'a>(
     }
     let mut tasks: FuturesUnordered<_> = split_pull
         .into_iter()
-        .map(|ts| async { mine_async(&ts, difficulty).await.map(|x| (x.0, x.1, ts)) })
+        .map(|ts| async { mine_async(draft, &ts, difficulty).await.map(|x| (x.0, x.1, ts)) })
         .collect();
     if let Some(Ok(nonce)) = tasks.next().await {
         Ok(nonce)
@@ -64,15 +55,16 @@ pub async fn try_mine_any_async<'a>(
 }
 
 pub fn try_mine_any(
+    draft: &Draft,
     difficulty: u8,
-    transactions: &Vec<ProvenTransaction>,
+    transactions: &[ProvenTransaction],
 ) -> Result<(BlockHash, Nonce)> {
     if transactions.is_empty() {
-        return mine(&transactions, difficulty);
+        return mine(draft, transactions, difficulty);
     }
     for i in (0..transactions.len()).step_by(MAX_TRANSACTION_COUNT) {
         let transactions = &transactions[i..transactions.len().min(i + MAX_TRANSACTION_COUNT)];
-        if let Ok(r) = mine(transactions, difficulty) {
+        if let Ok(r) = mine(draft, transactions, difficulty) {
             return Ok(r);
         }
     }
@@ -80,11 +72,12 @@ pub fn try_mine_any(
 }
 
 async fn mine_async(
+    draft: &Draft,
     transactions: &Vec<&ProvenTransaction>,
     difficulty: u8,
 ) -> Result<(BlockHash, Nonce)> {
     log::info!("Attempt to mine {:?} with difficulty {:?}", transactions, difficulty);
-    let block_data = serialize(transactions)?;
+    let block_data = serialize(&(draft, transactions))?;
     tokio::task::spawn(async move {
         (0..u32::MAX)
             .map(|n| (Nonce(n), hash_block(&block_data, Nonce(n))))
@@ -95,8 +88,8 @@ async fn mine_async(
     .await?
 }
 
-fn mine(transactions: &[ProvenTransaction], difficulty: u8) -> Result<(BlockHash, Nonce)> {
-    let block_data = serialize(&transactions)?;
+fn mine(draft: &Draft, transactions: &[ProvenTransaction], difficulty: u8) -> Result<(BlockHash, Nonce)> {
+    let block_data = serialize(&(draft, transactions))?;
     (0..u32::MAX)
         .map(|n| (Nonce(n), hash_block(&block_data, Nonce(n))))
         .find(|(_n, hash)| hash_matches(hash, difficulty))
@@ -105,21 +98,26 @@ fn mine(transactions: &[ProvenTransaction], difficulty: u8) -> Result<(BlockHash
 }
 
 fn hash_matches(hash: &str, difficulty: u8) -> bool {
-    hash[..difficulty as usize].chars().all(|b| b == '0')
+    hash.get(..difficulty as usize).is_some_and(|prefix| prefix.chars().all(|b| b == '0'))
 }
 
-pub fn prove_mined_block(
-    transactions: &Vec<ProvenTransaction>,
-    difficulty: u8,
-    nonce: Nonce,
-) -> Result<()> {
-    let block_data = serialize(transactions)?;
-    let hash = hash_block(&block_data, nonce);
+/// The block's hash must be the one of its header and transactions and
+/// satisfy its difficulty, which mustn't be below `min_difficulty`.
+pub fn prove_mined_block(block: &Block, min_difficulty: u8) -> Result<()> {
+    let difficulty = block.header.difficulty;
+    if difficulty < min_difficulty {
+        bail!("Block {} is mined with difficulty {}, at least {} is needed", block.header.index.0, difficulty, min_difficulty)
+    }
+    let block_data = serialize(&(Draft::of(&block.header), &block.transactions.0))?;
+    let hash = hash_block(&block_data, block.nonce);
+    if hash != block.header.hash.0 {
+        bail!("Block hash doesn't match: block {} claims hash {:?}, but hashes to {:?}", block.header.index.0, block.header.hash, hash)
+    }
     if hash_matches(&hash, difficulty) {
         Ok(())
     } else {
         bail!("Block hash doesn't match: hashed transactions {:?} yielded hash {:?} which doesn't satisfy difficulty of {:?}",
-            transactions,
+            block.transactions,
             hash,
             difficulty,
         )
@@ -138,17 +136,17 @@ mod tests {
     use rand::{Rng, SeedableRng};
 
     use crate::domain::{
-        blockchain::{BlocksTransactions, NoCoin},
+        blockchain::{genesis_block, BlockHeader, BlocksTransactions, NoCoin},
         network::NodeId,
-        rsa_verification::{encode_message, generate_key},
-        transaction::{AffordableTransaction, Transaction},
+        signature::{generate_key, sign, SignatureAlgorithm},
+        transaction::{AffordableTransaction, Proof, Transaction},
     };
 
     use super::*;
 
     fn some_transactions() -> Vec<ProvenTransaction> {
         let mut seed = [0; 32];
-        let key = generate_key().unwrap();
+        let key = generate_key(SignatureAlgorithm::default()).unwrap();
 
         seed[0] = 1;
         seed[1] = 0xA;
@@ -156,7 +154,7 @@ mod tests {
         let mut rng = rand::prelude::StdRng::from_seed(seed);
         (0..rng.gen_range(1..=10))
             .map(|_| ProvenTransaction {
-                proof: Some(encode_message(&vec![rng.gen(), rng.gen()], &key.0).unwrap()),
+                proof: Some(Proof::Single(sign(&[rng.gen(), rng.gen()], &key.0).unwrap())),
                 transaction: AffordableTransaction(Transaction::new(
                     Some(NodeId(rng.gen())),
                     NodeId(rng.gen()),
@@ -167,19 +165,21 @@ mod tests {
             .collect()
     }
 
+    fn draft() -> Draft {
+        Draft::on(&genesis_block())
+    }
+
     #[tokio::test]
     async fn possible_difficulty() {
         let transactions = BlocksTransactions(some_transactions());
+        let draft = draft();
 
         for dif in 1..4 {
-            let (_, nonce) = mine_async(&transactions.0.iter().collect(), dif as u8)
+            let (_, nonce) = mine_async(&draft, &transactions.0.iter().collect(), dif as u8)
                 .await
                 .unwrap();
 
-            let hash = hash_block(
-                &serialize::<Vec<&ProvenTransaction>>(&transactions.0.iter().collect()).unwrap(),
-                nonce,
-            );
+            let hash = hash_block(&serialize(&(&draft, &transactions.0)).unwrap(), nonce);
 
             assert_eq!(
                 hash[..dif],
@@ -194,16 +194,28 @@ mod tests {
     async fn hash_and_verify() {
         let transactions = some_transactions();
         let difficulty = 3;
+        let draft = draft();
 
-        let (_, nonce) = mine_async(&transactions.iter().collect(), difficulty)
+        let (hash, nonce) = mine_async(&draft, &transactions.iter().collect(), difficulty)
             .await
             .unwrap();
-        let proof = prove_mined_block(&transactions, difficulty, nonce);
-
-        assert!(proof.is_ok());
-
-        let invalid_proof = prove_mined_block(&transactions, difficulty, Nonce(nonce.0 + 1));
-
-        assert!(invalid_proof.is_err());
+        let block = Block {
+            header: BlockHeader::new(draft, hash),
+            mined_by: NodeId(1),
+            transactions: BlocksTransactions(transactions),
+            nonce,
+        };
+
+        assert!(prove_mined_block(&block, difficulty).is_ok());
+        assert!(prove_mined_block(&block, block.header.difficulty + 1).is_err());
+        let mut wrong_nonce = block.clone();
+        wrong_nonce.nonce = Nonce(nonce.0 + 1);
+        assert!(prove_mined_block(&wrong_nonce, difficulty).is_err());
+        let mut moved = block.clone();
+        moved.header.index = moved.header.index.next_index();
+        assert!(prove_mined_block(&moved, difficulty).is_err());
+        let mut impossible = block;
+        impossible.header.difficulty = u8::MAX;
+        assert!(prove_mined_block(&impossible, difficulty).is_err());
     }
 }
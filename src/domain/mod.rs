@@ -1,23 +1,54 @@
 mod blockchain;
+mod contract;
+mod ed25519_verification;
+mod hd_wallet;
+mod keystore;
+mod envelope;
+mod forks;
+mod liveness;
 mod mining;
+mod multisig;
 mod network;
+mod params;
+mod peers;
+mod reputation;
 mod rsa_verification;
+mod script;
 mod serialization;
+mod signature;
 #[cfg(test)]
-mod testing;
+pub(crate) mod testing;
+mod token;
 mod transaction;
 mod wallet;
 
-pub use blockchain::{Block, Blockchain};
-pub use network::{Network, Node, User, NodeId};
-pub use rsa_verification::{PubKey, RSAEncodedMsg};
-pub use transaction::{Transaction, ProvenTransaction};
+pub use blockchain::{Block, Blockchain, Draft, NoCoin};
+pub use envelope::{open, seal, Envelope};
+pub use network::{Network, Node, NodeId};
+pub use hd_wallet::HdWallet;
+pub use keystore::{KdfParams, Keystore};
+pub use multisig::{
+    add_cosignature, cosign_as_node, partial_transaction, propose_multisig, try_register_multisig,
+    Cosignature, Cosigned, MultisigPolicy,
+};
+pub use contract::{try_send_contract, ContractAction, Instr};
+pub use params::ChainParams;
+pub use peers::{set_peer_policy, Eviction, PeerPolicy};
+pub use script::{try_register_script, Script, ScriptTemplate};
+pub use token::{try_send_token, TokenAction};
+pub use reputation::{Misbehavior, Reputation, ReputationConfig};
+pub use transaction::{Lock, Proof, Transaction, ProvenTransaction, SignedTransaction, TransactionId};
 
 pub use network::{
-    acknowledge_node, try_add_block, try_add_transaction, try_adopt_network,
-    try_adopt_pending_transactions, try_create_node, try_start_new_network,
-    create_mined_block,
+    try_acknowledge_node, try_add_block, try_add_transaction, try_adopt_network,
+    try_adopt_pending_transactions, try_register_node, try_pay, try_send_payment, try_start_new_network, spendable,
+    create_mined_block, mineable_transactions, pending_payments,
+};
+pub use liveness::{record_heartbeat, record_missed_heartbeat, remove_node};
+pub use signature::{generate_key, PrivKey, PubKey, SignatureAlgorithm};
+pub use serialization::{
+    decode, encode, frame_len, BINARY_CONTENT_TYPE, FRAME_HEADER_LEN, WIRE_VERSION,
 };
-pub use rsa_verification::generate_key;
-pub use mining::try_mine_any_async;
+pub use mining::{try_mine_any, try_mine_any_async};
 pub use transaction::create_mining_reward;
+pub use wallet::{calculate_all_wallets, calculate_wallet, calculate_token_balances, statement_csv, HistoryEntry};
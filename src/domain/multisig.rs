@@ -0,0 +1,306 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    blockchain::{Blockchain, NoCoin},
+    network::{ensure_key_fits, try_add_transaction, Network, NodeId},
+    serialization::serialize,
+    signature::{sign, verify, PrivKey, PubKey, Signature},
+    transaction::{
+        approve, map_to_affordable, Proof, SignedTransaction, Transaction, TransactionId,
+    },
+};
+
+pub const MAX_COSIGNERS: usize = 16;
+
+/// Spending rule of a multisig account, `threshold` of the `keys` have to sign.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    pub keys: Vec<PubKey>,
+}
+
+/// Signature of one co-signer, `key_index` points into the policy's keys.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Cosignature {
+    pub key_index: u8,
+    pub signature: Signature,
+}
+
+impl MultisigPolicy {
+    /// Derived like addresses of keys, but from the whole policy, so changing
+    /// any key or the threshold gives a different account.
+    pub fn address(&self) -> Result<NodeId> {
+        let mut data = b"nocoin multisig".to_vec();
+        data.extend(serialize(self)?);
+        Ok(NodeId::derived(&Sha256::digest(data)))
+    }
+
+    pub(super) fn validate(&self, network: &Network) -> Result<()> {
+        if self.keys.len() > MAX_COSIGNERS {
+            bail!("Multisig can have at most {} keys, has {}", MAX_COSIGNERS, self.keys.len())
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            bail!("Threshold {} doesn't fit {} keys", self.threshold, self.keys.len())
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            ensure_key_fits(network, key)?;
+            if self.keys[..i].contains(key) {
+                bail!("Key {:?} is in the policy twice", key)
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self, index: u8) -> Result<&PubKey> {
+        self.keys
+            .get(index as usize)
+            .ok_or(anyhow!("Policy has no key number {}", index))
+    }
+
+    /// Every signature has to be valid, and they have to come from at least
+    /// `threshold` different keys.
+    pub(super) fn verify(&self, data: &[u8], cosignatures: &[Cosignature]) -> Result<()> {
+        let mut signed = BTreeSet::new();
+        for cosignature in cosignatures {
+            verify(data, &cosignature.signature, self.key(cosignature.key_index)?)?;
+            if !signed.insert(cosignature.key_index) {
+                bail!("Key number {} signed twice", cosignature.key_index)
+            }
+        }
+        if signed.len() < self.threshold as usize {
+            bail!("Only {} of required {} keys signed", signed.len(), self.threshold)
+        }
+        Ok(())
+    }
+}
+
+/// Registration transactions are the only place policies are kept.
+pub(super) fn find_policy<'a>(blockchain: &'a Blockchain, address: &NodeId) -> Option<&'a MultisigPolicy> {
+    blockchain
+        .0
+        .iter()
+        .flat_map(|b| b.transactions.0.iter())
+        .map(|t| &t.transaction.0)
+        .find(|t| t.to == *address && t.multisig.is_some())
+        .and_then(|t| t.multisig.as_ref())
+}
+
+/// Done by each co-signer on their own, only the signature leaves their machine.
+fn cosign(
+    transaction: &Transaction,
+    policy: &MultisigPolicy,
+    priv_key: &PrivKey,
+    pub_key: &PubKey,
+) -> Result<Cosignature> {
+    let key_index = policy
+        .keys
+        .iter()
+        .position(|k| k == pub_key)
+        .ok_or(anyhow!("Key {:?} is not a co-signer", pub_key))?;
+    Ok(Cosignature {
+        key_index: key_index as u8,
+        signature: sign(&serialize(transaction)?, priv_key)?,
+    })
+}
+
+/// Node co-signs a payment of a multisig account whose policy holds its key.
+pub fn cosign_as_node(network: &Network, transaction: &Transaction) -> Result<Cosignature> {
+    let sender = transaction
+        .from
+        .ok_or(anyhow!("Mining reward can't be signed by co-signers"))?;
+    let policy = find_policy(&network.blockchain, &sender)
+        .ok_or(anyhow!("{:?} is not a registered multisig account", sender))?;
+    cosign(transaction, policy, &network.user.priv_key, &network.user.node.pub_key)
+}
+
+/// Transaction from a multisig account waiting for its co-signers.
+#[derive(Debug, Serialize, Clone)]
+pub struct PartialTransaction {
+    pub transaction: Transaction,
+    pub threshold: u8,
+    pub cosignatures: Vec<Cosignature>,
+}
+
+#[derive(Default)]
+pub struct Cosigning(HashMap<TransactionId, PartialTransaction>);
+
+pub enum Cosigned {
+    Pending(PartialTransaction),
+    /// Enough signatures were collected, the transaction is in the poll now.
+    Complete(TransactionId, SignedTransaction),
+}
+
+/// Node pays `ammount` into a new multisig account, returns the account's address.
+pub fn try_register_multisig(
+    network: &mut Network,
+    policy: MultisigPolicy,
+    ammount: NoCoin,
+    fee: NoCoin,
+) -> Result<(NodeId, TransactionId, SignedTransaction)> {
+    let address = policy.address()?;
+    let transaction = Transaction {
+        multisig: Some(policy),
+        ..Transaction::new(Some(network.user.node.id), address, fee, ammount)
+    };
+    let proven = approve(map_to_affordable(network, transaction)?, &network.user.priv_key)?;
+    let signed = SignedTransaction {
+        transaction: proven.transaction.0,
+        proof: proven.proof.ok_or(anyhow!("Registration wasn't signed"))?,
+    };
+    let id = try_add_transaction(network, signed.transaction.clone(), signed.proof.clone())?;
+    Ok((address, id, signed))
+}
+
+/// Starts collecting signatures for a payment from a registered multisig account.
+pub fn propose_multisig(network: &mut Network, transaction: Transaction) -> Result<TransactionId> {
+    let sender = transaction
+        .from
+        .ok_or(anyhow!("Mining reward can't be signed by co-signers"))?;
+    let threshold = find_policy(&network.blockchain, &sender)
+        .ok_or(anyhow!("{:?} is not a registered multisig account", sender))?
+        .threshold;
+    let transaction = map_to_affordable(network, transaction)?.0;
+    let id = transaction.id()?;
+    network.cosigning.0.entry(id.clone()).or_insert(PartialTransaction {
+        transaction,
+        threshold,
+        cosignatures: vec![],
+    });
+    Ok(id)
+}
+
+pub fn partial_transaction<'a>(network: &'a Network, id: &TransactionId) -> Option<&'a PartialTransaction> {
+    network.cosigning.0.get(id)
+}
+
+/// A newer signature of the same co-signer replaces the older one.
+pub fn add_cosignature(
+    network: &mut Network,
+    id: &TransactionId,
+    cosignature: Cosignature,
+) -> Result<Cosigned> {
+    let partial = network
+        .cosigning
+        .0
+        .get(id)
+        .ok_or(anyhow!("No transaction {:?} waits for signatures", id))?;
+    let sender = partial
+        .transaction
+        .from
+        .ok_or(anyhow!("Mining reward can't be signed by co-signers"))?;
+    let policy = find_policy(&network.blockchain, &sender)
+        .ok_or(anyhow!("{:?} is not a registered multisig account", sender))?;
+    let serialized = serialize(&partial.transaction)?;
+    verify(&serialized, &cosignature.signature, policy.key(cosignature.key_index)?)?;
+
+    let partial = network.cosigning.0.get_mut(id).unwrap();
+    partial.cosignatures.retain(|c| c.key_index != cosignature.key_index);
+    partial.cosignatures.push(cosignature);
+    if partial.cosignatures.len() < partial.threshold as usize {
+        return Ok(Cosigned::Pending(partial.clone()));
+    }
+    let partial = network.cosigning.0.remove(id).unwrap();
+    let signed = SignedTransaction {
+        transaction: partial.transaction,
+        proof: Proof::Multisig(partial.cosignatures),
+    };
+    let id = try_add_transaction(network, signed.transaction.clone(), signed.proof.clone())?;
+    Ok(Cosigned::Complete(id, signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        signature::generate_key,
+        testing::{mine, network},
+    };
+
+    use super::*;
+
+    fn custody() -> (Network, Vec<(PrivKey, PubKey)>, NodeId) {
+        let algorithm = Default::default();
+        let mut network = network(8100);
+        mine(&mut network);
+        let cosigners: Vec<_> = (0..3).map(|_| generate_key(algorithm).unwrap()).collect();
+        let policy = MultisigPolicy {
+            threshold: 2,
+            keys: cosigners.iter().map(|(_, public)| public.clone()).collect(),
+        };
+        let (address, _, _) = try_register_multisig(&mut network, policy, NoCoin(6.), NoCoin(1.)).unwrap();
+        mine(&mut network);
+        (network, cosigners, address)
+    }
+
+    #[test]
+    fn spending_needs_threshold_of_signatures() {
+        let (mut network, cosigners, address) = custody();
+        let policy = find_policy(&network.blockchain, &address).unwrap().clone();
+        let payment = Transaction::new(Some(address), NodeId(8101), NoCoin(1.), NoCoin(4.));
+        let signatures: Vec<_> = cosigners
+            .iter()
+            .map(|(private, public)| cosign(&payment, &policy, private, public).unwrap())
+            .collect();
+
+        let single = Proof::Multisig(vec![signatures[0].clone()]);
+        assert!(try_add_transaction(&mut network, payment.clone(), single).is_err());
+        let repeated = Proof::Multisig(vec![signatures[2].clone(), signatures[2].clone()]);
+        assert!(try_add_transaction(&mut network, payment.clone(), repeated).is_err());
+        let (node_key, _) = generate_key(Default::default()).unwrap();
+        let forged = Proof::Single(sign(&serialize(&payment).unwrap(), &node_key).unwrap());
+        assert!(try_add_transaction(&mut network, payment.clone(), forged).is_err());
+
+        let enough = Proof::Multisig(vec![signatures[2].clone(), signatures[0].clone()]);
+        try_add_transaction(&mut network, payment, enough).unwrap();
+    }
+
+    #[test]
+    fn collects_partial_signatures() {
+        let (mut network, cosigners, address) = custody();
+        let policy = find_policy(&network.blockchain, &address).unwrap().clone();
+        let payment = Transaction::new(Some(address), NodeId(8101), NoCoin(1.), NoCoin(4.));
+        let id = propose_multisig(&mut network, payment.clone()).unwrap();
+        let (outsider, outsider_public) = generate_key(Default::default()).unwrap();
+        let mut forged = cosign(&payment, &policy, &cosigners[0].0, &cosigners[0].1).unwrap();
+        forged.signature = sign(&serialize(&payment).unwrap(), &outsider).unwrap();
+        assert!(cosign(&payment, &policy, &outsider, &outsider_public).is_err());
+        assert!(add_cosignature(&mut network, &id, forged).is_err());
+
+        let first = cosign(&payment, &policy, &cosigners[1].0, &cosigners[1].1).unwrap();
+        assert!(matches!(
+            add_cosignature(&mut network, &id, first.clone()).unwrap(),
+            Cosigned::Pending(p) if p.cosignatures.len() == 1
+        ));
+        assert!(matches!(
+            add_cosignature(&mut network, &id, first).unwrap(),
+            Cosigned::Pending(p) if p.cosignatures.len() == 1
+        ));
+        let second = cosign(&payment, &policy, &cosigners[0].0, &cosigners[0].1).unwrap();
+        let Cosigned::Complete(completed, _) = add_cosignature(&mut network, &id, second).unwrap() else {
+            panic!("Two of three signatures should be enough")
+        };
+
+        assert_eq!(completed, id);
+        assert!(partial_transaction(&network, &id).is_none());
+        assert_eq!(network.transactions_poll.len(), 1);
+    }
+
+    #[test]
+    fn node_cosigns_only_accounts_holding_its_key() {
+        let (mut network, _, custodied) = custody();
+        let policy = MultisigPolicy { threshold: 1, keys: vec![network.user.node.pub_key.clone()] };
+        let (own, _, _) = try_register_multisig(&mut network, policy, NoCoin(3.), NoCoin(1.)).unwrap();
+        mine(&mut network);
+        let foreign = Transaction::new(Some(custodied), NodeId(8101), NoCoin(1.), NoCoin(4.));
+        let payment = Transaction::new(Some(own), NodeId(8101), NoCoin(1.), NoCoin(1.));
+        let id = propose_multisig(&mut network, payment.clone()).unwrap();
+
+        let cosignature = cosign_as_node(&network, &payment).unwrap();
+
+        assert!(cosign_as_node(&network, &foreign).is_err());
+        assert!(matches!(add_cosignature(&mut network, &id, cosignature).unwrap(), Cosigned::Complete(..)));
+    }
+}
@@ -3,12 +3,26 @@ use std::{collections::HashMap, net::SocketAddr};
 use anyhow::{anyhow, bail, Result};
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use super::{
-    blockchain::{genesis_block, verify_blockchain, Blockchain, NoCoin, Nonce, BlocksTransactions, BlockHeader},
+    blockchain::{ensure_timely, genesis_block, verify_blockchain, Blockchain, NoCoin, Nonce, BlocksTransactions, BlockHeader, Draft},
+    contract::ContractLedger,
+    envelope::{open, unix_timestamp, Envelope, ReplayGuard},
+    forks::{extends, prune, try_add_side_block, Forks},
+    hd_wallet::HdWallet,
+    liveness::Liveness,
     mining::{prove_mined_block, BlockHash},
-    rsa_verification::{generate_key, PrivKey, PubKey},
-    transaction::{verify_transaction, ProvenTransaction},
+    multisig::Cosigning,
+    params::ChainParams,
+    peers::{admit, PeerPolicy},
+    reputation::OutOfSync,
+    signature::{PrivKey, PubKey},
+    transaction::{
+        create_transaction, ensure_affordable_with_pending, pending_spends, verify_transaction, Lock, Proof, ProvenTransaction, SignedTransaction,
+        TransactionId,
+    },
+    wallet::{calculate_wallet, HistoryIndex},
     Block, Transaction,
 };
 
@@ -16,7 +30,24 @@ use super::{
 pub struct NodeId(pub usize);
 
 impl NodeId {
-    pub const MAX: NodeId = NodeId(usize::MAX);
+    /// Ids of nodes are their ports, ids derived from keys have the top bit
+    /// set, so the two never collide.
+    const DERIVED_BIT: usize = 1 << (usize::BITS - 1);
+
+    /// Address of an account which isn't a node, owned by whoever holds the key.
+    pub fn of_key(key: &PubKey) -> NodeId {
+        Self::derived(&Sha256::digest(&key.bytes))
+    }
+
+    pub(super) fn derived(digest: &[u8]) -> NodeId {
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&digest[..8]);
+        NodeId(u64::from_be_bytes(prefix) as usize | Self::DERIVED_BIT)
+    }
+
+    pub fn is_derived(&self) -> bool {
+        self.0 & Self::DERIVED_BIT != 0
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -29,9 +60,15 @@ pub struct Node {
 pub struct Network {
     pub user: User,
     pub nodes: Vec<Node>,
+    pub peer_policy: PeerPolicy,
+    pub params: ChainParams,
     pub blockchain: Blockchain,
+    pub forks: Forks,
     pub transactions_poll: Vec<ProvenTransaction>,
     pub cache: Cache,
+    pub liveness: Liveness,
+    pub replay_guard: ReplayGuard,
+    pub cosigning: Cosigning,
     _void: (),
 }
 
@@ -43,14 +80,29 @@ impl Network {
 
 pub struct Cache {
     pub wallet: HashMap<NodeId, NoCoin>,
+    pub history: HistoryIndex,
+    pub contracts: ContractLedger,
 }
 
 pub struct User {
     pub node: Node,
     pub priv_key: PrivKey,
+    pub wallet: Option<HdWallet>,
+}
+
+pub(super) fn ensure_key_fits(network: &Network, key: &PubKey) -> Result<()> {
+    if key.algorithm != network.params.signature_algorithm {
+        bail!(
+            "Network signs with {:?}, but key is for {:?}",
+            network.params.signature_algorithm,
+            key.algorithm
+        )
+    }
+    Ok(())
 }
 
 pub fn try_create_node(network: &mut Network, addr: SocketAddr, key: PubKey) -> Result<&Node> {
+    ensure_key_fits(network, &key)?;
     let id = NodeId(addr.port().into());
     if network.nodes.iter().any(|n| n.id == id) {
         Err(anyhow::anyhow!(
@@ -63,28 +115,123 @@ pub fn try_create_node(network: &mut Network, addr: SocketAddr, key: PubKey) ->
             addr,
             pub_key: key,
         };
-        network.nodes.push(node);
-        Ok(&network.nodes.last().unwrap())
+        admit(network, node)?;
+        Ok(network.nodes.last().unwrap())
     }
 }
 
-pub fn acknowledge_node(network: &mut Network, node: Node) -> Result<()> {
+/// Opens a registration, which must be signed with the key it presents and come
+/// from the id the node will be given.
+fn open_registration(network: &mut Network, registration: Envelope<Node>) -> Result<Node> {
+    let claimed_key = registration.payload.pub_key.clone();
+    let expected_id = NodeId(registration.payload.addr.port().into());
+    if registration.sender != expected_id {
+        bail!(
+            "Registration sent as {:?} for node which would be {:?}",
+            registration.sender,
+            expected_id
+        )
+    }
+    let node = open(network, registration, Some(&claimed_key))?;
+    Ok(Node {
+        id: expected_id,
+        ..node
+    })
+}
+
+/// Adds a node which asked to join.
+pub fn try_register_node(network: &mut Network, registration: Envelope<Node>) -> Result<&Node> {
+    let node = open_registration(network, registration)?;
+    try_create_node(network, node.addr, node.pub_key)
+}
+
+/// Adds a node which registered with another member, given the registration it signed itself.
+pub fn try_acknowledge_node(network: &mut Network, registration: Envelope<Node>) -> Result<()> {
+    let node = open_registration(network, registration)?;
+    acknowledge_node(network, node)
+}
+
+pub(super) fn acknowledge_node(network: &mut Network, node: Node) -> Result<()> {
+    ensure_key_fits(network, &node.pub_key)?;
     if network.nodes.iter().any(|n| n.id == node.id) {
         bail!("Node {:?} is already in the network", node)
     } else {
-        network.nodes.push(node);
-        Ok(())
+        admit(network, node)
     }
 }
 
 pub fn try_add_transaction(
     network: &mut Network,
     transaction: Transaction,
-    proof: Vec<u8>,
-) -> Result<()> {
+    proof: Proof,
+) -> Result<TransactionId> {
     let transaction = verify_transaction(network, transaction, proof)?;
+    add_to_poll(network, transaction)
+}
+
+/// Pays from the node's own account, the signed transaction is returned so it can be gossiped.
+pub fn try_send_payment(
+    network: &mut Network,
+    recipient: NodeId,
+    ammount: NoCoin,
+    fee: NoCoin,
+    lock: Option<Lock>,
+) -> Result<(TransactionId, SignedTransaction)> {
+    let transaction = create_transaction(network, &recipient, ammount, fee, lock)?;
+    send(network, transaction)
+}
+
+/// Pays through the loaded wallet, one transaction per account it draws from,
+/// or from the node's own account when there is no wallet.
+pub fn try_pay(
+    network: &mut Network,
+    recipient: NodeId,
+    ammount: NoCoin,
+    fee: NoCoin,
+    lock: Option<Lock>,
+) -> Result<Vec<(TransactionId, SignedTransaction)>> {
+    let Some(wallet) = network.user.wallet.as_ref() else {
+        return Ok(vec![try_send_payment(network, recipient, ammount, fee, lock)?]);
+    };
+    let payments = wallet.create_payment(network, recipient, ammount, fee, lock)?;
+    payments.into_iter().map(|payment| send(network, payment)).collect()
+}
+
+/// Coins the node can still pay with through `try_pay`, less what its pending payments spend.
+pub fn spendable(network: &Network) -> NoCoin {
+    let accounts = match network.user.wallet.as_ref() {
+        Some(wallet) => wallet.accounts().iter().map(|a| a.address).collect(),
+        None => vec![network.user.node.id],
+    };
+    accounts.iter().fold(NoCoin(0.), |sum, account| {
+        sum + calculate_wallet(account, &network.blockchain) - pending_spends(network, account)
+    })
+}
+
+fn send(network: &mut Network, transaction: ProvenTransaction) -> Result<(TransactionId, SignedTransaction)> {
+    let signed = SignedTransaction {
+        transaction: transaction.transaction.0.clone(),
+        proof: transaction.proof.clone().ok_or(anyhow!("Payment wasn't signed"))?,
+    };
+    let id = add_to_poll(network, transaction)?;
+    Ok((id, signed))
+}
+
+pub(super) fn add_to_poll(network: &mut Network, transaction: ProvenTransaction) -> Result<TransactionId> {
+    let id = transaction.transaction.0.id()?;
+    if network
+        .transactions_poll
+        .iter()
+        .any(|t| t.transaction.0 == transaction.transaction.0)
+    {
+        bail!(OutOfSync(format!("Transaction {:?} is already pending", id)))
+    }
+    if network.blockchain.contains(&transaction.transaction.0) {
+        bail!(OutOfSync(format!("Transaction {:?} is already mined", id)))
+    }
+    ensure_affordable_with_pending(network, &transaction.transaction.0)?;
     network.transactions_poll.push(transaction);
-    Ok(())
+    Ok(id)
 }
 
 fn remove_transactions_from_poll(poll: &mut Vec<ProvenTransaction>, transactions: &[ProvenTransaction]) -> Result<()> {
@@ -93,17 +240,90 @@ fn remove_transactions_from_poll(poll: &mut Vec<ProvenTransaction>, transactions
         .filter(|t| poll.iter().any(|x| x.transaction.0 == t.transaction.0))
         .collect::<Vec<_>>();
     if removable_from_poll.len() < transactions.len() - 1 {
-        bail!("Transactions in the block (except reward) are not in the poll. Rejecting block.");
+        bail!(OutOfSync("Transactions in the block (except reward) are not in the poll. Rejecting block.".to_owned()));
     } else {
         poll.retain(|t| transactions.iter().all(|x| x.transaction.0 != t.transaction.0));
         Ok(())
     }
 }
 
+/// Pending transactions which the next block may include, locked ones wait in the poll.
+pub fn mineable_transactions(network: &Network) -> Vec<ProvenTransaction> {
+    let height = network.blockchain.height() + 1;
+    let now = unix_timestamp() as usize;
+    network
+        .transactions_poll
+        .iter()
+        .filter(|t| t.transaction.0.is_mature(height, now))
+        .cloned()
+        .collect()
+}
+
+/// Payment waiting in the poll, `unlocked` tells if it may already be mined.
+#[derive(Debug, Serialize, Clone)]
+pub struct PendingPayment {
+    pub id: TransactionId,
+    pub transaction: Transaction,
+    pub unlocked: bool,
+}
+
+/// Lets payees see what is coming to them, locked payments included.
+pub fn pending_payments(network: &Network, address: &NodeId) -> Result<Vec<PendingPayment>> {
+    let height = network.blockchain.height() + 1;
+    let now = unix_timestamp() as usize;
+    network
+        .transactions_poll
+        .iter()
+        .map(|t| &t.transaction.0)
+        .filter(|t| t.to == *address)
+        .map(|t| {
+            Ok(PendingPayment {
+                id: t.id()?,
+                transaction: t.clone(),
+                unlocked: t.is_mature(height, now),
+            })
+        })
+        .collect()
+}
+
+fn ensure_one_reward(block: &Block) -> Result<()> {
+    let rewards = block.transactions.0.iter().filter(|t| t.transaction.0.from.is_none()).count();
+    if rewards != 1 {
+        bail!("Block {} has {} mining rewards instead of one", block.header.index.0, rewards)
+    }
+    Ok(())
+}
+
+fn ensure_mature(block: &Block) -> Result<()> {
+    let premature = block
+        .transactions
+        .0
+        .iter()
+        .map(|t| &t.transaction.0)
+        .find(|t| !t.is_mature(block.header.index.0, block.header.timestamp));
+    match premature {
+        Some(t) => bail!(
+            "Block {} includes transaction locked until {:?}",
+            block.header.index.0,
+            t.lock
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Extends the chain with the block or keeps it as part of another branch,
+/// the longest branch wins.
 pub fn try_add_block(network: &mut Network, block: Block) -> Result<()> {
-    prove_mined_block(&block.transactions.0, block.header.difficulty, block.nonce)?;
+    prove_mined_block(&block, network.params.mining_difficulty)?;
+    ensure_one_reward(&block)?;
+    ensure_mature(&block)?;
+    if !extends(network.blockchain.last_block(), &block) {
+        return try_add_side_block(network, block);
+    }
+    ensure_timely(&network.blockchain.0, &block, unix_timestamp() as usize)?;
     remove_transactions_from_poll(&mut network.transactions_poll, &block.transactions.0)?;
     network.blockchain.0.push(block);
+    prune(network);
     Ok(())
 }
 
@@ -115,6 +335,7 @@ fn new_user(addr: SocketAddr, priv_key: PrivKey, pub_key: PubKey) -> Result<User
             pub_key,
         },
         priv_key,
+        wallet: None,
     })
 }
 
@@ -126,13 +347,21 @@ pub fn try_start_new_network(
     let user = new_user(addr, priv_key, pub_key)?; 
     let node = user.node.clone();
     Ok(Network {
-        user: user,
+        user,
         nodes: vec![node],
+        peer_policy: PeerPolicy::default(),
+        params: ChainParams::default(),
         blockchain: Blockchain(vec![genesis_block()]),
+        forks: Forks::default(),
         transactions_poll: vec![],
         cache: Cache {
             wallet: HashMap::new(),
+            history: HistoryIndex::default(),
+            contracts: ContractLedger::default(),
         },
+        liveness: Liveness::default(),
+        replay_guard: ReplayGuard::default(),
+        cosigning: Cosigning::default(),
         _void: (),
     })
 }
@@ -143,27 +372,39 @@ pub fn try_adopt_network(
     pub_key: PubKey,
     nodes: Vec<Node>,
     chain: Blockchain,
+    params: ChainParams,
 ) -> Result<Network> {
-    let chain = verify_blockchain(chain)?;
+    let chain = verify_blockchain(chain, params.mining_difficulty)?;
     Ok(Network {
         user: new_user(addr, priv_key, pub_key)?,
         nodes,
+        peer_policy: PeerPolicy::default(),
+        params,
         blockchain: chain,
+        forks: Forks::default(),
         transactions_poll: vec![],
         cache: Cache {
             wallet: HashMap::new(),
+            history: HistoryIndex::default(),
+            contracts: ContractLedger::default(),
         },
+        liveness: Liveness::default(),
+        replay_guard: ReplayGuard::default(),
+        cosigning: Cosigning::default(),
         _void: (),
     })
 }
 
 pub fn try_adopt_pending_transactions(
     network: &mut Network,
-    transactions: Vec<(Transaction, Vec<u8>)>,
+    transactions: Vec<ProvenTransaction>,
 ) -> Result<()> {
     let verification_results: Vec<_> = transactions
         .into_iter()
-        .map(|(transaction, proof)| verify_transaction(network, transaction, proof))
+        .map(|proven| match proven.proof {
+            Some(proof) => verify_transaction(network, proven.transaction.0, proof),
+            None => Err(anyhow!("Pending transaction {:?} has no proof", proven.transaction)),
+        })
         .collect();
     if verification_results.iter().any(|r| r.is_err()) {
         let error_text = verification_results
@@ -181,9 +422,9 @@ pub fn try_adopt_pending_transactions(
     }
 }
 
-pub fn create_mined_block(prev_block: &Block, hash: BlockHash, nonce: Nonce, transactions: &[&ProvenTransaction], miner: NodeId) -> Block {
+pub fn create_mined_block(draft: Draft, hash: BlockHash, nonce: Nonce, transactions: &[&ProvenTransaction], miner: NodeId) -> Block {
     Block {
-        header: BlockHeader::new(prev_block, hash),
+        header: BlockHeader::new(draft, hash),
         mined_by: miner,
         transactions: BlocksTransactions(transactions.iter().map(|&x| x.clone()).collect()),
         nonce,
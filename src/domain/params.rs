@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    blockchain::{NoCoin, GENESIS_DIFFICULTY, MAX_TRANSACTION_COUNT},
+    serialization::serialize,
+    signature::SignatureAlgorithm,
+    transaction::Transaction,
+};
+
+const MINING_DIFFICULTY: u8 = 5;
+
+/// Rules every node of one network has to agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub genesis_difficulty: u8,
+    pub mining_difficulty: u8,
+    pub max_transaction_count: usize,
+    pub mining_reward: NoCoin,
+    pub signature_algorithm: SignatureAlgorithm,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            genesis_difficulty: GENESIS_DIFFICULTY,
+            mining_difficulty: MINING_DIFFICULTY,
+            max_transaction_count: MAX_TRANSACTION_COUNT,
+            mining_reward: Transaction::MINING_REWARD,
+            signature_algorithm: SignatureAlgorithm::default(),
+        }
+    }
+}
+
+impl ChainParams {
+    /// Fingerprint exchanged in handshakes, nodes with different params can't peer.
+    pub fn hash(&self) -> [u8; 32] {
+        let bytes = serialize(self).expect("Chain params are always serializable");
+        Sha256::digest(bytes).into()
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::network::{Network, Node};
+
+/// Which peer has to go once the table is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Eviction {
+    /// Newcomers push out the peer known the longest.
+    Oldest,
+    /// Nobody is pushed out, newcomers are turned away until a peer leaves.
+    #[default]
+    Newcomer,
+    /// The newest peer of the address group with most peers goes, a newcomer
+    /// from that group is turned away. Many identities of one operator still
+    /// get only as many places as a single honest group.
+    CrowdedGroup,
+}
+
+/// How many peers a node keeps besides itself, without a limit it keeps
+/// everybody who registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PeerPolicy {
+    pub max_peers: Option<usize>,
+    pub eviction: Eviction,
+}
+
+/// Addresses in the same /16 are likely run by the same operator.
+fn address_group(addr: &SocketAddr) -> (u16, u16) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            (a.into(), b.into())
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, ..] = ip.segments();
+            (a, b)
+        }
+    }
+}
+
+/// Adds `node` to the peers, making room for it as the node's policy says.
+pub(super) fn admit(network: &mut Network, node: Node) -> Result<()> {
+    let Some(max_peers) = network.peer_policy.max_peers else {
+        network.nodes.push(node);
+        return Ok(());
+    };
+    if network.other_nodes().count() >= max_peers {
+        let evicted = match network.peer_policy.eviction {
+            Eviction::Oldest => network.other_nodes().next().map(|n| n.id),
+            Eviction::Newcomer => bail!("Peer table is full with {} nodes", max_peers),
+            Eviction::CrowdedGroup => {
+                let mut groups = BTreeMap::new();
+                for peer in network.other_nodes() {
+                    *groups.entry(address_group(&peer.addr)).or_insert(0) += 1;
+                }
+                let own = address_group(&node.addr);
+                let (crowded, peers) = groups.iter().max_by_key(|(_, peers)| **peers).unwrap_or((&own, &0));
+                if groups.get(&own).copied().unwrap_or(0) + 1 >= *peers {
+                    bail!("Address group of {:?} already has as many peers as any other", node.id)
+                }
+                network.other_nodes().filter(|n| address_group(&n.addr) == *crowded).last().map(|n| n.id)
+            }
+        };
+        network.nodes.retain(|n| Some(n.id) != evicted);
+    }
+    network.nodes.push(node);
+    Ok(())
+}
+
+/// Switches to `policy`, peers are admitted again in the order they came.
+pub fn set_peer_policy(network: &mut Network, policy: PeerPolicy) {
+    network.peer_policy = policy;
+    let own = network.user.node.id;
+    let (own, peers): (Vec<_>, Vec<_>) = std::mem::take(&mut network.nodes)
+        .into_iter()
+        .partition(|n| n.id == own);
+    network.nodes = own;
+    for peer in peers {
+        let _ = admit(network, peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        network::{acknowledge_node, try_start_new_network, NodeId},
+        signature::generate_key,
+    };
+
+    use super::*;
+
+    fn node(ip: [u8; 4], port: u16) -> Node {
+        let (_, pub_key) = generate_key(Default::default()).unwrap();
+        let addr = SocketAddr::from((ip, port));
+        Node { id: NodeId(port.into()), addr, pub_key }
+    }
+
+    /// One honest peer and two of an attacker.
+    fn full_table(eviction: Eviction) -> Network {
+        let (private, public) = generate_key(Default::default()).unwrap();
+        let mut network = try_start_new_network(SocketAddr::from(([10, 0, 0, 1], 8100)), private, public).unwrap();
+        set_peer_policy(&mut network, PeerPolicy { max_peers: Some(3), eviction });
+        acknowledge_node(&mut network, node([10, 1, 0, 1], 8101)).unwrap();
+        acknowledge_node(&mut network, node([198, 51, 0, 1], 9000)).unwrap();
+        acknowledge_node(&mut network, node([198, 51, 0, 2], 9001)).unwrap();
+        network
+    }
+
+    fn peers(network: &Network) -> Vec<usize> {
+        network.other_nodes().map(|n| n.id.0).collect()
+    }
+
+    #[test]
+    fn full_table_evicts_by_policy() {
+        let mut oldest = full_table(Eviction::Oldest);
+        let mut newcomer = full_table(Eviction::Newcomer);
+        let mut crowded = full_table(Eviction::CrowdedGroup);
+
+        acknowledge_node(&mut oldest, node([198, 51, 0, 3], 9002)).unwrap();
+        assert!(acknowledge_node(&mut newcomer, node([198, 51, 0, 3], 9002)).is_err());
+        assert!(acknowledge_node(&mut crowded, node([198, 51, 0, 3], 9002)).is_err());
+        acknowledge_node(&mut crowded, node([10, 9, 0, 1], 8109)).unwrap();
+
+        assert_eq!(peers(&oldest), vec![9000, 9001, 9002]);
+        assert_eq!(peers(&newcomer), vec![8101, 9000, 9001]);
+        assert_eq!(peers(&crowded), vec![8101, 9000, 8109]);
+    }
+}
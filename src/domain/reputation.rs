@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    InvalidBlock,
+    InvalidTransaction,
+    BadSignature,
+    MalformedPayload,
+}
+
+impl Misbehavior {
+    /// Forged signatures weigh more than plain rejections, rejections honest
+    /// peers cause don't count at all.
+    pub fn of(e: &anyhow::Error, otherwise: Misbehavior) -> Option<Misbehavior> {
+        if e.downcast_ref::<InvalidSignature>().is_some() {
+            Some(Misbehavior::BadSignature)
+        } else if e.downcast_ref::<OutOfSync>().is_some() {
+            None
+        } else {
+            Some(otherwise)
+        }
+    }
+
+    fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidTransaction => 10,
+            Misbehavior::MalformedPayload => 20,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::BadSignature => 50,
+        }
+    }
+}
+
+/// Attached as context to errors caused by a signature which doesn't verify,
+/// so the web layer can tell forgeries apart from other rejections.
+#[derive(Debug)]
+pub struct InvalidSignature;
+
+impl Display for InvalidSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Signature doesn't match the signed data")
+    }
+}
+
+/// Rejection an honest peer causes by knowing more or less than this node,
+/// like an orphan block or a transaction which is already pending.
+#[derive(Debug)]
+pub struct OutOfSync(pub String);
+
+impl Display for OutOfSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OutOfSync {}
+
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: 100,
+            ban_duration: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+pub struct Reputation {
+    config: ReputationConfig,
+    penalties: HashMap<SocketAddr, u32>,
+    bans: HashMap<SocketAddr, Instant>,
+}
+
+impl Reputation {
+    pub fn new(config: ReputationConfig) -> Self {
+        Self {
+            config,
+            penalties: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Adds penalty points to the peer, returns true if that got the peer banned.
+    pub fn penalize(&mut self, peer: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let points = self.penalties.entry(peer).or_insert(0);
+        *points += misbehavior.penalty();
+        if *points >= self.config.ban_threshold {
+            self.penalties.remove(&peer);
+            self.bans
+                .insert(peer, Instant::now() + self.config.ban_duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_banned(&mut self, peer: &SocketAddr) -> bool {
+        match self.bans.get(peer) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.bans.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn penalty_of(&self, peer: &SocketAddr) -> u32 {
+        self.penalties.get(peer).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 8100))
+    }
+
+    #[test]
+    fn bans_after_threshold() {
+        let mut reputation = Reputation::new(ReputationConfig::default());
+
+        assert!(!reputation.penalize(peer(), Misbehavior::InvalidBlock));
+        assert!(!reputation.is_banned(&peer()));
+        assert_eq!(reputation.penalty_of(&peer()), 50);
+
+        assert!(reputation.penalize(peer(), Misbehavior::BadSignature));
+        assert!(reputation.is_banned(&peer()));
+        assert!(!reputation.is_banned(&SocketAddr::from(([127, 0, 0, 1], 8101))));
+    }
+
+    #[test]
+    fn ban_expires() {
+        let mut reputation = Reputation::new(ReputationConfig {
+            ban_threshold: 1,
+            ban_duration: Duration::ZERO,
+        });
+
+        assert!(reputation.penalize(peer(), Misbehavior::MalformedPayload));
+
+        assert!(!reputation.is_banned(&peer()));
+        assert_eq!(reputation.penalty_of(&peer()), 0);
+    }
+}
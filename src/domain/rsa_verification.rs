@@ -1,118 +1,137 @@
-use std::fmt::Debug;
-
-use anyhow::{bail, Result};
+use anyhow::{anyhow, Result};
+use rand::{rngs::OsRng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
 use rsa::{
-    pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
-    PaddingScheme, PublicKey,
+    pkcs1::{
+        der::Document, DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey,
+        EncodeRsaPublicKey,
+    },
+    Hash, PaddingScheme, PublicKey,
 };
-use serde::{de::Visitor, Deserialize, Serialize};
+use secrets::SecretVec;
+use sha2::Sha256;
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct RSAEncodedMsg(Vec<u8>);
-#[derive(Debug, Clone)]
-pub struct PubKey(rsa::RsaPublicKey);
-pub struct PrivKey(rsa::RsaPrivateKey);
+use super::signature::{secret_copy, PrivKey, PubKey, SignatureAlgorithm, SignatureScheme};
 
 const PRIVATE_KEY_LEN: usize = 1024;
-const RSA_MESSAGE_LEN: usize = 117;
 
-impl Debug for RSAEncodedMsg {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&String::from_utf8_lossy(&self.0))
+pub struct RsaPkcs1v15;
+pub struct RsaPss;
+
+fn generate_rsa_key(algorithm: SignatureAlgorithm) -> Result<(PrivKey, PubKey)> {
+    rsa_key_pair(algorithm, rsa::RsaPrivateKey::new(&mut OsRng, PRIVATE_KEY_LEN)?)
+}
+
+fn derive_rsa_key(algorithm: SignatureAlgorithm, seed: &[u8; 32]) -> Result<(PrivKey, PubKey)> {
+    let mut rng = ChaCha20Rng::from_seed(*seed);
+    rsa_key_pair(algorithm, rsa::RsaPrivateKey::new(&mut rng, PRIVATE_KEY_LEN)?)
+}
+
+fn rsa_key_pair(algorithm: SignatureAlgorithm, key: rsa::RsaPrivateKey) -> Result<(PrivKey, PubKey)> {
+    let public = PubKey {
+        algorithm,
+        bytes: key.to_public_key().to_pkcs1_der()?.as_der().to_vec(),
+    };
+    let private = PrivKey {
+        algorithm,
+        secret: secret_copy(key.to_pkcs1_der()?.as_der()),
+    };
+    Ok((private, public))
+}
+
+fn rsa_secret(key: &PrivKey) -> Result<rsa::RsaPrivateKey> {
+    match key.algorithm {
+        SignatureAlgorithm::RsaPkcs1v15Sha256 | SignatureAlgorithm::RsaPssSha256 => {
+            rsa::RsaPrivateKey::from_pkcs1_der(&key.secret.borrow())
+                .map_err(|e| anyhow!("Invalid RSA private key: {}", e))
+        }
+        _ => Err(anyhow!("{:?} key can't sign with RSA", key.algorithm)),
     }
 }
 
-struct KeyVisitor;
-impl<'de> Visitor<'de> for KeyVisitor {
-    type Value = String;
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("Expecting string")
+fn export_rsa_secret(key: &PrivKey) -> Result<SecretVec<u8>> {
+    rsa_secret(key)?;
+    Ok(secret_copy(&key.secret.borrow()))
+}
+
+fn import_rsa_secret(algorithm: SignatureAlgorithm, secret: &[u8]) -> Result<(PrivKey, PubKey)> {
+    let key = rsa::RsaPrivateKey::from_pkcs1_der(secret)
+        .map_err(|e| anyhow!("Invalid RSA private key: {}", e))?;
+    rsa_key_pair(algorithm, key)
+}
+
+fn rsa_public(key: &PubKey) -> Result<rsa::RsaPublicKey> {
+    rsa::RsaPublicKey::from_pkcs1_der(&key.bytes).map_err(|e| anyhow!("Invalid RSA key: {}", e))
+}
+
+impl SignatureScheme for RsaPkcs1v15 {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::RsaPkcs1v15Sha256
     }
 
-    fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        Ok(v)
+    fn generate_key(&self) -> Result<(PrivKey, PubKey)> {
+        generate_rsa_key(self.algorithm())
     }
 
-    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error, 
-    {
-        Ok(v.to_owned())    
+    fn sign_digest(&self, digest: &[u8], key: &PrivKey) -> Result<Vec<u8>> {
+        rsa_secret(key)?
+            .sign(PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)), digest)
+            .map_err(|e| e.into())
     }
 
-    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error, 
-    {
-        Ok(v.to_owned())    
+    fn verify_digest(&self, digest: &[u8], signature: &[u8], key: &PubKey) -> Result<()> {
+        rsa_public(key)?
+            .verify(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                digest,
+                signature,
+            )
+            .map_err(anyhow::Error::msg)
     }
-}
-impl<'de> Deserialize<'de> for PubKey {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let str = deserializer.deserialize_string(KeyVisitor)?;
-        let pub_key = rsa::RsaPublicKey::from_pkcs1_pem(&str).map_err(|_| {
-            serde::de::Error::custom(&format!(
-                "Couldn't create public key from saved pem: {}",
-                str
-            ))
-        })?;
-        Ok(PubKey(pub_key))
+
+    fn export_secret(&self, key: &PrivKey) -> Result<SecretVec<u8>> {
+        export_rsa_secret(key)
     }
-}
 
-impl Serialize for PubKey {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let key = self
-            .0
-            .to_pkcs1_pem(rsa::pkcs8::LineEnding::CRLF)
-            .map_err(|_| serde::ser::Error::custom("Couldn't serialize pub key."))?;
-        serializer.serialize_str(&key)
+    fn import_secret(&self, secret: &[u8]) -> Result<(PrivKey, PubKey)> {
+        import_rsa_secret(self.algorithm(), secret)
     }
-}
 
-pub fn generate_key() -> Result<(PrivKey, PubKey)> {
-    let mut rng = rand::thread_rng();
-    let key = rsa::RsaPrivateKey::new(&mut rng, PRIVATE_KEY_LEN).map(|key| PrivKey(key))?;
-    let pub_key = key.0.to_public_key();
-    Ok((key, PubKey(pub_key)))
+    fn derive_key(&self, seed: &[u8; 32]) -> Result<(PrivKey, PubKey)> {
+        derive_rsa_key(self.algorithm(), seed)
+    }
 }
 
-pub fn encode_message(serialized_data: &[u8], private_key: &PrivKey) -> Result<RSAEncodedMsg> {
-    if serialized_data.len() > RSA_MESSAGE_LEN {
-        bail!(format!(
-            "Proof is too long, can be up to {RSA_MESSAGE_LEN} but is {}",
-            serialized_data.len()
-        ))
-    } else {
-        private_key
-            .0
-            .sign(PaddingScheme::new_pkcs1v15_sign(None), serialized_data)
-            .map(|bytes| RSAEncodedMsg(bytes))
+impl SignatureScheme for RsaPss {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::RsaPssSha256
+    }
+
+    fn generate_key(&self) -> Result<(PrivKey, PubKey)> {
+        generate_rsa_key(self.algorithm())
+    }
+
+    fn sign_digest(&self, digest: &[u8], key: &PrivKey) -> Result<Vec<u8>> {
+        rsa_secret(key)?
+            .sign(PaddingScheme::new_pss::<Sha256, _>(OsRng), digest)
             .map_err(|e| e.into())
     }
-}
 
-pub fn verify_message(
-    serialized_data: &[u8],
-    signed_data: Vec<u8>,
-    public_key: &PubKey,
-) -> Result<RSAEncodedMsg> {
-    public_key
-        .0
-        .verify(
-            PaddingScheme::PKCS1v15Encrypt,
-            serialized_data,
-            &signed_data,
-        )
-        .map(|_| RSAEncodedMsg(signed_data))
-        .map_err(anyhow::Error::msg)
-}
\ No newline at end of file
+    fn verify_digest(&self, digest: &[u8], signature: &[u8], key: &PubKey) -> Result<()> {
+        rsa_public(key)?
+            .verify(PaddingScheme::new_pss::<Sha256, _>(OsRng), digest, signature)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn export_secret(&self, key: &PrivKey) -> Result<SecretVec<u8>> {
+        export_rsa_secret(key)
+    }
+
+    fn import_secret(&self, secret: &[u8]) -> Result<(PrivKey, PubKey)> {
+        import_rsa_secret(self.algorithm(), secret)
+    }
+
+    fn derive_key(&self, seed: &[u8; 32]) -> Result<(PrivKey, PubKey)> {
+        derive_rsa_key(self.algorithm(), seed)
+    }
+}
@@ -0,0 +1,541 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    blockchain::{Blockchain, NoCoin},
+    network::{try_add_transaction, Network, NodeId},
+    serialization::{deserialize, serialize},
+    signature::{verify, PubKey, Signature},
+    transaction::{
+        approve, map_to_affordable, Lock, SignedTransaction, Transaction, TransactionId,
+    },
+};
+
+pub const MAX_SCRIPT_LEN: usize = 256;
+pub const MAX_STACK_DEPTH: usize = 64;
+pub const MAX_ITEM_LEN: usize = 1024;
+/// Enough for a 16 key multisig and some hashing, nothing runs longer.
+pub const GAS_LIMIT: u64 = 1_000;
+
+const SIGNATURE_GAS: u64 = 50;
+const HASH_GAS: u64 = 10;
+const OP_GAS: u64 = 1;
+
+/// Instructions work on a stack of byte strings. Numbers are 8 byte big
+/// endian, a value is false when it's empty or all zeroes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Op {
+    Push(Vec<u8>),
+    PushNumber(u64),
+    Dup,
+    Drop,
+    Swap,
+    Not,
+    Equal,
+    EqualVerify,
+    Verify,
+    Sha256,
+    /// Pops a key, then a signature of the spending transaction.
+    CheckSig,
+    CheckSigVerify,
+    /// Pops the key count, the keys, the threshold and that many signatures,
+    /// which have to be in the same order as their keys.
+    CheckMultisig,
+    /// Fails unless the transaction is height-locked at least until the popped height.
+    CheckLockHeightVerify,
+    /// Fails unless the transaction is time-locked at least until the popped timestamp.
+    CheckLockTimeVerify,
+    If,
+    Else,
+    EndIf,
+    /// Always fails, marks unspendable accounts.
+    Return,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Script(pub Vec<Op>);
+
+/// What a script may look at. It never sees the chain, so its result only
+/// depends on the transaction and the witness.
+pub struct ScriptContext<'a> {
+    /// Serialized transaction, the data signatures are checked against.
+    pub message: &'a [u8],
+    pub lock: Option<Lock>,
+}
+
+fn to_number(item: &[u8]) -> Result<u64> {
+    if item.len() > 8 {
+        bail!("Number can have at most 8 bytes, has {}", item.len())
+    }
+    let mut bytes = [0; 8];
+    bytes[8 - item.len()..].copy_from_slice(item);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn to_bool(item: &[u8]) -> bool {
+    item.iter().any(|&b| b != 0)
+}
+
+fn from_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+/// Witness item holding a key, as `CheckSig` expects it.
+pub fn key_item(key: &PubKey) -> Result<Vec<u8>> {
+    serialize(key)
+}
+
+/// Witness item holding a signature, as `CheckSig` expects it.
+#[cfg(test)]
+pub fn signature_item(signature: &Signature) -> Result<Vec<u8>> {
+    serialize(signature)
+}
+
+/// Malformed keys or signatures make the check fail, not the whole script.
+fn check_sig(context: &ScriptContext, signature: &[u8], key: &[u8]) -> bool {
+    match (deserialize::<Signature>(signature), deserialize::<PubKey>(key)) {
+        (Ok(signature), Ok(key)) => verify(context.message, &signature, &key).is_ok(),
+        _ => false,
+    }
+}
+
+struct Machine<'a> {
+    stack: Vec<Vec<u8>>,
+    context: &'a ScriptContext<'a>,
+    gas: u64,
+}
+
+impl<'a> Machine<'a> {
+    fn charge(&mut self, gas: u64) -> Result<()> {
+        self.gas += gas;
+        if self.gas > GAS_LIMIT {
+            bail!("Script ran out of gas after {}", GAS_LIMIT)
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, item: Vec<u8>) -> Result<()> {
+        if item.len() > MAX_ITEM_LEN {
+            bail!("Stack item has {} bytes, limit is {}", item.len(), MAX_ITEM_LEN)
+        }
+        if self.stack.len() == MAX_STACK_DEPTH {
+            bail!("Stack is deeper than {}", MAX_STACK_DEPTH)
+        }
+        self.stack.push(item);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>> {
+        self.stack.pop().ok_or(anyhow!("Script popped an empty stack"))
+    }
+
+    fn pop_number(&mut self) -> Result<u64> {
+        to_number(&self.pop()?)
+    }
+
+    fn verify(&mut self) -> Result<()> {
+        if !to_bool(&self.pop()?) {
+            bail!("Script verification failed")
+        }
+        Ok(())
+    }
+
+    fn check_multisig(&mut self) -> Result<bool> {
+        let key_count = self.pop_number()? as usize;
+        if key_count > MAX_STACK_DEPTH {
+            bail!("Multisig with {} keys", key_count)
+        }
+        let keys = (0..key_count).map(|_| self.pop()).collect::<Result<Vec<_>>>()?;
+        let threshold = self.pop_number()? as usize;
+        if threshold > key_count {
+            bail!("Multisig needs {} of only {} keys", threshold, key_count)
+        }
+        let signatures = (0..threshold).map(|_| self.pop()).collect::<Result<Vec<_>>>()?;
+        self.charge(SIGNATURE_GAS * key_count as u64)?;
+        // both were popped in reverse, so keys[i] is still matched in order
+        let mut keys = keys.iter();
+        Ok(signatures
+            .iter()
+            .all(|signature| keys.any(|key| check_sig(self.context, signature, key))))
+    }
+
+    fn check_lock(&mut self, lock: fn(usize) -> Lock) -> Result<()> {
+        let required = self.pop_number()? as usize;
+        let satisfied = match (self.context.lock, lock(required)) {
+            (Some(Lock::Height(h)), Lock::Height(r)) => h >= r,
+            (Some(Lock::Timestamp(t)), Lock::Timestamp(r)) => t >= r,
+            _ => false,
+        };
+        if !satisfied {
+            bail!("Transaction isn't locked until {:?}", lock(required))
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<()> {
+        match op {
+            Op::Push(item) => self.push(item.clone())?,
+            Op::PushNumber(n) => self.push(n.to_be_bytes().to_vec())?,
+            Op::Dup => {
+                let top = self.stack.last().ok_or(anyhow!("Nothing to duplicate"))?.clone();
+                self.push(top)?
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.push(a)?;
+                self.push(b)?
+            }
+            Op::Not => {
+                let value = to_bool(&self.pop()?);
+                self.push(from_bool(!value))?
+            }
+            Op::Equal => {
+                let equal = self.pop()? == self.pop()?;
+                self.push(from_bool(equal))?
+            }
+            Op::EqualVerify => {
+                if self.pop()? != self.pop()? {
+                    bail!("Script items aren't equal")
+                }
+            }
+            Op::Verify => self.verify()?,
+            Op::Sha256 => {
+                self.charge(HASH_GAS)?;
+                let item = self.pop()?;
+                self.push(Sha256::digest(item).to_vec())?
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                self.charge(SIGNATURE_GAS)?;
+                let key = self.pop()?;
+                let signature = self.pop()?;
+                let valid = check_sig(self.context, &signature, &key);
+                if *op == Op::CheckSigVerify && !valid {
+                    bail!("Script signature check failed")
+                }
+                if *op == Op::CheckSig {
+                    self.push(from_bool(valid))?
+                }
+            }
+            Op::CheckMultisig => {
+                let valid = self.check_multisig()?;
+                self.push(from_bool(valid))?
+            }
+            Op::CheckLockHeightVerify => self.check_lock(Lock::Height)?,
+            Op::CheckLockTimeVerify => self.check_lock(Lock::Timestamp)?,
+            Op::Return => bail!("Script returned early"),
+            Op::If | Op::Else | Op::EndIf => unreachable!("branches are handled by run"),
+        }
+        Ok(())
+    }
+}
+
+impl Script {
+    /// Scripts are checked once when registered, so a broken one can't lock coins away.
+    pub fn validate(&self) -> Result<()> {
+        if self.0.len() > MAX_SCRIPT_LEN {
+            bail!("Script has {} ops, limit is {}", self.0.len(), MAX_SCRIPT_LEN)
+        }
+        let mut depth: usize = 0;
+        for op in &self.0 {
+            match op {
+                Op::If => depth += 1,
+                Op::Else if depth == 0 => bail!("Else outside of If"),
+                Op::EndIf => {
+                    depth = depth.checked_sub(1).ok_or(anyhow!("EndIf without If"))?
+                }
+                Op::Push(item) if item.len() > MAX_ITEM_LEN => {
+                    bail!("Pushed item has {} bytes, limit is {}", item.len(), MAX_ITEM_LEN)
+                }
+                _ => {}
+            }
+        }
+        if depth != 0 {
+            bail!("If without EndIf")
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> Result<NodeId> {
+        let mut data = b"nocoin script".to_vec();
+        data.extend(serialize(self)?);
+        Ok(NodeId::derived(&Sha256::digest(data)))
+    }
+
+    /// Runs the script on top of the witness, spending is allowed when it
+    /// finishes with a true value on top. Returns the gas used.
+    pub fn execute(&self, witness: &[Vec<u8>], context: &ScriptContext) -> Result<u64> {
+        self.validate()?;
+        let mut machine = Machine {
+            stack: vec![],
+            context,
+            gas: 0,
+        };
+        for item in witness {
+            machine.push(item.clone())?;
+        }
+        // every enclosing branch has to be taken for an op to run
+        let mut branches: Vec<bool> = vec![];
+        for op in &self.0 {
+            machine.charge(OP_GAS)?;
+            let running = branches.iter().all(|&b| b);
+            match op {
+                Op::If => {
+                    let taken = running && to_bool(&machine.pop()?);
+                    branches.push(taken);
+                }
+                Op::Else => {
+                    let outer = branches[..branches.len() - 1].iter().all(|&b| b);
+                    let last = branches.last_mut().unwrap();
+                    *last = outer && !*last;
+                }
+                Op::EndIf => {
+                    branches.pop();
+                }
+                op if running => machine.step(op)?,
+                _ => {}
+            }
+        }
+        if !machine.stack.last().map(|top| to_bool(top)).unwrap_or(false) {
+            bail!("Script finished without a true value on top")
+        }
+        Ok(machine.gas)
+    }
+
+    pub fn pay_to_key(key: &PubKey) -> Result<Self> {
+        Ok(Self(vec![Op::Push(key_item(key)?), Op::CheckSig]))
+    }
+
+    /// Spent with signatures of `threshold` keys, given in the order of `keys`.
+    pub fn multisig(threshold: u64, keys: &[PubKey]) -> Result<Self> {
+        let mut ops = vec![Op::PushNumber(threshold)];
+        for key in keys {
+            ops.push(Op::Push(key_item(key)?));
+        }
+        ops.push(Op::PushNumber(keys.len() as u64));
+        ops.push(Op::CheckMultisig);
+        Ok(Self(ops))
+    }
+
+    /// Hash-time-locked contract: `recipient` takes the coins with the preimage
+    /// of `hash`, or `refund` takes them back once `refund_height` is reached.
+    /// Claimed with witness `[signature, preimage, true]`, refunded with
+    /// `[signature, false]` from a transaction locked until `refund_height`.
+    pub fn htlc(hash: [u8; 32], recipient: &PubKey, refund: &PubKey, refund_height: usize) -> Result<Self> {
+        Ok(Self(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(hash.to_vec()),
+            Op::EqualVerify,
+            Op::Push(key_item(recipient)?),
+            Op::Else,
+            Op::PushNumber(refund_height as u64),
+            Op::CheckLockHeightVerify,
+            Op::Push(key_item(refund)?),
+            Op::EndIf,
+            Op::CheckSig,
+        ]))
+    }
+}
+
+/// Standard scripts, for registering accounts without writing their script.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptTemplate {
+    PayToKey { key: PubKey },
+    Multisig { threshold: u64, keys: Vec<PubKey> },
+    Htlc { hash: [u8; 32], recipient: PubKey, refund: PubKey, refund_height: usize },
+}
+
+impl ScriptTemplate {
+    pub fn build(&self) -> Result<Script> {
+        match self {
+            ScriptTemplate::PayToKey { key } => Script::pay_to_key(key),
+            ScriptTemplate::Multisig { threshold, keys } => Script::multisig(*threshold, keys),
+            ScriptTemplate::Htlc { hash, recipient, refund, refund_height } => {
+                Script::htlc(*hash, recipient, refund, *refund_height)
+            }
+        }
+    }
+}
+
+/// Registration transactions are the only place scripts are kept.
+pub(super) fn find_script<'a>(blockchain: &'a Blockchain, address: &NodeId) -> Option<&'a Script> {
+    blockchain
+        .0
+        .iter()
+        .flat_map(|b| b.transactions.0.iter())
+        .map(|t| &t.transaction.0)
+        .find(|t| t.to == *address && t.script.is_some())
+        .and_then(|t| t.script.as_ref())
+}
+
+/// Node pays `ammount` into a new account guarded by `script`, returns the account's address.
+pub fn try_register_script(
+    network: &mut Network,
+    script: Script,
+    ammount: NoCoin,
+    fee: NoCoin,
+) -> Result<(NodeId, TransactionId, SignedTransaction)> {
+    let address = script.address()?;
+    let transaction = Transaction {
+        script: Some(script),
+        ..Transaction::new(Some(network.user.node.id), address, fee, ammount)
+    };
+    let proven = approve(map_to_affordable(network, transaction)?, &network.user.priv_key)?;
+    let signed = SignedTransaction {
+        transaction: proven.transaction.0,
+        proof: proven.proof.ok_or(anyhow!("Registration wasn't signed"))?,
+    };
+    let id = try_add_transaction(network, signed.transaction.clone(), signed.proof.clone())?;
+    Ok((address, id, signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        signature::{generate_key, sign, PrivKey, SignatureAlgorithm},
+        testing::{mine, network},
+        transaction::Proof,
+    };
+
+    use super::*;
+
+    fn keys() -> (PrivKey, PubKey) {
+        generate_key(SignatureAlgorithm::Ed25519).unwrap()
+    }
+
+    fn run(script: &Script, witness: &[Vec<u8>], message: &[u8], lock: Option<Lock>) -> Result<u64> {
+        script.execute(witness, &ScriptContext { message, lock })
+    }
+
+    fn signed(message: &[u8], key: &PrivKey) -> Vec<u8> {
+        signature_item(&sign(message, key).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pay_to_key_needs_its_signature() {
+        let (private, public) = keys();
+        let (other, _) = keys();
+        let script = Script::pay_to_key(&public).unwrap();
+
+        run(&script, &[signed(b"tx", &private)], b"tx", None).unwrap();
+        assert!(run(&script, &[signed(b"tx", &other)], b"tx", None).is_err());
+        assert!(run(&script, &[signed(b"other tx", &private)], b"tx", None).is_err());
+        assert!(run(&script, &[b"garbage".to_vec()], b"tx", None).is_err());
+        assert!(run(&script, &[], b"tx", None).is_err());
+    }
+
+    #[test]
+    fn templates_build_standard_scripts() {
+        let (_, first) = keys();
+        let (_, second) = keys();
+        let keys = serde_json::to_value([&first, &second]).unwrap();
+        let multisig = serde_json::json!({ "multisig": { "threshold": 1, "keys": keys } });
+        let pay_to_key = serde_json::json!({ "pay_to_key": { "key": keys[0] } });
+
+        let multisig: ScriptTemplate = serde_json::from_value(multisig).unwrap();
+        let pay_to_key: ScriptTemplate = serde_json::from_value(pay_to_key).unwrap();
+
+        assert_eq!(multisig.build().unwrap(), Script::multisig(1, &[first.clone(), second]).unwrap());
+        assert_eq!(pay_to_key.build().unwrap(), Script::pay_to_key(&first).unwrap());
+    }
+
+    #[test]
+    fn htlc_is_claimed_with_preimage_or_refunded_after_timeout() {
+        let (recipient, recipient_public) = keys();
+        let (refund, refund_public) = keys();
+        let preimage = b"secret".to_vec();
+        let hash: [u8; 32] = Sha256::digest(&preimage).into();
+        let script = Script::htlc(hash, &recipient_public, &refund_public, 100).unwrap();
+        let yes = from_bool(true);
+        let no = from_bool(false);
+
+        let claim = [signed(b"tx", &recipient), preimage.clone(), yes.clone()];
+        run(&script, &claim, b"tx", None).unwrap();
+        let wrong_preimage = [signed(b"tx", &recipient), b"guess".to_vec(), yes.clone()];
+        assert!(run(&script, &wrong_preimage, b"tx", None).is_err());
+        let refund_claims = [signed(b"tx", &refund), preimage, yes];
+        assert!(run(&script, &refund_claims, b"tx", None).is_err());
+
+        let early_refund = [signed(b"tx", &refund), no.clone()];
+        assert!(run(&script, &early_refund, b"tx", None).is_err());
+        assert!(run(&script, &early_refund, b"tx", Some(Lock::Height(99))).is_err());
+        assert!(run(&script, &early_refund, b"tx", Some(Lock::Timestamp(1000))).is_err());
+        run(&script, &early_refund, b"tx", Some(Lock::Height(100))).unwrap();
+        let stolen = [signed(b"tx", &recipient), no];
+        assert!(run(&script, &stolen, b"tx", Some(Lock::Height(100))).is_err());
+    }
+
+    #[test]
+    fn multisig_needs_ordered_signatures() {
+        let keys: Vec<_> = (0..3).map(|_| keys()).collect();
+        let public: Vec<_> = keys.iter().map(|(_, p)| p.clone()).collect();
+        let script = Script::multisig(2, &public).unwrap();
+        let witness = |a: usize, b: usize| vec![signed(b"tx", &keys[a].0), signed(b"tx", &keys[b].0)];
+
+        run(&script, &witness(0, 2), b"tx", None).unwrap();
+        run(&script, &witness(1, 2), b"tx", None).unwrap();
+        assert!(run(&script, &witness(2, 0), b"tx", None).is_err());
+        assert!(run(&script, &witness(1, 1), b"tx", None).is_err());
+        assert!(run(&script, &[signed(b"tx", &keys[0].0)], b"tx", None).is_err());
+    }
+
+    #[test]
+    fn scripts_are_bounded() {
+        let hashing = Script(vec![Op::Sha256; 200]);
+        assert!(run(&hashing, &[vec![1]], b"tx", None).is_err());
+        let flooding = Script(vec![Op::Dup; MAX_STACK_DEPTH]);
+        assert!(run(&flooding, &[vec![1]], b"tx", None).is_err());
+        assert!(Script(vec![Op::If, Op::PushNumber(1)]).validate().is_err());
+        assert!(Script(vec![Op::EndIf]).validate().is_err());
+        assert!(run(&Script(vec![Op::Return]), &[vec![1]], b"tx", None).is_err());
+    }
+
+    #[test]
+    fn skipped_branches_do_nothing() {
+        let script = Script(vec![
+            Op::If,
+            Op::Return,
+            Op::Else,
+            Op::If,
+            Op::Return,
+            Op::EndIf,
+            Op::PushNumber(7),
+            Op::EndIf,
+        ]);
+
+        assert!(run(&script, &[from_bool(false), from_bool(true)], b"tx", None).is_err());
+        assert_eq!(
+            run(&script, &[from_bool(false), from_bool(false)], b"tx", None).unwrap(),
+            8
+        );
+    }
+
+    #[test]
+    fn htlc_account_is_spent_through_verification() {
+        let mut network = network(8100);
+        mine(&mut network);
+        let (recipient, recipient_public) = generate_key(Default::default()).unwrap();
+        let (_, refund_public) = generate_key(Default::default()).unwrap();
+        let preimage = b"secret".to_vec();
+        let script = Script::htlc(Sha256::digest(&preimage).into(), &recipient_public, &refund_public, 50).unwrap();
+        let (address, _, _) = try_register_script(&mut network, script, NoCoin(6.), NoCoin(1.)).unwrap();
+        mine(&mut network);
+
+        let claim = Transaction::new(Some(address), NodeId(8101), NoCoin(1.), NoCoin(5.));
+        let message = serialize(&claim).unwrap();
+        let guess = Proof::Script(vec![signed(&message, &recipient), b"guess".to_vec(), from_bool(true)]);
+        let witness = Proof::Script(vec![signed(&message, &recipient), preimage, from_bool(true)]);
+
+        assert!(try_add_transaction(&mut network, claim.clone(), guess).is_err());
+        try_add_transaction(&mut network, claim, witness).unwrap();
+    }
+}
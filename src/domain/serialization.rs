@@ -1,10 +1,181 @@
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
+pub const WIRE_VERSION: u8 = 1;
+pub const BINARY_CONTENT_TYPE: &str = "application/x-nocoin";
+
+pub const FRAME_HEADER_LEN: usize = 1 + 4;
+
+/// Canonical byte form of a value, used for hashing and signing.
+/// Fixed width little endian integers and no field names, so equal values
+/// always produce equal bytes.
 pub fn serialize<T>(serializable: &T) -> Result<Vec<u8>>
 where
-    T: Serialize,
+    T: Serialize + ?Sized,
+{
+    bincode::serialize(serializable).map_err(|e| e.into())
+}
+
+/// Reverse of `serialize`.
+pub fn deserialize<T>(bytes: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
 {
-    serde_json::to_vec(serializable).map_err(|e| e.into())
+    bincode::deserialize(bytes).map_err(|e| e.into())
+}
+
+/// Wire frame: version byte, big endian u32 payload length, canonical payload.
+pub fn encode<T>(value: &T) -> Result<Vec<u8>>
+where
+    T: Serialize + ?Sized,
+{
+    let payload = serialize(value)?;
+    let len = u32::try_from(payload.len())?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.push(WIRE_VERSION);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Length of the whole frame starting at `bytes`, if its header is complete.
+pub fn frame_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    Some(FRAME_HEADER_LEN + len as usize)
+}
+
+pub fn decode<T>(frame: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    match frame.first() {
+        None => bail!("Empty frame"),
+        Some(&WIRE_VERSION) => {}
+        Some(version) => bail!(
+            "Unsupported wire version {}, expected {}",
+            version,
+            WIRE_VERSION
+        ),
+    }
+    let expected = frame_len(frame).unwrap_or(usize::MAX);
+    if frame.len() != expected {
+        bail!(
+            "Frame length mismatch, header says {} bytes but got {}",
+            expected,
+            frame.len()
+        )
+    }
+    bincode::deserialize(&frame[FRAME_HEADER_LEN..]).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::domain::{
+        blockchain::{BlockHeader, BlockIndex, BlocksTransactions, NoCoin, Nonce},
+        mining::BlockHash,
+        transaction::{create_mining_reward, AffordableTransaction, ProvenTransaction, Transaction},
+        Block, Blockchain, NodeId,
+    };
+
+    use super::*;
+
+    fn large_chain(blocks: usize) -> Blockchain {
+        Blockchain(
+            (0..blocks)
+                .map(|i| Block {
+                    header: BlockHeader {
+                        index: BlockIndex(i),
+                        prev_hash: BlockHash::default(),
+                        hash: BlockHash::default(),
+                        timestamp: i,
+                        difficulty: 3,
+                    },
+                    mined_by: NodeId(8100 + i % 7),
+                    transactions: BlocksTransactions(
+                        (0..9)
+                            .map(|t| ProvenTransaction {
+                                transaction: AffordableTransaction(Transaction::new(
+                                    Some(NodeId(8100 + t)),
+                                    NodeId(8101 + t),
+                                    NoCoin(0.5),
+                                    NoCoin(t as f32),
+                                )),
+                                proof: None,
+                            })
+                            .chain(std::iter::once(create_mining_reward(NodeId(8100))))
+                            .collect(),
+                    ),
+                    nonce: Nonce(i as u32),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        let chain = large_chain(3);
+
+        let frame = encode(&chain).unwrap();
+        let decoded: Blockchain = decode(&frame).unwrap();
+
+        assert_eq!(frame_len(&frame), Some(frame.len()));
+        assert_eq!(serialize(&decoded).unwrap(), serialize(&chain).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut frame = encode(&NodeId(8100)).unwrap();
+
+        assert!(decode::<NodeId>(&frame[..frame.len() - 1]).is_err());
+        frame[0] = WIRE_VERSION + 1;
+        assert!(decode::<NodeId>(&frame).is_err());
+        assert!(decode::<NodeId>(&[]).is_err());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture wire_benchmark`.
+    #[test]
+    #[ignore]
+    fn wire_benchmark() {
+        const ROUNDS: u32 = 10;
+        let chain = large_chain(10_000);
+
+        let json = serde_json::to_vec(&chain).unwrap();
+        let binary = encode(&chain).unwrap();
+        println!(
+            "Chain of {} blocks: json {} bytes, binary {} bytes ({:.1}%)",
+            chain.0.len(),
+            json.len(),
+            binary.len(),
+            100. * binary.len() as f64 / json.len() as f64
+        );
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let bytes = serde_json::to_vec(&chain).unwrap();
+            let _: Blockchain = serde_json::from_slice(&bytes).unwrap();
+        }
+        let json_time = start.elapsed() / ROUNDS;
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let bytes = encode(&chain).unwrap();
+            let _: Blockchain = decode(&bytes).unwrap();
+        }
+        let binary_time = start.elapsed() / ROUNDS;
+        let throughput = |bytes: usize, time: std::time::Duration| {
+            bytes as f64 / time.as_secs_f64() / 1_000_000.
+        };
+        println!(
+            "Roundtrip: json {:?} ({:.1} MB/s), binary {:?} ({:.1} MB/s)",
+            json_time,
+            throughput(json.len(), json_time),
+            binary_time,
+            throughput(binary.len(), binary_time)
+        );
+    }
 }
@@ -0,0 +1,160 @@
+use std::fmt::Debug;
+
+use anyhow::{bail, Result};
+use secrets::SecretVec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    ed25519_verification::Ed25519,
+    rsa_verification::{RsaPkcs1v15, RsaPss},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignatureAlgorithm {
+    #[default]
+    RsaPkcs1v15Sha256,
+    RsaPssSha256,
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    pub fn scheme(self) -> &'static dyn SignatureScheme {
+        match self {
+            SignatureAlgorithm::RsaPkcs1v15Sha256 => &RsaPkcs1v15,
+            SignatureAlgorithm::RsaPssSha256 => &RsaPss,
+            SignatureAlgorithm::Ed25519 => &Ed25519,
+        }
+    }
+}
+
+/// Signs and verifies SHA-256 digests of messages, never the messages
+/// themselves, so there is no limit on how much can be signed.
+pub trait SignatureScheme: Sync {
+    fn algorithm(&self) -> SignatureAlgorithm;
+    fn generate_key(&self) -> Result<(PrivKey, PubKey)>;
+    fn sign_digest(&self, digest: &[u8], key: &PrivKey) -> Result<Vec<u8>>;
+    fn verify_digest(&self, digest: &[u8], signature: &[u8], key: &PubKey) -> Result<()>;
+    /// Secret half of the key in the scheme's own encoding, for storing it.
+    fn export_secret(&self, key: &PrivKey) -> Result<SecretVec<u8>>;
+    fn import_secret(&self, secret: &[u8]) -> Result<(PrivKey, PubKey)>;
+    /// Same seed always gives the same key pair.
+    fn derive_key(&self, seed: &[u8; 32]) -> Result<(PrivKey, PubKey)>;
+}
+
+/// Secret half of a key in its scheme's export encoding. It is kept only in
+/// guarded memory and decoded just for the moment of signing.
+pub struct PrivKey {
+    pub algorithm: SignatureAlgorithm,
+    pub(super) secret: SecretVec<u8>,
+}
+
+/// Public half of a key, encoded the way its scheme expects: PKCS#1 DER for
+/// RSA, the 32 raw bytes for Ed25519.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PubKey {
+    pub algorithm: SignatureAlgorithm,
+    pub(super) bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub algorithm: SignatureAlgorithm,
+    pub(super) bytes: Vec<u8>,
+}
+
+pub(super) fn secret_copy(bytes: &[u8]) -> SecretVec<u8> {
+    SecretVec::new(bytes.len(), |secret| secret.copy_from_slice(bytes))
+}
+
+fn short_hex(bytes: &[u8]) -> String {
+    bytes.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Debug for PubKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}({}..)", self.algorithm, short_hex(&self.bytes))
+    }
+}
+
+impl Debug for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}({}..)", self.algorithm, short_hex(&self.bytes))
+    }
+}
+
+pub fn generate_key(algorithm: SignatureAlgorithm) -> Result<(PrivKey, PubKey)> {
+    algorithm.scheme().generate_key()
+}
+
+pub fn sign(data: &[u8], key: &PrivKey) -> Result<Signature> {
+    let digest = Sha256::digest(data);
+    let bytes = key.algorithm.scheme().sign_digest(&digest, key)?;
+    Ok(Signature {
+        algorithm: key.algorithm,
+        bytes,
+    })
+}
+
+pub fn verify(data: &[u8], signature: &Signature, key: &PubKey) -> Result<()> {
+    if signature.algorithm != key.algorithm {
+        bail!(
+            "Signature made with {:?} can't be checked by {:?} key",
+            signature.algorithm,
+            key.algorithm
+        )
+    }
+    let digest = Sha256::digest(data);
+    key.algorithm
+        .scheme()
+        .verify_digest(&digest, &signature.bytes, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [SignatureAlgorithm; 3] = [
+        SignatureAlgorithm::RsaPkcs1v15Sha256,
+        SignatureAlgorithm::RsaPssSha256,
+        SignatureAlgorithm::Ed25519,
+    ];
+
+    #[test]
+    fn signs_messages_of_any_length() {
+        let long_message = vec![7; 10_000];
+        for algorithm in ALGORITHMS {
+            let (private, public) = generate_key(algorithm).unwrap();
+
+            let signature = sign(&long_message, &private).unwrap();
+
+            assert_eq!(signature.algorithm, algorithm);
+            verify(&long_message, &signature, &public).unwrap();
+            assert!(verify(&long_message[1..], &signature, &public).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_signatures_of_other_algorithms() {
+        let (rsa_private, _) = generate_key(SignatureAlgorithm::RsaPkcs1v15Sha256).unwrap();
+        let (_, pss_public) = generate_key(SignatureAlgorithm::RsaPssSha256).unwrap();
+        let (_, ed_public) = generate_key(SignatureAlgorithm::Ed25519).unwrap();
+
+        let mut signature = sign(b"data", &rsa_private).unwrap();
+        assert!(verify(b"data", &signature, &pss_public).is_err());
+        signature.algorithm = SignatureAlgorithm::Ed25519;
+        assert!(verify(b"data", &signature, &ed_public).is_err());
+    }
+
+    #[test]
+    fn keys_survive_the_wire() {
+        for algorithm in ALGORITHMS {
+            let (private, public) = generate_key(algorithm).unwrap();
+            let signature = sign(b"data", &private).unwrap();
+
+            let public: PubKey = serde_json::from_str(&serde_json::to_string(&public).unwrap()).unwrap();
+
+            verify(b"data", &signature, &public).unwrap();
+        }
+    }
+}
@@ -1,3 +1,105 @@
+use std::net::SocketAddr;
+
+use super::{
+    blockchain::{Block, BlocksTransactions, Draft},
+    contract::Instr,
+    mining::{try_mine_any, BlockHash},
+    network::{create_mined_block, try_add_block, try_start_new_network, Network, NodeId},
+    signature::generate_key,
+    transaction::{create_mining_reward, ProvenTransaction},
+};
+
+/// Node on localhost at `port` with a fresh key.
+pub fn network(port: u16) -> Network {
+    let (private, public) = generate_key(Default::default()).unwrap();
+    try_start_new_network(SocketAddr::from(([127, 0, 0, 1], port)), private, public).unwrap()
+}
+
+/// Node at 8100 with an easy difficulty and one block rewarding it.
+pub fn funded_network() -> Network {
+    let mut network = network(8100);
+    network.params.mining_difficulty = 1;
+    let block = mine_on(network.blockchain.last_block(), vec![], network.user.node.id);
+    try_add_block(&mut network, block).unwrap();
+    network
+}
+
+/// Appends the poll and a reward for the node as the next block, without proof of work.
+pub fn mine(network: &mut Network) {
+    let mut block: Block = network.blockchain.last_block().clone();
+    block.header.index = block.header.index.next_index();
+    block.header.hash = BlockHash(format!("{:064}", block.header.index.0));
+    let mut transactions: Vec<ProvenTransaction> = network.transactions_poll.drain(..).collect();
+    transactions.push(create_mining_reward(network.user.node.id));
+    block.transactions = BlocksTransactions(transactions);
+    network.blockchain.0.push(block);
+}
+
+/// Mines `transactions` and a reward for `miner` on `parent` at difficulty 1.
+pub fn mine_on(parent: &Block, mut transactions: Vec<ProvenTransaction>, miner: NodeId) -> Block {
+    transactions.push(create_mining_reward(miner));
+    let draft = Draft::on(parent);
+    let (hash, nonce) = try_mine_any(&draft, 1, &transactions).unwrap();
+    let included: Vec<_> = transactions.iter().collect();
+    create_mined_block(draft, hash, nonce, &included, miner)
+}
+
+/// Token with `mint(amount)` for whoever deploys it first and `transfer(to, amount)`.
+/// Balances are stored under the holder's id, the owner under key -1.
+pub fn token_contract() -> Vec<Instr> {
+    use Instr::*;
+    vec![
+        /* 0 */ Input(0),
+        /* 1 */ JumpIf(12),
+        // mint: only the first caller becomes the owner
+        /* 2 */ Push(-1),
+        /* 3 */ Load,
+        /* 4 */ Dup(0),
+        /* 5 */ IsZero,
+        /* 6 */ JumpIf(34),
+        /* 7 */ Caller,
+        /* 8 */ Eq,
+        /* 9 */ JumpIf(38),
+        /* 10 */ Revert,
+        /* 11 */ Stop,
+        // transfer(to, amount)
+        /* 12 */ Caller,
+        /* 13 */ Dup(0),
+        /* 14 */ Load,
+        /* 15 */ Input(2),
+        /* 16 */ Sub,
+        /* 17 */ Dup(0),
+        /* 18 */ Push(0),
+        /* 19 */ Lt,
+        /* 20 */ JumpIf(33),
+        /* 21 */ Store,
+        /* 22 */ Input(1),
+        /* 23 */ Dup(0),
+        /* 24 */ Load,
+        /* 25 */ Input(2),
+        /* 26 */ Add,
+        /* 27 */ Store,
+        /* 28 */ Caller,
+        /* 29 */ Input(1),
+        /* 30 */ Input(2),
+        /* 31 */ Log(3),
+        /* 32 */ Stop,
+        /* 33 */ Revert,
+        // first mint sets the owner, then falls through to minting
+        /* 34 */ Pop,
+        /* 35 */ Push(-1),
+        /* 36 */ Caller,
+        /* 37 */ Store,
+        /* 38 */ Caller,
+        /* 39 */ Dup(0),
+        /* 40 */ Load,
+        /* 41 */ Input(1),
+        /* 42 */ Add,
+        /* 43 */ Store,
+    ]
+}
+
+
 // use std::net::{Ipv4Addr, SocketAddrV4};
 
 // use super::network::{Node, NodeId};
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    blockchain::NoCoin,
+    network::{try_add_transaction, Network, NodeId},
+    transaction::{approve, map_to_affordable, SignedTransaction, Transaction, TransactionId},
+    wallet::calculate_token_balances,
+};
+
+pub const MAX_SYMBOL_LEN: usize = 8;
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_DECIMALS: u8 = 18;
+
+/// Amounts are in the token's smallest unit, `decimals` only says how to show them.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum TokenAction {
+    /// Creates `supply` units owned by the sender, `to` must be the sender.
+    Issue {
+        symbol: String,
+        name: String,
+        decimals: u8,
+        supply: u64,
+    },
+    Transfer { symbol: String, ammount: u64 },
+    /// Destroys units of the sender, `to` must be the sender.
+    Burn { symbol: String, ammount: u64 },
+}
+
+impl TokenAction {
+    pub fn symbol(&self) -> &str {
+        match self {
+            TokenAction::Issue { symbol, .. }
+            | TokenAction::Transfer { symbol, .. }
+            | TokenAction::Burn { symbol, .. } => symbol,
+        }
+    }
+}
+
+/// Issued token, `supply` shrinks with every burn.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub supply: u64,
+    pub issuer: NodeId,
+}
+
+impl TokenInfo {
+    /// `units` with the decimal point where the token puts it.
+    pub fn format(&self, units: u64) -> String {
+        let decimals = self.decimals as usize;
+        if decimals == 0 {
+            return units.to_string();
+        }
+        let digits = format!("{:0>width$}", units, width = decimals + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - decimals);
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+pub(super) fn validate_symbol(symbol: &str) -> Result<()> {
+    if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN {
+        bail!("Symbol must have 1 to {} characters", MAX_SYMBOL_LEN)
+    }
+    if !symbol.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        bail!("Symbol {:?} may only use capital letters and digits", symbol)
+    }
+    Ok(())
+}
+
+/// Token transactions move no NoCoin besides the fee, so the two can't be confused.
+pub(super) fn validate_token(network: &Network, transaction: &Transaction) -> Result<()> {
+    if transaction.token.is_none() {
+        return Ok(());
+    }
+    if transaction.multisig.is_some() || transaction.script.is_some() || transaction.contract.is_some() {
+        bail!("Token transaction can't register an account or call a contract too")
+    }
+    if transaction.ammount != NoCoin(0.) {
+        bail!("Token transaction can't send NoCoin, only pay its fee")
+    }
+    calculate_token_balances(&network.blockchain).check(transaction)
+}
+
+/// Node signs a token transaction from its own account, `to` is only used by transfers.
+pub fn try_send_token(
+    network: &mut Network,
+    to: NodeId,
+    action: TokenAction,
+    fee: NoCoin,
+) -> Result<(TransactionId, SignedTransaction)> {
+    let sender = network.user.node.id;
+    let to = match &action {
+        TokenAction::Transfer { .. } => to,
+        TokenAction::Issue { .. } | TokenAction::Burn { .. } => sender,
+    };
+    let transaction = Transaction {
+        token: Some(action),
+        ..Transaction::new(Some(sender), to, fee, NoCoin(0.))
+    };
+    let proven = approve(map_to_affordable(network, transaction)?, &network.user.priv_key)?;
+    let signed = SignedTransaction {
+        transaction: proven.transaction.0,
+        proof: proven.proof.ok_or(anyhow!("Token transaction wasn't signed"))?,
+    };
+    let id = try_add_transaction(network, signed.transaction.clone(), signed.proof.clone())?;
+    Ok((id, signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        testing::{mine, network},
+        wallet::calculate_wallet,
+    };
+
+    use super::*;
+
+    fn issue(symbol: &str) -> TokenAction {
+        TokenAction::Issue {
+            symbol: symbol.to_owned(),
+            name: "Gold".to_owned(),
+            decimals: 2,
+            supply: 10_000,
+        }
+    }
+
+    fn transfer(ammount: u64) -> TokenAction {
+        TokenAction::Transfer { symbol: "GLD".to_owned(), ammount }
+    }
+
+    #[test]
+    fn issued_tokens_move_between_accounts() {
+        let mut network = network(8100);
+        let (issuer, holder) = (network.user.node.id, NodeId(8101));
+        mine(&mut network);
+        try_send_token(&mut network, holder, issue("GLD"), NoCoin(1.)).unwrap();
+        assert!(try_send_token(&mut network, holder, transfer(1), NoCoin(1.)).is_err());
+        mine(&mut network);
+
+        assert!(try_send_token(&mut network, holder, issue("GLD"), NoCoin(1.)).is_err());
+        assert!(try_send_token(&mut network, holder, issue("gld"), NoCoin(1.)).is_err());
+        assert!(try_send_token(&mut network, holder, transfer(10_001), NoCoin(1.)).is_err());
+        try_send_token(&mut network, holder, transfer(2_550), NoCoin(1.)).unwrap();
+        let burn = TokenAction::Burn { symbol: "GLD".to_owned(), ammount: 450 };
+        try_send_token(&mut network, holder, burn, NoCoin(1.)).unwrap();
+        mine(&mut network);
+
+        let balances = calculate_token_balances(&network.blockchain);
+        let token = balances.token("GLD").unwrap();
+        assert_eq!((token.issuer, token.supply), (issuer, 9_550));
+        assert_eq!(balances.balance(&issuer, "GLD"), 7_000);
+        assert_eq!(token.format(balances.balance(&holder, "GLD")), "25.50");
+        assert_eq!(calculate_wallet(&issuer, &network.blockchain), NoCoin(30. - 3.));
+    }
+
+    #[test]
+    fn uncovered_transfers_in_one_block_are_skipped() {
+        let mut network = network(8100);
+        mine(&mut network);
+        try_send_token(&mut network, NodeId(0), issue("GLD"), NoCoin(1.)).unwrap();
+        mine(&mut network);
+        try_send_token(&mut network, NodeId(8101), transfer(6_000), NoCoin(1.)).unwrap();
+        try_send_token(&mut network, NodeId(8102), transfer(6_000), NoCoin(1.)).unwrap();
+        mine(&mut network);
+
+        let balances = calculate_token_balances(&network.blockchain);
+
+        assert_eq!(balances.balance(&NodeId(8101), "GLD"), 6_000);
+        assert_eq!(balances.balance(&NodeId(8102), "GLD"), 0);
+        assert_eq!(balances.balance(&network.user.node.id, "GLD"), 4_000);
+    }
+}
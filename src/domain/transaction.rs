@@ -2,14 +2,20 @@ use std::fmt::Debug;
 
 use super::{
     blockchain::NoCoin,
-    network::{Network, Node, NodeId, User},
-    rsa_verification::{encode_message, verify_message, RSAEncodedMsg},
+    contract::{validate_action, ContractAction},
+    multisig::{find_policy, Cosignature, MultisigPolicy},
+    script::{find_script, Script, ScriptContext},
+    network::{ensure_key_fits, Network, Node, NodeId},
+    reputation::InvalidSignature,
     serialization::serialize,
+    signature::{sign, verify, PrivKey, PubKey, Signature},
+    token::{validate_token, TokenAction},
     wallet::calculate_wallet,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Transaction {
@@ -17,6 +23,59 @@ pub struct Transaction {
     pub to: NodeId,
     pub fee: NoCoin,
     pub ammount: NoCoin,
+    /// Accounts derived from keys aren't registered anywhere, so spending from
+    /// them has to reveal the key.
+    pub sender_key: Option<PubKey>,
+    /// Registers `to` as a multisig account spendable under this policy.
+    pub multisig: Option<MultisigPolicy>,
+    /// Registers `to` as an account spendable by whoever satisfies this script.
+    pub script: Option<Script>,
+    /// Deploys or calls the contract at `to`, the fee pays for its gas.
+    pub contract: Option<ContractAction>,
+    /// Issues, transfers or burns a token, NoCoin only pays the fee.
+    pub token: Option<TokenAction>,
+    /// Earliest block the transaction may go into, signed like the rest of it.
+    pub lock: Option<Lock>,
+    /// Tells apart payments which are otherwise the same, so that a mined one
+    /// can be refused when it's sent again.
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Lock {
+    Height(usize),
+    /// Unix time in seconds, compared with timestamps of blocks.
+    Timestamp(usize),
+}
+
+impl Lock {
+    pub fn is_open(&self, height: usize, timestamp: usize) -> bool {
+        match *self {
+            Lock::Height(unlocks_at) => height >= unlocks_at,
+            Lock::Timestamp(unlocks_at) => timestamp >= unlocks_at,
+        }
+    }
+}
+
+/// Hex SHA-256 of the serialized transaction.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct TransactionId(pub String);
+
+/// Single accounts sign on their own, multisig accounts need signatures of
+/// enough of their co-signers, script accounts need a witness their script accepts.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Proof {
+    Single(Signature),
+    Multisig(Vec<Cosignature>),
+    Script(Vec<Vec<u8>>),
+}
+
+/// What a client submits, the transaction together with the sender's proof of it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SignedTransaction {
+    pub transaction: Transaction,
+    pub proof: Proof,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -25,17 +84,40 @@ pub struct AffordableTransaction(pub Transaction);
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProvenTransaction {
     pub transaction: AffordableTransaction,
-    pub proof: Option<RSAEncodedMsg>,
+    pub proof: Option<Proof>,
 }
 
 impl Transaction {
-    const MINING_REWARD: NoCoin = NoCoin(10.);
+    pub const MINING_REWARD: NoCoin = NoCoin(10.);
     pub fn new(from: Option<NodeId>, to: NodeId, fee: NoCoin, ammount: NoCoin) -> Self {
         Self {
             from,
             to,
             fee,
             ammount,
+            sender_key: None,
+            multisig: None,
+            script: None,
+            contract: None,
+            token: None,
+            lock: None,
+            nonce: rand::random(),
+        }
+    }
+
+    /// Whether a block at `height` made at `timestamp` may include the transaction.
+    pub fn is_mature(&self, height: usize, timestamp: usize) -> bool {
+        self.lock.map(|l| l.is_open(height, timestamp)).unwrap_or(true)
+    }
+
+    pub fn id(&self) -> Result<TransactionId> {
+        Ok(TransactionId(format!("{:x}", Sha256::digest(serialize(self)?))))
+    }
+
+    pub fn from_key(key: &PubKey, to: NodeId, fee: NoCoin, ammount: NoCoin) -> Self {
+        Self {
+            sender_key: Some(key.clone()),
+            ..Self::new(Some(NodeId::of_key(key)), to, fee, ammount)
         }
     }
 }
@@ -45,27 +127,26 @@ pub fn create_transaction(
     recipient: &NodeId,
     ammount: NoCoin,
     fee: NoCoin,
+    lock: Option<Lock>,
 ) -> Result<ProvenTransaction> {
-    let transaction = Transaction::new(
-        Some(network.user.node.id.clone()),
-        recipient.clone(),
-        fee,
-        ammount,
-    );
-    let affordable = map_to_affordable(&network, transaction)?;
-    approve(affordable, &network.user)
+    let transaction = Transaction {
+        lock,
+        ..Transaction::new(Some(network.user.node.id), *recipient, fee, ammount)
+    };
+    let affordable = map_to_affordable(network, transaction)?;
+    approve(affordable, &network.user.priv_key)
 }
 
-fn approve(transaction: AffordableTransaction, user: &User) -> Result<ProvenTransaction> {
+pub fn approve(transaction: AffordableTransaction, key: &PrivKey) -> Result<ProvenTransaction> {
     let serialized = serialize(&transaction.0)?;
-    let proof = encode_message(&serialized, &user.priv_key)?;
+    let proof = Proof::Single(sign(&serialized, key)?);
     Ok(ProvenTransaction { proof: Some(proof), transaction })
 }
 
 pub fn verify_transaction(
     network: &Network,
     transaction: Transaction,
-    proof: Vec<u8>,
+    proof: Proof,
 ) -> Result<ProvenTransaction> {
     let transaction = map_to_affordable(network, transaction)?;
     prove_transaction(network, transaction, proof)
@@ -79,46 +160,255 @@ fn find_sender<'a>(network: &'a Network, id: &'a NodeId) -> Result<&'a Node> {
         .ok_or(anyhow!(format!("No node with id: {:?}", id)))
 }
 
+fn sender_key<'a>(network: &'a Network, transaction: &'a Transaction) -> Result<&'a PubKey> {
+    let sender = transaction
+        .from
+        .as_ref()
+        .ok_or(anyhow!("Mining reward has no sender to prove it"))?;
+    if !sender.is_derived() {
+        return Ok(&find_sender(network, sender)?.pub_key);
+    }
+    let key = transaction
+        .sender_key
+        .as_ref()
+        .ok_or(anyhow!("Spending from {:?} requires its key", sender))?;
+    if NodeId::of_key(key) != *sender {
+        bail!("Key doesn't belong to {:?}", sender)
+    }
+    ensure_key_fits(network, key)?;
+    Ok(key)
+}
+
+fn proven_sender(transaction: &Transaction) -> Result<&NodeId> {
+    transaction
+        .from
+        .as_ref()
+        .ok_or(anyhow!("Mining reward has no sender to prove it"))
+}
+
 fn prove_transaction(
     network: &Network,
     transaction: AffordableTransaction,
-    proof: Vec<u8>,
+    proof: Proof,
 ) -> Result<ProvenTransaction> {
-    let sender = find_sender(network, transaction.0.from.as_ref().unwrap())?;
     let serialized = serialize(&transaction.0)?;
-    let proof = verify_message(&serialized, proof, &sender.pub_key)?;
+    match &proof {
+        Proof::Single(signature) => {
+            let key = sender_key(network, &transaction.0)?;
+            verify(&serialized, signature, key).context(InvalidSignature)?;
+        }
+        Proof::Multisig(cosignatures) => {
+            let sender = proven_sender(&transaction.0)?;
+            find_policy(&network.blockchain, sender)
+                .ok_or(anyhow!("{:?} is not a registered multisig account", sender))?
+                .verify(&serialized, cosignatures)
+                .context(InvalidSignature)?;
+        }
+        Proof::Script(witness) => {
+            let sender = proven_sender(&transaction.0)?;
+            let context = ScriptContext {
+                message: &serialized,
+                lock: transaction.0.lock,
+            };
+            find_script(&network.blockchain, sender)
+                .ok_or(anyhow!("{:?} is not a script account", sender))?
+                .execute(witness, &context)
+                .context(InvalidSignature)?;
+        }
+    }
     Ok(ProvenTransaction { proof: Some(proof), transaction })
 }
 
-fn map_to_affordable(network: &Network, transaction: Transaction) -> Result<AffordableTransaction> {
+pub(super) fn map_to_affordable(network: &Network, transaction: Transaction) -> Result<AffordableTransaction> {
+    let (ammount, fee) = (transaction.ammount.0, transaction.fee.0);
+    if !ammount.is_finite() || !fee.is_finite() || ammount < 0. || fee < 0. {
+        bail!("Ammount {} and fee {} must be finite and not negative", ammount, fee)
+    }
+    if let Some(policy) = transaction.multisig.as_ref() {
+        policy.validate(network)?;
+        if policy.address()? != transaction.to {
+            bail!("Multisig registration must pay to {:?}", policy.address()?)
+        }
+    }
+    if let Some(script) = transaction.script.as_ref() {
+        script.validate()?;
+        if script.address()? != transaction.to {
+            bail!("Script registration must pay to {:?}", script.address()?)
+        }
+    }
+    validate_action(network, &transaction)?;
+    validate_token(network, &transaction)?;
     if let Some(sender) = transaction.from.as_ref() {
-        let sender = find_sender(network, sender)?;
+        if !sender.is_derived() {
+            find_sender(network, sender)?;
+        }
         let cash = network
             .cache
             .wallet
-            .get(&sender.id)
-            .map(|c| c.clone())
-            .unwrap_or_else(|| calculate_wallet(&sender.id, &network.blockchain));
-        if cash <= transaction.ammount + transaction.fee {
+            .get(sender)
+            .copied()
+            .unwrap_or_else(|| calculate_wallet(sender, &network.blockchain));
+        if cash < transaction.ammount + transaction.fee {
             Err(anyhow!(
                 "Sender doesn't have enough coins to complete transaction.".to_owned()
             ))
         } else {
-            if transaction.ammount != Transaction::MINING_REWARD || transaction.fee.0 != 0. {
-                Err(anyhow!("Mining reward must have ammount equal to {:?} and fee eq to 0, but was {:?}", 
-                    Transaction::MINING_REWARD, 
-                    transaction))
-            } else {
-                Ok(AffordableTransaction(transaction))
-            }
+            Ok(AffordableTransaction(transaction))
         }
-    } else {
+    } else if transaction.ammount != Transaction::MINING_REWARD || transaction.fee.0 != 0. {
         // mining reward, assumes it is affordable but mining reward must be constant
+        Err(anyhow!("Mining reward must have ammount equal to {:?} and fee eq to 0, but was {:?}",
+            Transaction::MINING_REWARD,
+            transaction))
+    } else {
         Ok(AffordableTransaction(transaction))
     }
 }
 
+/// Coins the sender already promised to payments waiting in the poll.
+pub(super) fn pending_spends(network: &Network, sender: &NodeId) -> NoCoin {
+    network
+        .transactions_poll
+        .iter()
+        .map(|t| &t.transaction.0)
+        .filter(|t| t.from.as_ref() == Some(sender))
+        .fold(NoCoin(0.), |spent, t| spent + t.ammount + t.fee)
+}
+
+/// The sender must afford the transaction on top of its payments still in the poll.
+pub(super) fn ensure_affordable_with_pending(network: &Network, transaction: &Transaction) -> Result<()> {
+    let Some(sender) = transaction.from.as_ref() else {
+        return Ok(());
+    };
+    let cash = calculate_wallet(sender, &network.blockchain);
+    let needed = pending_spends(network, sender) + transaction.ammount + transaction.fee;
+    if cash < needed {
+        bail!("{:?} has {:?}, but its pending payments with this one need {:?}", sender, cash, needed)
+    }
+    Ok(())
+}
+
 pub fn create_mining_reward(miner: NodeId) -> ProvenTransaction {
     let transaction = Transaction::new(None, miner, NoCoin(0.), Transaction::MINING_REWARD);
     ProvenTransaction { transaction: AffordableTransaction(transaction), proof: None }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        blockchain::Draft,
+        envelope::unix_timestamp,
+        mining::try_mine_any,
+        network::{
+            create_mined_block, mineable_transactions, pending_payments, try_add_block,
+            try_add_transaction, try_send_payment,
+        },
+        testing::{funded_network, mine_on},
+        wallet::calculate_wallet,
+    };
+
+    use super::*;
+
+    #[test]
+    fn submitted_payment_is_accepted_once() {
+        let mut network = funded_network();
+        let (id, signed) = try_send_payment(&mut network, NodeId(8101), NoCoin(3.), NoCoin(1.), None).unwrap();
+        network.transactions_poll.clear();
+
+        let body = serde_json::to_string(&signed).unwrap();
+        let submitted: SignedTransaction = serde_json::from_str(&body).unwrap();
+        let mut tampered = submitted.clone();
+        tampered.transaction.ammount = NoCoin(5.);
+
+        assert!(try_add_transaction(&mut network, tampered.transaction, tampered.proof).is_err());
+        let accepted = try_add_transaction(&mut network, submitted.transaction.clone(), submitted.proof.clone());
+        assert_eq!(accepted.unwrap(), id);
+        assert!(try_add_transaction(&mut network, submitted.transaction, submitted.proof).is_err());
+        assert_eq!(network.transactions_poll.len(), 1);
+    }
+
+    #[test]
+    fn mined_payment_cannot_be_replayed() {
+        let mut network = funded_network();
+        let payee = NodeId(8101);
+        let (_, signed) = try_send_payment(&mut network, payee, NoCoin(3.), NoCoin(1.), None).unwrap();
+        let (_, again) = try_send_payment(&mut network, payee, NoCoin(3.), NoCoin(1.), None).unwrap();
+        let block = mine_on(network.blockchain.last_block(), network.transactions_poll.clone(), NodeId(8102));
+        try_add_block(&mut network, block).unwrap();
+
+        let replayed = try_add_transaction(&mut network, signed.transaction, signed.proof);
+
+        assert!(replayed.is_err());
+        assert!(network.blockchain.contains(&again.transaction));
+        assert_eq!(calculate_wallet(&payee, &network.blockchain), NoCoin(6.));
+    }
+
+    #[test]
+    fn locked_payment_waits_for_its_height() {
+        let mut network = funded_network();
+        let payee = NodeId(8101);
+        let unlocks_at = network.blockchain.height() + 2;
+        try_send_payment(&mut network, payee, NoCoin(3.), NoCoin(1.), Some(Lock::Height(unlocks_at))).unwrap();
+
+        assert!(mineable_transactions(&network).is_empty());
+        let pending = pending_payments(&network, &payee).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert!(!pending[0].unlocked);
+        let (tip, miner) = (network.blockchain.last_block().clone(), network.user.node.id);
+        let premature = mine_on(&tip, network.transactions_poll.clone(), miner);
+        assert!(try_add_block(&mut network, premature).is_err());
+
+        try_add_block(&mut network, mine_on(&tip, vec![], miner)).unwrap();
+        let mature = mineable_transactions(&network);
+        assert_eq!(mature.len(), 1);
+        let tip = network.blockchain.last_block().clone();
+        try_add_block(&mut network, mine_on(&tip, mature, miner)).unwrap();
+        assert!(network.transactions_poll.is_empty());
+    }
+
+    #[test]
+    fn time_lock_needs_an_honest_timestamp() {
+        let mut network = funded_network();
+        let in_a_day = unix_timestamp() as usize + 24 * 60 * 60;
+        try_send_payment(&mut network, NodeId(8101), NoCoin(3.), NoCoin(1.), Some(Lock::Timestamp(in_a_day))).unwrap();
+        let mut transactions = network.transactions_poll.clone();
+        transactions.push(create_mining_reward(network.user.node.id));
+        let included: Vec<_> = transactions.iter().collect();
+        let mut draft = Draft::on(network.blockchain.last_block());
+        draft.timestamp = in_a_day;
+        let (hash, nonce) = try_mine_any(&draft, 1, &transactions).unwrap();
+        let future = create_mined_block(draft, hash, nonce, &included, network.user.node.id);
+        let mut backdated = future.clone();
+        backdated.header.timestamp = in_a_day - 24 * 60 * 60;
+
+        assert!(try_add_block(&mut network, future).is_err());
+        assert!(try_add_block(&mut network, backdated).is_err());
+
+        assert_eq!(network.blockchain.height(), 1);
+        assert_eq!(network.transactions_poll.len(), 1);
+    }
+
+    #[test]
+    fn payments_must_be_real_and_covered() {
+        let mut network = funded_network();
+        let negative = Transaction::new(Some(network.user.node.id), NodeId(8101), NoCoin(0.), NoCoin(-5.));
+        let invalid = Transaction::new(Some(network.user.node.id), NodeId(8101), NoCoin(f32::NAN), NoCoin(1.));
+
+        assert!(map_to_affordable(&network, negative).is_err());
+        assert!(map_to_affordable(&network, invalid).is_err());
+        try_send_payment(&mut network, NodeId(8101), NoCoin(8.), NoCoin(1.), None).unwrap();
+        assert!(try_send_payment(&mut network, NodeId(8102), NoCoin(8.), NoCoin(1.), None).is_err());
+
+        assert_eq!(network.transactions_poll.len(), 1);
+    }
+
+    #[test]
+    fn lock_is_covered_by_signature() {
+        let mut network = funded_network();
+        let (_, mut signed) = try_send_payment(&mut network, NodeId(8101), NoCoin(3.), NoCoin(1.), None).unwrap();
+        network.transactions_poll.clear();
+
+        signed.transaction.lock = Some(Lock::Timestamp(usize::MAX));
+
+        assert!(try_add_transaction(&mut network, signed.transaction, signed.proof).is_err());
+    }
+}
@@ -1,22 +1,30 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
 
 use super::{
     blockchain::{Block, Blockchain, NoCoin},
+    mining::BlockHash,
     network::NodeId,
+    token::{validate_symbol, TokenAction, TokenInfo, MAX_DECIMALS, MAX_NAME_LEN},
     transaction::Transaction,
 };
 
 pub fn calculate_all_wallets(blockchain: &Blockchain) -> HashMap<NodeId, NoCoin> {
     let mut result = HashMap::new();
     for block in blockchain.0.iter() {
-        *result.entry(block.mined_by.clone()).or_insert(NoCoin(0.)) += mining_fees_gain(block);
+        *result.entry(block.mined_by).or_insert(NoCoin(0.)) += mining_fees_gain(block);
         for proven_transaction in block.transactions.0.iter() {
             let transaction = &proven_transaction.transaction.0;
             if let Some(from) = transaction.from.as_ref() {
-                *result.entry(from.clone()).or_insert(NoCoin(0.)) +=
+                *result.entry(*from).or_insert(NoCoin(0.)) +=
                     NoCoin(0.) - transaction.ammount - transaction.fee;
             }
-            *result.entry(transaction.to.clone()).or_insert(NoCoin(0.)) += transaction.ammount;
+            *result.entry(transaction.to).or_insert(NoCoin(0.)) += transaction.ammount;
         }
     }
     result
@@ -59,17 +67,320 @@ fn transactions_gain_for(id: &NodeId, blockchain: &Blockchain) -> NoCoin {
         })
 }
 
+/// Sender paying itself, like token issues and burns do, still pays the fee.
 fn balance_after_transaction(id: &NodeId, transaction: &Transaction) -> NoCoin {
+    let mut balance = NoCoin(0.);
     if transaction.to == *id {
-        return transaction.ammount;
-    } else {
-        if let Some(s) = transaction.from.as_ref() {
-            if s == id {
-                return NoCoin(0.) - transaction.ammount - transaction.fee;
+        balance += transaction.ammount;
+    }
+    if transaction.from.as_ref() == Some(id) {
+        balance += NoCoin(0.) - transaction.ammount - transaction.fee;
+    }
+    balance
+}
+
+/// Issued tokens and who holds them, replayed from the chain like `calculate_wallet`.
+/// Transactions breaking the rules at the point they are replayed, like two
+/// transfers in one block spending the same units, change nothing.
+#[derive(Default)]
+pub struct TokenBalances {
+    tokens: HashMap<String, TokenInfo>,
+    balances: HashMap<(NodeId, String), u64>,
+}
+
+impl TokenBalances {
+    pub fn token(&self, symbol: &str) -> Option<&TokenInfo> {
+        self.tokens.get(symbol)
+    }
+
+    pub fn balance(&self, id: &NodeId, symbol: &str) -> u64 {
+        self.balances.get(&(*id, symbol.to_owned())).copied().unwrap_or(0)
+    }
+
+    /// Every token the address ever held, by symbol.
+    pub fn of(&self, id: &NodeId) -> BTreeMap<String, u64> {
+        self.balances
+            .iter()
+            .filter(|((holder, _), _)| holder == id)
+            .map(|((_, symbol), units)| (symbol.clone(), *units))
+            .collect()
+    }
+
+    fn spendable(&self, sender: &NodeId, symbol: &str, ammount: u64) -> Result<()> {
+        if !self.tokens.contains_key(symbol) {
+            bail!("No token {:?} was issued", symbol)
+        }
+        if ammount == 0 {
+            bail!("Token ammount must be positive")
+        }
+        if self.balance(sender, symbol) < ammount {
+            bail!("Sender doesn't have {} units of {}", ammount, symbol)
+        }
+        Ok(())
+    }
+
+    pub(super) fn check(&self, transaction: &Transaction) -> Result<()> {
+        let Some(action) = transaction.token.as_ref() else {
+            return Ok(());
+        };
+        let sender = transaction
+            .from
+            .as_ref()
+            .ok_or(anyhow!("Mining reward can't carry tokens"))?;
+        match action {
+            TokenAction::Issue { symbol, name, decimals, supply } => {
+                validate_symbol(symbol)?;
+                if name.is_empty() || name.len() > MAX_NAME_LEN {
+                    bail!("Token name must have 1 to {} characters", MAX_NAME_LEN)
+                }
+                if *decimals > MAX_DECIMALS {
+                    bail!("Token can have at most {} decimals", MAX_DECIMALS)
+                }
+                if *supply == 0 {
+                    bail!("Token supply must be positive")
+                }
+                if self.tokens.contains_key(symbol) {
+                    bail!("Token {:?} is already issued", symbol)
+                }
+                if transaction.to != *sender {
+                    bail!("Issued tokens must go to the issuer")
+                }
+            }
+            TokenAction::Transfer { symbol, ammount } => self.spendable(sender, symbol, *ammount)?,
+            TokenAction::Burn { symbol, ammount } => {
+                self.spendable(sender, symbol, *ammount)?;
+                if transaction.to != *sender {
+                    bail!("Burn must be sent to the burning account")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, transaction: &Transaction) {
+        let (Some(action), Some(sender)) = (&transaction.token, transaction.from) else {
+            return;
+        };
+        if self.check(transaction).is_err() {
+            return;
+        }
+        let symbol = action.symbol().to_owned();
+        match action {
+            TokenAction::Issue { name, decimals, supply, .. } => {
+                self.tokens.insert(
+                    symbol.clone(),
+                    TokenInfo {
+                        symbol: symbol.clone(),
+                        name: name.clone(),
+                        decimals: *decimals,
+                        supply: *supply,
+                        issuer: sender,
+                    },
+                );
+                self.balances.insert((sender, symbol), *supply);
+            }
+            TokenAction::Transfer { ammount, .. } => {
+                *self.balances.entry((sender, symbol.clone())).or_default() -= ammount;
+                *self.balances.entry((transaction.to, symbol)).or_default() += ammount;
+            }
+            TokenAction::Burn { ammount, .. } => {
+                *self.balances.entry((sender, symbol.clone())).or_default() -= ammount;
+                self.tokens.get_mut(&symbol).unwrap().supply -= ammount;
+            }
+        }
+    }
+}
+
+pub fn calculate_token_balances(blockchain: &Blockchain) -> TokenBalances {
+    let mut balances = TokenBalances::default();
+    for block in blockchain.0.iter() {
+        for proven_transaction in block.transactions.0.iter() {
+            balances.apply(&proven_transaction.transaction.0);
+        }
+    }
+    balances
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Incoming,
+    Outgoing,
+    /// Paid by the address as sender, or collected by it as miner.
+    Fee,
+    Coinbase,
+}
+
+#[derive(Debug, Clone)]
+struct IndexedEntry {
+    height: usize,
+    timestamp: usize,
+    kind: EntryKind,
+    counterparty: Option<NodeId>,
+    ammount: NoCoin,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub height: usize,
+    pub timestamp: usize,
+    pub kind: EntryKind,
+    pub counterparty: Option<NodeId>,
+    pub ammount: NoCoin,
+    pub balance: NoCoin,
+    pub confirmations: usize,
+}
+
+/// Every balance change of every address, in chain order. Catches up with the
+/// chain lazily and starts over if the blocks it indexed were replaced.
+#[derive(Default)]
+pub struct HistoryIndex {
+    entries: HashMap<NodeId, Vec<IndexedEntry>>,
+    indexed: Vec<BlockHash>,
+}
+
+impl HistoryIndex {
+    pub fn sync(&mut self, blockchain: &Blockchain) {
+        if !blockchain.starts_with(&self.indexed) {
+            *self = Self::default();
+        }
+        for block in &blockchain.0[self.indexed.len()..] {
+            self.index_block(block);
+            self.indexed.push(block.header.hash.clone());
+        }
+    }
+
+    fn push(&mut self, address: NodeId, block: &Block, kind: EntryKind, counterparty: Option<NodeId>, ammount: NoCoin) {
+        self.entries.entry(address).or_default().push(IndexedEntry {
+            height: block.header.index.0,
+            timestamp: block.header.timestamp,
+            kind,
+            counterparty,
+            ammount,
+        });
+    }
+
+    fn index_block(&mut self, block: &Block) {
+        for proven in &block.transactions.0 {
+            let transaction = &proven.transaction.0;
+            match transaction.from {
+                Some(from) => {
+                    self.push(from, block, EntryKind::Outgoing, Some(transaction.to), NoCoin(0.) - transaction.ammount);
+                    if transaction.fee.0 != 0. {
+                        self.push(from, block, EntryKind::Fee, Some(block.mined_by), NoCoin(0.) - transaction.fee);
+                        self.push(block.mined_by, block, EntryKind::Fee, Some(from), transaction.fee);
+                    }
+                    self.push(transaction.to, block, EntryKind::Incoming, Some(from), transaction.ammount);
+                }
+                None => self.push(transaction.to, block, EntryKind::Coinbase, None, transaction.ammount),
             }
         }
     }
-    NoCoin(0.)
+
+    /// All entries of the address, oldest first, with the balance after each one.
+    pub fn statement(&self, address: &NodeId, blockchain: &Blockchain) -> Vec<HistoryEntry> {
+        let tip = blockchain.height();
+        let mut balance = NoCoin(0.);
+        self.entries
+            .get(address)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|e| {
+                        balance += e.ammount;
+                        HistoryEntry {
+                            height: e.height,
+                            timestamp: e.timestamp,
+                            kind: e.kind,
+                            counterparty: e.counterparty,
+                            ammount: e.ammount,
+                            balance,
+                            confirmations: (tip + 1).saturating_sub(e.height),
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub fn statement_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = String::from("height,timestamp,kind,counterparty,ammount,balance,confirmations\n");
+    for e in entries {
+        let counterparty = e.counterparty.map(|c| c.0.to_string()).unwrap_or_default();
+        let _ = writeln!(
+            csv,
+            "{},{},{:?},{},{},{},{}",
+            e.height, e.timestamp, e.kind, counterparty, e.ammount.0, e.balance.0, e.confirmations
+        );
+    }
+    csv
+}
+
+#[cfg(test)]
+mod history_tests {
+    use crate::domain::{
+        blockchain::{genesis_block, BlocksTransactions},
+        transaction::{create_mining_reward, AffordableTransaction, ProvenTransaction},
+    };
+
+    use super::*;
+
+    fn block(height: usize, mined_by: NodeId, transactions: Vec<Transaction>) -> Block {
+        let mut block = genesis_block();
+        block.header.index.0 = height;
+        block.header.hash = BlockHash(format!("{:064}", height));
+        block.mined_by = mined_by;
+        block.transactions = BlocksTransactions(
+            transactions
+                .into_iter()
+                .map(|t| ProvenTransaction {
+                    transaction: AffordableTransaction(t),
+                    proof: None,
+                })
+                .collect(),
+        );
+        block
+    }
+
+    #[test]
+    fn statement_tracks_running_balance() {
+        let (alice, bob, miner) = (NodeId(8100), NodeId(8101), NodeId(8102));
+        let mut chain = Blockchain(vec![block(0, miner, vec![])]);
+        chain.0.push(block(1, miner, vec![create_mining_reward(alice).transaction.0]));
+        chain.0.push(block(2, miner, vec![Transaction::new(Some(alice), bob, NoCoin(1.), NoCoin(3.))]));
+        let mut index = HistoryIndex::default();
+
+        index.sync(&chain);
+        let statement = index.statement(&alice, &chain);
+
+        let kinds: Vec<_> = statement.iter().map(|e| (e.kind, e.balance, e.confirmations)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (EntryKind::Coinbase, NoCoin(10.), 2),
+                (EntryKind::Outgoing, NoCoin(7.), 1),
+                (EntryKind::Fee, NoCoin(6.), 1),
+            ]
+        );
+        assert_eq!(statement.last().unwrap().balance, calculate_wallet(&alice, &chain));
+        assert_eq!(index.statement(&miner, &chain)[0].ammount, NoCoin(1.));
+        assert_eq!(statement_csv(&statement).lines().count(), 4);
+    }
+
+    #[test]
+    fn reindexes_replaced_blocks() {
+        let (alice, miner) = (NodeId(8100), NodeId(8102));
+        let mut chain = Blockchain(vec![block(0, miner, vec![])]);
+        chain.0.push(block(1, miner, vec![create_mining_reward(alice).transaction.0]));
+        let mut index = HistoryIndex::default();
+        index.sync(&chain);
+
+        chain.0[1] = block(1, miner, vec![]);
+        chain.0[1].header.hash = BlockHash("f".repeat(64));
+        index.sync(&chain);
+
+        assert!(index.statement(&alice, &chain).is_empty());
+    }
 }
 
 // #[cfg(test)]
@@ -1,5 +1,9 @@
+#[allow(non_snake_case)]
 mod AI;
 mod domain;
+mod price;
+mod simulation;
+mod transport;
 mod web;
 
 use std::{
@@ -8,12 +12,70 @@ use std::{
 };
 
 use anyhow::Result;
+use transport::PeerTransport;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
+    if args().nth(1).as_deref() == Some("rotate-key") {
+        let port = args().nth(2).unwrap().parse().unwrap();
+        return AI::rotate_key(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into());
+    }
+    if args().nth(1).as_deref() == Some("simulate") {
+        let nodes = args().nth(2).unwrap().parse().unwrap();
+        let height = args().nth(3).unwrap().parse().unwrap();
+        return simulation::simulate(nodes, height).await;
+    }
+    if args().nth(1).as_deref() == Some("simulate-mining") {
+        let nodes = args().nth(2).unwrap().parse().unwrap();
+        let height = args().nth(3).unwrap().parse().unwrap();
+        let seed = args().nth(4).map(|s| s.parse().unwrap()).unwrap_or(0);
+        let report = simulation::simulate_mining(&simulation::MiningConfig::uniform(seed, nodes, height))?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    if args().nth(1).as_deref() == Some("selfish-mining") {
+        let nodes = args().nth(2).unwrap().parse().unwrap();
+        let runs = args().nth(3).unwrap().parse().unwrap();
+        let height = args().nth(4).unwrap().parse().unwrap();
+        let shares: Vec<_> = (1..=10).map(|i| i as f64 * 0.05).collect();
+        let points = simulation::selfish_revenue(nodes, &shares, runs, height, 0)?;
+        println!("{}", serde_json::to_string_pretty(&points)?);
+        return Ok(());
+    }
+    if args().nth(1).as_deref() == Some("double-spend") {
+        let runs = args().nth(2).unwrap().parse().unwrap();
+        let max_confirmations = args().nth(3).unwrap().parse().unwrap();
+        let shares: Vec<_> = (1..=6).map(|i| i as f64 * 0.1).collect();
+        let confirmations: Vec<_> = (1..=max_confirmations).collect();
+        let points = simulation::double_spend_success(&shares, &confirmations, runs, 20, 0).await?;
+        println!("{}", serde_json::to_string_pretty(&points)?);
+        return Ok(());
+    }
+    if args().nth(1).as_deref() == Some("eclipse") {
+        let honest = args().nth(2).unwrap().parse().unwrap();
+        let sybils = args().nth(3).unwrap().parse().unwrap();
+        let max_peers = args().nth(4).unwrap().parse().unwrap();
+        let reports = simulation::eclipse_defenses(honest, sybils, max_peers).await?;
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+    if args().nth(1).as_deref() == Some("scenario") {
+        let path = args().nth(2).unwrap();
+        let output = args().nth(3).map(Into::into);
+        simulation::run_scenario_file(path.as_ref(), output).await?;
+        return Ok(());
+    }
+    if args().nth(1).as_deref() == Some("mock-ticker") {
+        let port = args().nth(2).unwrap().parse().unwrap();
+        return AI::serve_mock_ticker(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into()).await;
+    }
     let port = args().nth(1).unwrap().parse().unwrap();
-    AI::start(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into()).await
+    let peer_transport = match args().nth(2).as_deref() {
+        Some("tcp") => PeerTransport::Tcp,
+        _ => PeerTransport::Http,
+    };
+    AI::start(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into(), peer_transport).await
 }
@@ -0,0 +1,59 @@
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+
+use super::PriceFeed;
+
+pub const BLOCKCHAIN_INFO_TICKER: &str = "https://blockchain.info/ticker";
+
+/// Reads the `last` USD price of a ticker in blockchain.info's format.
+pub struct HttpFeed {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpFeed {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Default for HttpFeed {
+    fn default() -> Self {
+        Self::new(BLOCKCHAIN_INFO_TICKER)
+    }
+}
+
+pub(super) fn parse_ticker(body: &str) -> Result<f64> {
+    let map: serde_json::Map<_, _> = serde_json::from_str(body)?;
+    let usd = map
+        .get("USD")
+        .ok_or(anyhow!("Didn't find USD node in response."))?;
+    let last = usd.get("last").ok_or(anyhow!(
+        "Didn't find 'last' in USD node. Node was: {:?}",
+        usd
+    ))?;
+    match last {
+        serde_json::Value::Number(n) => n
+            .as_f64()
+            .ok_or(anyhow!("Last number value is not a float.")),
+        _ => bail!("Last has invalid type. Expected number, was: {:?}", last),
+    }
+}
+
+#[async_trait]
+impl PriceFeed for HttpFeed {
+    async fn bitcoin_usd(&self) -> Result<f64> {
+        let response = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        parse_ticker(&response)
+    }
+}
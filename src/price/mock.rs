@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::info;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::PriceFeed;
+
+/// Always the same price, the fixture behind a mock ticker.
+pub struct FixedFeed(pub f64);
+
+#[async_trait]
+impl PriceFeed for FixedFeed {
+    async fn bitcoin_usd(&self) -> Result<f64> {
+        Ok(self.0)
+    }
+}
+
+fn ticker_body(price: f64) -> String {
+    serde_json::json!({
+        "USD": { "15m": price, "last": price, "buy": price, "sell": price, "symbol": "$" }
+    })
+    .to_string()
+}
+
+async fn answer(mut stream: TcpStream, feed: &dyn PriceFeed) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let body = ticker_body(feed.bitcoin_usd().await?);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Local stand-in for blockchain.info, answers every request with the
+/// ticker of `feed`'s next price. Runs until the listener fails.
+pub async fn serve_ticker(listener: TcpListener, feed: Arc<dyn PriceFeed>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        if let Err(e) = answer(stream, feed.as_ref()).await {
+            info!("Mock ticker failed to answer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::price::{http::parse_ticker, HttpFeed, Model, SyntheticFeed};
+
+    use super::*;
+
+    async fn start(feed: Arc<dyn PriceFeed>) -> HttpFeed {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ticker", listener.local_addr().unwrap());
+        tokio::spawn(serve_ticker(listener, feed));
+        HttpFeed::new(url)
+    }
+
+    #[tokio::test]
+    async fn http_feed_reads_mock_ticker() {
+        let fixed = start(Arc::new(FixedFeed(27_123.5))).await;
+        let model = Model::Gbm { drift: 0., volatility: 0.02 };
+        let moving = start(Arc::new(SyntheticFeed::new(7, 30_000., model))).await;
+        let expected = SyntheticFeed::new(7, 30_000., model);
+
+        assert_eq!(fixed.bitcoin_usd().await.unwrap(), 27_123.5);
+        for _ in 0..3 {
+            assert_eq!(moving.bitcoin_usd().await.unwrap(), expected.next_price());
+        }
+    }
+
+    #[test]
+    fn malformed_tickers_are_rejected() {
+        assert_eq!(parse_ticker(&ticker_body(100.)).unwrap(), 100.);
+        assert!(parse_ticker(r#"{"EUR": {"last": 1.0}}"#).is_err());
+        assert!(parse_ticker(r#"{"USD": {"last": "1.0"}}"#).is_err());
+        assert!(parse_ticker("<html>").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+mod http;
+mod mock;
+mod synthetic;
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+pub use http::HttpFeed;
+pub use mock::{serve_ticker, FixedFeed};
+pub use synthetic::{Model, NoCoinPrice, Peg, SyntheticFeed};
+
+/// Where a node learns what bitcoin is worth. Only `HttpFeed` needs the
+/// internet, the others make simulations and tests repeatable.
+#[async_trait]
+pub trait PriceFeed: Send + Sync {
+    /// Latest price of one bitcoin in USD.
+    async fn bitcoin_usd(&self) -> Result<f64>;
+}
+
+/// Bitcoin's price as the feed reports it, and what NoCoin is worth with it.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Quote {
+    pub bitcoin_usd: f64,
+    pub nocoin_usd: f64,
+}
+
+/// Feed together with NoCoin's value pegged to it.
+pub struct PriceOracle {
+    feed: Arc<dyn PriceFeed>,
+    nocoin: Mutex<NoCoinPrice>,
+}
+
+impl PriceOracle {
+    pub fn new(feed: Arc<dyn PriceFeed>, nocoin: NoCoinPrice) -> Self {
+        Self {
+            feed,
+            nocoin: Mutex::new(nocoin),
+        }
+    }
+
+    pub async fn quote(&self) -> Result<Quote> {
+        let bitcoin_usd = self.feed.bitcoin_usd().await?;
+        let nocoin_usd = self.nocoin.lock().unwrap().update(bitcoin_usd);
+        Ok(Quote { bitcoin_usd, nocoin_usd })
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
+
+use super::PriceFeed;
+
+/// Lowest price a random walk can reach, it never goes to zero or below.
+pub const RANDOM_WALK_FLOOR: f64 = 0.01;
+
+/// How a synthetic price moves from one step to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Model {
+    /// Adds a normal step with this standard deviation, in USD.
+    RandomWalk { step: f64 },
+    /// Geometric Brownian motion, `drift` and `volatility` are per step.
+    Gbm { drift: f64, volatility: f64 },
+}
+
+fn standard_normal(rng: &mut impl Rng) -> f64 {
+    // Box-Muller, 1 - u keeps the logarithm away from zero
+    let u: f64 = rng.gen();
+    let v: f64 = rng.gen();
+    (-2. * (1. - u).ln()).sqrt() * (2. * std::f64::consts::PI * v).cos()
+}
+
+/// Prices of a seeded random process, the same seed always gives the same prices.
+pub struct SyntheticFeed {
+    model: Model,
+    state: Mutex<(ChaCha8Rng, f64)>,
+}
+
+impl SyntheticFeed {
+    pub fn new(seed: u64, start: f64, model: Model) -> Self {
+        Self {
+            model,
+            state: Mutex::new((ChaCha8Rng::seed_from_u64(seed), start)),
+        }
+    }
+
+    /// Moves the price by one step and returns it.
+    pub fn next_price(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        let (rng, price) = &mut *state;
+        let z = standard_normal(rng);
+        *price = match self.model {
+            Model::RandomWalk { step } => (*price + step * z).max(RANDOM_WALK_FLOOR),
+            Model::Gbm { drift, volatility } => {
+                *price * (drift - volatility * volatility / 2. + volatility * z).exp()
+            }
+        };
+        *price
+    }
+}
+
+#[async_trait]
+impl PriceFeed for SyntheticFeed {
+    async fn bitcoin_usd(&self) -> Result<f64> {
+        Ok(self.next_price())
+    }
+}
+
+/// How NoCoin's simulated USD value follows bitcoin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peg {
+    /// Always `ratio` of a bitcoin.
+    Fixed { ratio: f64 },
+    /// Starts at `ratio` of a bitcoin, then its log returns have `correlation`
+    /// with bitcoin's. The rest is its own noise of `volatility` per step, which
+    /// should match bitcoin's volatility for the correlation to come out exact.
+    Correlated { ratio: f64, correlation: f64, volatility: f64 },
+}
+
+/// NoCoin's USD value, updated with every new bitcoin price.
+pub struct NoCoinPrice {
+    peg: Peg,
+    rng: ChaCha8Rng,
+    last: Option<(f64, f64)>,
+}
+
+impl NoCoinPrice {
+    pub fn new(seed: u64, peg: Peg) -> Self {
+        Self {
+            peg,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            last: None,
+        }
+    }
+
+    pub fn update(&mut self, bitcoin_usd: f64) -> f64 {
+        let price = match (self.peg, self.last) {
+            (Peg::Fixed { ratio }, _) | (Peg::Correlated { ratio, .. }, None) => ratio * bitcoin_usd,
+            (Peg::Correlated { correlation, volatility, .. }, Some((last_bitcoin, last_price))) => {
+                let correlation = correlation.clamp(-1., 1.);
+                let own = (1. - correlation * correlation).sqrt() * volatility * standard_normal(&mut self.rng);
+                last_price * (correlation * (bitcoin_usd / last_bitcoin).ln() + own).exp()
+            }
+        };
+        self.last = Some((bitcoin_usd, price));
+        price
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_returns(prices: &[f64]) -> Vec<f64> {
+        prices.windows(2).map(|w| (w[1] / w[0]).ln()).collect()
+    }
+
+    fn correlation(a: &[f64], b: &[f64]) -> f64 {
+        let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+        let (ma, mb) = (mean(a), mean(b));
+        let covariance: f64 = a.iter().zip(b).map(|(x, y)| (x - ma) * (y - mb)).sum();
+        let spread = |x: &[f64], m: f64| x.iter().map(|v| (v - m) * (v - m)).sum::<f64>().sqrt();
+        covariance / (spread(a, ma) * spread(b, mb))
+    }
+
+    #[test]
+    fn same_seed_repeats_prices() {
+        let gbm = Model::Gbm { drift: 0.001, volatility: 0.05 };
+        let walk = Model::RandomWalk { step: 5_000. };
+        let prices = |seed, model| {
+            let feed = SyntheticFeed::new(seed, 30_000., model);
+            (0..1_000).map(|_| feed.next_price()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(prices(1, gbm), prices(1, gbm));
+        assert_ne!(prices(1, gbm), prices(2, gbm));
+        assert!(prices(3, gbm).iter().all(|p| *p > 0.));
+        assert!(prices(3, walk).iter().all(|p| *p >= RANDOM_WALK_FLOOR));
+    }
+
+    #[test]
+    fn nocoin_follows_bitcoin() {
+        let feed = SyntheticFeed::new(11, 30_000., Model::Gbm { drift: 0., volatility: 0.02 });
+        let bitcoin: Vec<_> = (0..5_000).map(|_| feed.next_price()).collect();
+        let mut fixed = NoCoinPrice::new(0, Peg::Fixed { ratio: 0.001 });
+        let peg = Peg::Correlated { ratio: 0.001, correlation: 0.6, volatility: 0.02 };
+        let mut correlated = NoCoinPrice::new(0, peg);
+
+        let pegged: Vec<_> = bitcoin.iter().map(|b| fixed.update(*b)).collect();
+        let following: Vec<_> = bitcoin.iter().map(|b| correlated.update(*b)).collect();
+
+        assert!(pegged.iter().zip(&bitcoin).all(|(n, b)| (n - b * 0.001).abs() < 1e-9));
+        assert_eq!(following[0], bitcoin[0] * 0.001);
+        let measured = correlation(&log_returns(&bitcoin), &log_returns(&following));
+        assert!((measured - 0.6).abs() < 0.05, "Correlation was {}", measured);
+    }
+}
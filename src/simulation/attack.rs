@@ -0,0 +1,182 @@
+use anyhow::Result;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+
+use crate::domain::{generate_key, try_send_payment, Block, ChainParams, NoCoin, NodeId, SignatureAlgorithm, TransactionId};
+
+use super::{Simulation, SIMULATION_DIFFICULTY};
+
+/// Honest nodes of a double spend, the merchant among them.
+const HONEST_NODES: u16 = 3;
+const PRICE: NoCoin = NoCoin(9.);
+const FEE: NoCoin = NoCoin(1.);
+
+/// Attacker which pays a merchant and then mines in secret on a chain where
+/// the same coins, and the rewards for mining it, go to its stash instead.
+struct DoubleSpender {
+    id: NodeId,
+    stash: NodeId,
+    payment: TransactionId,
+    withheld: Vec<Block>,
+}
+
+impl DoubleSpender {
+    /// Pays `merchant` for all it has and leaves the others a block lower.
+    async fn pay(simulation: &Simulation, id: NodeId, merchant: NodeId) -> Result<Self> {
+        let payment = simulation.pay(&id, merchant, PRICE, FEE).await?;
+        simulation.hide(&id);
+        let (_, stash_key) = generate_key(SignatureAlgorithm::Ed25519)?;
+        let stash = NodeId::of_key(&stash_key);
+        let mut network = simulation.network(&id)?.lock().await;
+        network.transactions_poll.retain(|t| t.transaction.0.id().ok().as_ref() != Some(&payment));
+        try_send_payment(&mut network, stash, PRICE, FEE, None)?;
+        Ok(Self { id, stash, payment, withheld: vec![] })
+    }
+
+    async fn mine(&mut self, simulation: &Simulation) -> Result<()> {
+        let block = simulation.mine_quietly(&self.id, self.stash).await?;
+        self.withheld.push(block);
+        Ok(())
+    }
+
+    /// Shows up again with every block it kept to itself.
+    async fn release(self, simulation: &Simulation) -> Result<TransactionId> {
+        simulation.reveal(&self.id)?;
+        for block in self.withheld {
+            simulation.announce(&self.id, block).await?;
+        }
+        Ok(self.payment)
+    }
+}
+
+/// Runs one double spend on real nodes. The attacker mines the next block with
+/// `hash_share` chance, else one of the honest nodes does. It releases its chain
+/// once it is longer and the payment has `confirmations`, it gives up when
+/// `give_up_behind` blocks behind. Tells whether the merchant lost the payment.
+async fn double_spend(hash_share: f64, confirmations: usize, give_up_behind: usize, seed: u64) -> Result<bool> {
+    let params = ChainParams {
+        mining_difficulty: SIMULATION_DIFFICULTY,
+        signature_algorithm: SignatureAlgorithm::Ed25519,
+        ..ChainParams::default()
+    };
+    let mut simulation = Simulation::new(params);
+    let attacker = simulation.join().await?;
+    let mut honest = vec![];
+    for _ in 0..HONEST_NODES {
+        honest.push(simulation.join().await?);
+    }
+    let merchant = honest[0];
+    simulation.mine(&attacker).await?;
+    let fork = simulation.height_of(&merchant).await?;
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut spender = DoubleSpender::pay(&simulation, attacker, merchant).await?;
+    loop {
+        let public = simulation.height_of(&merchant).await?;
+        let private = fork + spender.withheld.len();
+        if public >= fork + confirmations && private > public {
+            break;
+        }
+        if public >= private + give_up_behind {
+            return Ok(false);
+        }
+        if rng.gen_bool(hash_share.clamp(0., 1.)) {
+            spender.mine(&simulation).await?;
+        } else {
+            let miner = honest[rng.gen_range(0..honest.len())];
+            simulation.mine(&miner).await?;
+        }
+    }
+    let payment = spender.release(&simulation).await?;
+    let network = simulation.network(&merchant)?.lock().await;
+    let kept = network
+        .blockchain
+        .0
+        .iter()
+        .flat_map(|b| &b.transactions.0)
+        .chain(&network.transactions_poll)
+        .any(|t| t.transaction.0.id().ok().as_ref() == Some(&payment));
+    Ok(!kept)
+}
+
+/// Chance an attacker with `hash_share`, who never gives up, gets a chain longer
+/// than one with `confirmations` blocks. Nakamoto's estimate in the Bitcoin paper
+/// is higher since it already counts catching up to an equal chain.
+pub fn expected_success(hash_share: f64, confirmations: usize) -> f64 {
+    let (q, p) = (hash_share, 1. - hash_share);
+    if q >= p {
+        return 1.;
+    }
+    // Chance the attacker has `k` blocks when the honest nodes find their last one
+    let mut found = p.powi(confirmations as i32);
+    let mut behind = 0.;
+    let mut success = 0.;
+    for k in 0..=confirmations {
+        if k > 0 {
+            found *= q * (k + confirmations - 1) as f64 / k as f64;
+        }
+        behind += found;
+        success += found * (q / p).powi((confirmations - k + 1) as i32);
+    }
+    success + 1. - behind
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttackPoint {
+    pub hash_share: f64,
+    pub confirmations: usize,
+    pub runs: usize,
+    /// Share of the runs in which the merchant lost the payment.
+    pub success_rate: f64,
+    pub expected: f64,
+}
+
+/// Double spends `runs` times for every hash share and number of confirmations
+/// the merchant waits for, shares of a half and above are 51% attacks.
+pub async fn double_spend_success(
+    hash_shares: &[f64],
+    confirmations: &[usize],
+    runs: usize,
+    give_up_behind: usize,
+    seed: u64,
+) -> Result<Vec<AttackPoint>> {
+    let mut points = vec![];
+    for &hash_share in hash_shares {
+        for &depth in confirmations {
+            let mut successes = 0;
+            for run in 0..runs as u64 {
+                if double_spend(hash_share, depth, give_up_behind, seed.wrapping_add(run)).await? {
+                    successes += 1;
+                }
+            }
+            points.push(AttackPoint {
+                hash_share,
+                confirmations: depth,
+                runs,
+                success_rate: successes as f64 / runs.max(1) as f64,
+                expected: expected_success(hash_share, depth),
+            });
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_success_needs_a_longer_chain() {
+        assert!((expected_success(0.1, 1) - 0.0311111).abs() < 1e-6);
+        assert!(expected_success(0.3, 6) < expected_success(0.3, 2));
+        assert_eq!(expected_success(0.5, 10), 1.);
+    }
+
+    #[tokio::test]
+    async fn majority_reverses_deep_payments() {
+        let points = double_spend_success(&[0.1, 0.7], &[4], 10, 8, 0).await.unwrap();
+
+        assert!(points[0].success_rate < 0.2, "{:?}", points);
+        assert!(points[1].success_rate > 0.8, "{:?}", points);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rand::{seq::index::sample, Rng};
+
+use super::scheduler::{exponential, VirtualTime};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Latency {
+    Fixed(VirtualTime),
+    Uniform { min: VirtualTime, max: VirtualTime },
+    /// Never faster than `min`, on top of it waits exponentially with `mean`.
+    Exponential { min: VirtualTime, mean: VirtualTime },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut impl Rng) -> VirtualTime {
+        match self {
+            Latency::Fixed(latency) => *latency,
+            Latency::Uniform { min, max } => VirtualTime(rng.gen_range(min.0..=max.0.max(min.0))),
+            Latency::Exponential { min, mean } => *min + exponential(rng, mean.0 as f64),
+        }
+    }
+}
+
+/// How one node's messages reach another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub latency: Latency,
+    /// Chance a message is never delivered.
+    pub loss: f64,
+    /// Bytes per second, messages on a busy link wait for the ones sent before them.
+    pub bandwidth: Option<u64>,
+}
+
+impl Link {
+    pub fn fixed(latency: VirtualTime) -> Self {
+        Self { latency: Latency::Fixed(latency), loss: 0., bandwidth: None }
+    }
+}
+
+/// Nodes of different groups can't reach each other between `at` and `heal_at`.
+/// Nodes missing from every group make one more group together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub at: VirtualTime,
+    pub heal_at: VirtualTime,
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl Partition {
+    fn group(&self, node: usize) -> usize {
+        self.groups
+            .iter()
+            .position(|g| g.contains(&node))
+            .unwrap_or(self.groups.len())
+    }
+
+    pub fn separates(&self, from: usize, to: usize) -> bool {
+        self.group(from) != self.group(to)
+    }
+}
+
+/// Who talks to whom. In a full mesh the miner sends a block to everybody,
+/// otherwise nodes relay the blocks they adopt to their neighbours.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Topology {
+    FullMesh,
+    /// Neighbours of every node, each link goes both ways.
+    Graph(Vec<Vec<usize>>),
+}
+
+impl Topology {
+    fn from_edges(nodes: usize, edges: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        let mut neighbours = vec![BTreeSet::new(); nodes];
+        for (a, b) in edges.into_iter().filter(|(a, b)| a != b) {
+            neighbours[a].insert(b);
+            neighbours[b].insert(a);
+        }
+        Topology::Graph(neighbours.into_iter().map(|n| n.into_iter().collect()).collect())
+    }
+
+    pub fn ring(nodes: usize) -> Self {
+        Self::from_edges(nodes, (0..nodes).map(|n| (n, (n + 1) % nodes)))
+    }
+
+    /// Every node picks `degree` others at random, so some end up with more neighbours.
+    pub fn random(nodes: usize, degree: usize, rng: &mut impl Rng) -> Self {
+        let degree = degree.min(nodes.saturating_sub(1));
+        let edges: Vec<_> = (0..nodes)
+            .flat_map(|n| {
+                sample(rng, nodes - 1, degree)
+                    .into_iter()
+                    .map(move |o| (n, if o >= n { o + 1 } else { o }))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Self::from_edges(nodes, edges)
+    }
+
+    /// Nodes `node` sends its blocks to, `nodes` is the size of the whole network.
+    pub fn neighbours(&self, node: usize, nodes: usize) -> Vec<usize> {
+        match self {
+            Topology::FullMesh => (0..nodes).filter(|n| *n != node).collect(),
+            Topology::Graph(neighbours) => neighbours[node].clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConditions {
+    pub topology: Topology,
+    pub default_link: Link,
+    /// Links which differ from the default, by sender and recipient.
+    pub links: HashMap<(usize, usize), Link>,
+    pub partitions: Vec<Partition>,
+}
+
+impl NetworkConditions {
+    /// Every message arrives after the same delay.
+    pub fn constant(latency: VirtualTime) -> Self {
+        Self {
+            topology: Topology::FullMesh,
+            default_link: Link::fixed(latency),
+            links: HashMap::new(),
+            partitions: vec![],
+        }
+    }
+
+    pub fn link(&self, from: usize, to: usize) -> &Link {
+        self.links.get(&(from, to)).unwrap_or(&self.default_link)
+    }
+}
+
+/// Messages in flight on the links, it remembers until when each link is busy.
+#[derive(Default)]
+pub struct Wires {
+    busy_until: HashMap<(usize, usize), VirtualTime>,
+}
+
+impl Wires {
+    /// Delay after which a message of `size` bytes sent now arrives, `None` if it's lost.
+    pub fn send(
+        &mut self,
+        conditions: &NetworkConditions,
+        rng: &mut impl Rng,
+        now: VirtualTime,
+        (from, to): (usize, usize),
+        size: u64,
+    ) -> Option<VirtualTime> {
+        let link = conditions.link(from, to);
+        if link.loss > 0. && rng.gen_bool(link.loss.min(1.)) {
+            return None;
+        }
+        let mut delay = link.latency.sample(rng);
+        if let Some(bandwidth) = link.bandwidth {
+            let start = self.busy_until.get(&(from, to)).copied().unwrap_or_default().max(now);
+            let sent = start + VirtualTime(size * 1000 / bandwidth.max(1));
+            self.busy_until.insert((from, to), sent);
+            delay = VirtualTime(sent.0 - now.0) + delay;
+        }
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[test]
+    fn busy_links_queue_messages() {
+        let conditions = NetworkConditions {
+            default_link: Link { bandwidth: Some(1_000), ..Link::fixed(VirtualTime(100)) },
+            ..NetworkConditions::constant(VirtualTime(0))
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut wires = Wires::default();
+
+        let first = wires.send(&conditions, &mut rng, VirtualTime(0), (0, 1), 2_000);
+        let second = wires.send(&conditions, &mut rng, VirtualTime(500), (0, 1), 2_000);
+        let other_link = wires.send(&conditions, &mut rng, VirtualTime(500), (1, 0), 2_000);
+
+        assert_eq!(first, Some(VirtualTime(2_100)));
+        assert_eq!(second, Some(VirtualTime(3_600)));
+        assert_eq!(other_link, Some(VirtualTime(2_100)));
+    }
+
+    #[test]
+    fn random_topology_links_both_ways() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+
+        let topology = Topology::random(50, 4, &mut rng);
+
+        for node in 0..50 {
+            let neighbours = topology.neighbours(node, 50);
+            assert!(neighbours.len() >= 4 && !neighbours.contains(&node));
+            assert!(neighbours.iter().all(|n| topology.neighbours(*n, 50).contains(&node)));
+        }
+        assert_eq!(Topology::ring(4).neighbours(0, 4), vec![1, 3]);
+    }
+
+    #[test]
+    fn lossy_links_drop_messages() {
+        let mut conditions = NetworkConditions::constant(VirtualTime(10));
+        conditions.links.insert((0, 1), Link { loss: 0.3, ..Link::fixed(VirtualTime(10)) });
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut wires = Wires::default();
+
+        let delivered = (0..10_000)
+            .filter(|_| wires.send(&conditions, &mut rng, VirtualTime(0), (0, 1), 1).is_some())
+            .count();
+
+        assert!((delivered as f64 / 10_000. - 0.7).abs() < 0.02);
+        assert!((0..100).all(|_| wires.send(&conditions, &mut rng, VirtualTime(0), (1, 0), 1).is_some()));
+    }
+}
@@ -0,0 +1,216 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    domain::{
+        create_mined_block, create_mining_reward, generate_key, seal, try_mine_any, Block, ChainParams, Draft,
+        Eviction, Node, NodeId, PeerPolicy, PrivKey, SignatureAlgorithm,
+    },
+    transport::Transport,
+};
+
+use super::{Simulation, SIMULATION_DIFFICULTY};
+
+/// Identities of the attacker get ports from here on, all from one address group.
+const SYBIL_PORT: u16 = 40_000;
+/// Blocks the attacker mines on the victim's tip before the flood.
+const STALE_BLOCKS: usize = 2;
+
+/// Many identities of one attacker, they only exist to register.
+struct Sybils {
+    identities: Vec<(Node, PrivKey)>,
+}
+
+impl Sybils {
+    fn new(count: u16) -> Result<Self> {
+        let identities = (0..count)
+            .map(|i| {
+                let (private, public) = generate_key(SignatureAlgorithm::Ed25519)?;
+                let ip = Ipv4Addr::new(198, 51, (i >> 8) as u8, i as u8);
+                let addr: SocketAddr = SocketAddrV4::new(ip, SYBIL_PORT + i).into();
+                let node = Node { id: NodeId(addr.port().into()), addr, pub_key: public };
+                Ok((node, private))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { identities })
+    }
+
+    fn is_sybil(&self, id: &NodeId) -> bool {
+        self.identities.iter().any(|(n, _)| n.id == *id)
+    }
+
+    /// Registers every identity with `victim`, returns how many it took.
+    async fn flood(&self, simulation: &Simulation, victim: &Node) -> usize {
+        let mut accepted = 0;
+        for (node, private) in &self.identities {
+            let registration = seal(node.id, private, node.clone());
+            match registration {
+                Ok(registration) => match simulation.transport.register(victim, registration).await {
+                    Ok(_) => accepted += 1,
+                    Err(e) => info!("Victim turned away {:?}: {}", node.id, e),
+                },
+                Err(e) => info!("Couldn't sign registration of {:?}: {}", node.id, e),
+            }
+        }
+        accepted
+    }
+}
+
+/// Blocks on top of `tip` which nobody else builds on.
+fn stale_branch(tip: &Block, miner: NodeId, length: usize) -> Result<Vec<Block>> {
+    let mut branch: Vec<Block> = vec![];
+    for _ in 0..length {
+        let transactions = vec![create_mining_reward(miner)];
+        let draft = Draft::on(branch.last().unwrap_or(tip));
+        let (hash, nonce) = try_mine_any(&draft, SIMULATION_DIFFICULTY, &transactions)?;
+        let included: Vec<_> = transactions.iter().collect();
+        branch.push(create_mined_block(draft, hash, nonce, &included, miner));
+    }
+    Ok(branch)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EclipseReport {
+    pub policy: PeerPolicy,
+    pub sybils: usize,
+    /// Registrations the victim took.
+    pub accepted: usize,
+    pub victim_peers: usize,
+    pub victim_honest_peers: usize,
+    pub victim_sybil_share: f64,
+    /// Share of the attacker's identities in the tables of the other honest nodes,
+    /// the victim tells them about every node it takes.
+    pub mean_sybil_share: f64,
+    /// Different tips the other honest nodes ended with, more than one if
+    /// the flood cut them off from each other too.
+    pub honest_tips: usize,
+    /// Blocks the honest nodes mined after the flood which the victim doesn't have.
+    pub missed_blocks: usize,
+    /// The victim follows the attacker's branch instead of the honest chain.
+    pub fed_stale_chain: bool,
+}
+
+fn sybil_share(peers: &[&Node], sybils: &Sybils) -> f64 {
+    let count = peers.iter().filter(|n| sybils.is_sybil(&n.id)).count();
+    count as f64 / peers.len().max(1) as f64
+}
+
+/// `honest` nodes join, the last of them is the victim. The attacker mines a short
+/// branch on the victim's tip, registers `sybils` identities with the victim,
+/// the others mine `honest_blocks` and then the attacker sends its branch to
+/// the victim through one of its identities the victim still knows.
+pub async fn eclipse(honest: u16, sybils: u16, honest_blocks: usize, policy: PeerPolicy) -> Result<EclipseReport> {
+    let params = ChainParams {
+        mining_difficulty: SIMULATION_DIFFICULTY,
+        signature_algorithm: SignatureAlgorithm::Ed25519,
+        ..ChainParams::default()
+    };
+    let mut simulation = Simulation::new(params);
+    simulation.set_peer_policy(policy);
+    let mut nodes = vec![];
+    for _ in 0..honest.max(2) {
+        nodes.push(simulation.join().await?);
+    }
+    let victim = nodes.pop().unwrap();
+    simulation.mine(&nodes[0]).await?;
+    let attacker = Sybils::new(sybils)?;
+    let (victim_node, tip) = {
+        let network = simulation.network(&victim)?.lock().await;
+        (network.user.node.clone(), network.blockchain.last_block().clone())
+    };
+    let (first_sybil, _) = attacker.identities.first().ok_or(anyhow!("Attacker has no identities"))?;
+    let branch = stale_branch(&tip, first_sybil.id, STALE_BLOCKS)?;
+
+    let accepted = attacker.flood(&simulation, &victim_node).await;
+    let mut mined = vec![];
+    for height in 0..honest_blocks {
+        let miner = &nodes[height % nodes.len()];
+        simulation.mine(miner).await?;
+        let network = simulation.network(miner)?.lock().await;
+        mined.push((network.blockchain.height(), network.blockchain.last_block().header.hash.clone()));
+    }
+    let known = {
+        let network = simulation.network(&victim)?.lock().await;
+        attacker.identities.iter().find(|(n, _)| network.nodes.iter().any(|p| p.id == n.id))
+    };
+    if let Some((sender, private)) = known {
+        for block in branch.iter().cloned() {
+            let announcement = seal(sender.id, private, block)?;
+            simulation.transport.broadcast_block(std::slice::from_ref(&victim_node), &announcement).await?;
+        }
+    }
+
+    let mut tips = vec![];
+    let mut shares = vec![];
+    for id in &nodes {
+        let network = simulation.network(id)?.lock().await;
+        tips.push(network.blockchain.last_block().header.hash.0.clone());
+        shares.push(sybil_share(&network.other_nodes().collect::<Vec<_>>(), &attacker));
+    }
+    tips.sort();
+    tips.dedup();
+    let network = simulation.network(&victim)?.lock().await;
+    let peers: Vec<_> = network.other_nodes().collect();
+    let missed = mined
+        .iter()
+        .filter(|(height, hash)| network.blockchain.0.get(*height).is_none_or(|b| b.header.hash != *hash))
+        .count();
+    Ok(EclipseReport {
+        policy,
+        sybils: sybils.into(),
+        accepted,
+        victim_peers: peers.len(),
+        victim_honest_peers: peers.iter().filter(|n| !attacker.is_sybil(&n.id)).count(),
+        victim_sybil_share: sybil_share(&peers, &attacker),
+        mean_sybil_share: shares.iter().sum::<f64>() / shares.len().max(1) as f64,
+        honest_tips: tips.len(),
+        missed_blocks: missed,
+        fed_stale_chain: branch
+            .last()
+            .is_some_and(|b| network.blockchain.last_block().header.hash == b.header.hash),
+    })
+}
+
+/// Runs the same eclipse against nodes without a peer limit and with each
+/// way of evicting peers from a table of `max_peers`.
+pub async fn eclipse_defenses(honest: u16, sybils: u16, max_peers: usize) -> Result<Vec<EclipseReport>> {
+    let limited = |eviction| PeerPolicy { max_peers: Some(max_peers), eviction };
+    let policies = [
+        PeerPolicy::default(),
+        limited(Eviction::Oldest),
+        limited(Eviction::Newcomer),
+        limited(Eviction::CrowdedGroup),
+    ];
+    let mut reports = vec![];
+    for policy in policies {
+        reports.push(eclipse(honest, sybils, STALE_BLOCKS + 3, policy).await?);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sybils_eclipse_only_evicting_nodes() {
+        let reports = eclipse_defenses(6, 30, 8).await.unwrap();
+
+        let [open, oldest, newcomer, crowded] = &reports[..] else {
+            panic!("{:?}", reports)
+        };
+        assert_eq!(open.accepted, 30);
+        assert_eq!(open.victim_honest_peers, 5);
+        assert!(!open.fed_stale_chain && open.missed_blocks == 0, "{:?}", open);
+        assert_eq!(oldest.victim_honest_peers, 0);
+        assert!(oldest.fed_stale_chain && oldest.missed_blocks == 5, "{:?}", oldest);
+        assert_eq!((newcomer.accepted, newcomer.victim_honest_peers), (3, 5));
+        assert!(!newcomer.fed_stale_chain && newcomer.missed_blocks == 0, "{:?}", newcomer);
+        assert_eq!(crowded.victim_honest_peers, 5);
+        assert!(!crowded.fed_stale_chain && crowded.missed_blocks == 0, "{:?}", crowded);
+        assert_eq!(crowded.honest_tips, 1);
+    }
+}
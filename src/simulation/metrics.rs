@@ -0,0 +1,263 @@
+use std::{fmt::Write, fs, path::PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use super::scheduler::VirtualTime;
+
+/// What to collect while a simulation runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsConfig {
+    /// Nodes and the network are sampled this often.
+    pub interval: VirtualTime,
+    /// Where the CSV and JSON files go, nothing is written without it.
+    pub directory: Option<PathBuf>,
+    /// Files are written again this often while the run goes on, else only at its end.
+    pub flush_every: Option<VirtualTime>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    pub fn add(&mut self, bytes: u64) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeSample {
+    pub at: VirtualTime,
+    pub node: usize,
+    pub online: bool,
+    pub height: usize,
+    /// Share of the hash power of the nodes online, 0 while offline.
+    pub hash_share: f64,
+    pub blocks_mined: usize,
+    pub sent: Traffic,
+    pub received: Traffic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetworkSample {
+    pub at: VirtualTime,
+    pub height: usize,
+    pub blocks_mined: usize,
+    /// Mined blocks which aren't on the longest chain of the nodes online.
+    pub orphans: usize,
+    pub orphan_rate: f64,
+    pub traffic: Traffic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainEventKind {
+    /// A node mined on a block which already had a child.
+    Fork,
+    /// A node switched to a chain which doesn't extend its tip.
+    Reorg,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainEvent {
+    pub at: VirtualTime,
+    pub node: usize,
+    pub kind: ChainEventKind,
+    /// Height of the block mined or switched to.
+    pub height: usize,
+    /// Blocks the node gave up, 0 for forks.
+    pub depth: usize,
+}
+
+/// State of a real node after a block of the main chain.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EconomySample {
+    pub at: VirtualTime,
+    pub height: usize,
+    pub node: usize,
+    /// Transactions waiting in the node's poll.
+    pub mempool: usize,
+    pub balance: f32,
+    /// Fees of the node's confirmed payments so far.
+    pub fees_paid: f32,
+}
+
+/// Time series of one run, written as CSV tables and one JSON file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Metrics {
+    pub interval: VirtualTime,
+    pub nodes: Vec<NodeSample>,
+    pub network: Vec<NetworkSample>,
+    pub chain_events: Vec<ChainEvent>,
+    /// Sampled after every main chain block when agents trade.
+    pub economy: Vec<EconomySample>,
+    #[serde(skip)]
+    next_sample: VirtualTime,
+    #[serde(skip)]
+    config: MetricsConfig,
+    #[serde(skip)]
+    flushed_at: VirtualTime,
+}
+
+impl Metrics {
+    pub fn new(config: MetricsConfig) -> Self {
+        Self {
+            interval: config.interval,
+            nodes: vec![],
+            network: vec![],
+            chain_events: vec![],
+            economy: vec![],
+            next_sample: VirtualTime(0),
+            config,
+            flushed_at: VirtualTime(0),
+        }
+    }
+
+    /// Time of the next sample if it is due by `now`.
+    pub fn due(&self, now: VirtualTime) -> Option<VirtualTime> {
+        (self.next_sample <= now).then_some(self.next_sample)
+    }
+
+    pub fn event(&mut self, at: VirtualTime, node: usize, kind: ChainEventKind, height: usize, depth: usize) {
+        self.chain_events.push(ChainEvent { at, node, kind, height, depth });
+    }
+
+    /// Takes the samples of one moment, the next one is due an interval later.
+    pub fn record(&mut self, nodes: Vec<NodeSample>, network: NetworkSample) -> Result<()> {
+        let at = network.at;
+        self.nodes.extend(nodes);
+        self.network.push(network);
+        self.next_sample = at + VirtualTime(self.interval.0.max(1));
+        self.flush_if_due(at)
+    }
+
+    pub fn record_economy(&mut self, samples: Vec<EconomySample>) -> Result<()> {
+        let at = samples.first().map(|s| s.at);
+        self.economy.extend(samples);
+        at.map_or(Ok(()), |at| self.flush_if_due(at))
+    }
+
+    fn flush_if_due(&mut self, at: VirtualTime) -> Result<()> {
+        match self.config.flush_every {
+            Some(every) if at >= self.flushed_at + every => {
+                self.flushed_at = at;
+                self.write()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes every table to the configured directory, if there is one.
+    pub fn write(&self) -> Result<()> {
+        let Some(directory) = &self.config.directory else {
+            return Ok(());
+        };
+        fs::create_dir_all(directory)?;
+        fs::write(directory.join("nodes.csv"), self.nodes_csv())?;
+        fs::write(directory.join("network.csv"), self.network_csv())?;
+        fs::write(directory.join("chain_events.csv"), self.chain_events_csv())?;
+        fs::write(directory.join("economy.csv"), self.economy_csv())?;
+        fs::write(directory.join("metrics.json"), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn nodes_csv(&self) -> String {
+        let mut csv = String::from(
+            "at_ms,node,online,height,hash_share,blocks_mined,messages_sent,bytes_sent,messages_received,bytes_received\n",
+        );
+        for s in &self.nodes {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{}",
+                s.at.0,
+                s.node,
+                s.online,
+                s.height,
+                s.hash_share,
+                s.blocks_mined,
+                s.sent.messages,
+                s.sent.bytes,
+                s.received.messages,
+                s.received.bytes
+            );
+        }
+        csv
+    }
+
+    pub fn network_csv(&self) -> String {
+        let mut csv = String::from("at_ms,height,blocks_mined,orphans,orphan_rate,messages,bytes\n");
+        for s in &self.network {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{}",
+                s.at.0, s.height, s.blocks_mined, s.orphans, s.orphan_rate, s.traffic.messages, s.traffic.bytes
+            );
+        }
+        csv
+    }
+
+    pub fn chain_events_csv(&self) -> String {
+        let mut csv = String::from("at_ms,node,kind,height,depth\n");
+        for e in &self.chain_events {
+            let kind = match e.kind {
+                ChainEventKind::Fork => "fork",
+                ChainEventKind::Reorg => "reorg",
+            };
+            let _ = writeln!(csv, "{},{},{},{},{}", e.at.0, e.node, kind, e.height, e.depth);
+        }
+        csv
+    }
+
+    pub fn economy_csv(&self) -> String {
+        let mut csv = String::from("at_ms,height,node,mempool,balance,fees_paid\n");
+        for s in &self.economy {
+            let _ = writeln!(csv, "{},{},{},{},{},{}", s.at.0, s.height, s.node, s.mempool, s.balance, s.fees_paid);
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            conditions::Partition,
+            mining::{simulate_mining, MiningConfig},
+        },
+        *,
+    };
+
+    #[test]
+    fn samples_match_the_report() {
+        let hour = VirtualTime::from_secs(3600);
+        let directory = std::env::temp_dir().join(format!("nocoin-metrics-{}", std::process::id()));
+        let mut config = MiningConfig::uniform(3, 10, 300);
+        config.network.partitions.push(Partition { at: hour, heal_at: hour + hour, groups: vec![(0..5).collect()] });
+        config.metrics = Some(MetricsConfig {
+            interval: VirtualTime::from_secs(600),
+            directory: Some(directory.clone()),
+            flush_every: None,
+        });
+
+        let report = simulate_mining(&config).unwrap();
+
+        let metrics = report.metrics.unwrap();
+        assert_eq!(metrics.nodes.len(), 10 * metrics.network.len());
+        assert!(metrics.network.len() as u64 > report.duration.0 / 600_000);
+        let last = metrics.network.last().unwrap();
+        assert_eq!((last.height, last.orphans), (report.height, report.stale_blocks));
+        let reorgs = metrics.chain_events.iter().filter(|e| e.kind == ChainEventKind::Reorg).count();
+        assert_eq!(reorgs, report.reorgs);
+        assert!(metrics.chain_events.iter().any(|e| e.kind == ChainEventKind::Fork));
+        assert!(last.traffic.messages > 0 && last.traffic.bytes == last.traffic.messages * config.block_size);
+        metrics.write().unwrap();
+        let csv = fs::read_to_string(directory.join("nodes.csv")).unwrap();
+        assert_eq!(csv.lines().count(), metrics.nodes.len() + 1);
+        assert!(directory.join("metrics.json").exists());
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
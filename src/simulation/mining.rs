@@ -0,0 +1,560 @@
+use anyhow::{anyhow, bail, Result};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    conditions::{NetworkConditions, Topology, Wires},
+    metrics::{ChainEventKind, Metrics, MetricsConfig, NetworkSample, NodeSample, Traffic},
+    scheduler::{exponential, Scheduler, VirtualTime},
+    selfish::{Reaction, Withholding},
+};
+
+/// Mean time between blocks of the whole network, like the pause of `mine_from_time_to_time`.
+pub const BLOCK_INTERVAL: VirtualTime = VirtualTime::from_secs(60);
+pub const PROPAGATION_DELAY: VirtualTime = VirtualTime(500);
+/// Roughly a serialized block full of payments, only matters for links with a bandwidth.
+pub const BLOCK_SIZE: u64 = 4_000;
+
+#[derive(Debug, Clone)]
+pub struct MiningConfig {
+    pub seed: u64,
+    /// Share of every node, they don't have to add up to anything.
+    pub hash_power: Vec<f64>,
+    pub block_interval: VirtualTime,
+    pub network: NetworkConditions,
+    pub block_size: u64,
+    /// Nodes which join are offline until then, nodes which leave stop mining and listening.
+    pub membership: Vec<(VirtualTime, Membership)>,
+    /// Mining stops once a block this high is found, blocks on their way are still delivered.
+    pub target_height: usize,
+    /// Mining also stops at this time, if the chain didn't get high enough.
+    pub max_duration: Option<VirtualTime>,
+    /// Nodes which don't mine honestly, by node.
+    pub strategies: Vec<(usize, MinerStrategy)>,
+    /// Time series to collect, nothing is collected without it.
+    pub metrics: Option<MetricsConfig>,
+}
+
+/// How a dishonest node mines, honest nodes publish every block they find.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MinerStrategy {
+    /// Withholds its blocks and publishes them when the others catch up.
+    Selfish,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Membership {
+    Join(usize),
+    Leave(usize),
+}
+
+impl MiningConfig {
+    pub fn uniform(seed: u64, nodes: usize, target_height: usize) -> Self {
+        Self {
+            seed,
+            hash_power: vec![1.; nodes],
+            block_interval: BLOCK_INTERVAL,
+            network: NetworkConditions::constant(PROPAGATION_DELAY),
+            block_size: BLOCK_SIZE,
+            membership: vec![],
+            target_height,
+            max_duration: None,
+            strategies: vec![],
+            metrics: None,
+        }
+    }
+}
+
+/// Block of the model, it only remembers what decides which chain wins.
+#[derive(Debug, Clone)]
+pub(super) struct ModelBlock {
+    pub(super) parent: usize,
+    pub(super) height: usize,
+    pub(super) miner: usize,
+    pub(super) mined_at: VirtualTime,
+}
+
+/// Block of `block`'s chain at `height`, which mustn't be above `block`.
+pub(super) fn ancestor(blocks: &[ModelBlock], mut block: usize, height: usize) -> usize {
+    while blocks[block].height > height {
+        block = blocks[block].parent;
+    }
+    block
+}
+
+enum MiningEvent {
+    /// Node's Poisson clock ticked, it found a block on its current tip.
+    Mine(usize),
+    Deliver { from: usize, node: usize, block: usize },
+    Split(usize),
+    Heal(usize),
+    Change(Membership),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MiningReport {
+    pub height: usize,
+    pub blocks_mined: usize,
+    /// Mined blocks which didn't end up in the main chain.
+    pub stale_blocks: usize,
+    pub stale_rate: f64,
+    /// Times a node switched to a chain which doesn't extend its tip.
+    pub reorgs: usize,
+    /// Most blocks a node had to give up in one switch.
+    pub max_reorg_depth: usize,
+    /// How long after each partition healed all nodes had the same tip again,
+    /// in the order of `partitions`. `None` if they never did.
+    pub convergence_after_heal: Vec<Option<VirtualTime>>,
+    /// Main chain blocks of each node, in the order of `hash_power`.
+    pub main_chain_blocks: Vec<usize>,
+    pub converged: bool,
+    pub duration: VirtualTime,
+    /// Hash of who mined each main chain block and when, equal for runs which replay exactly.
+    pub fingerprint: String,
+    /// Miner of every main chain block after genesis and when it found it.
+    #[serde(skip)]
+    pub main_chain: Vec<(usize, VirtualTime)>,
+    #[serde(skip)]
+    pub metrics: Option<Metrics>,
+}
+
+/// What every node did so far, for the metrics.
+#[derive(Debug, Clone, Copy, Default)]
+struct NodeActivity {
+    mined: usize,
+    sent: Traffic,
+    received: Traffic,
+}
+
+/// Samples every node and the network as they are at `at`.
+fn sample(
+    metrics: &mut Metrics,
+    at: VirtualTime,
+    config: &MiningConfig,
+    blocks: &[ModelBlock],
+    tips: &[usize],
+    online: &[bool],
+    activity: &[NodeActivity],
+) -> Result<()> {
+    let online_power: f64 = config.hash_power.iter().zip(online).filter(|(_, o)| **o).map(|(p, _)| p).sum();
+    let nodes = (0..tips.len())
+        .map(|node| NodeSample {
+            at,
+            node,
+            online: online[node],
+            height: blocks[tips[node]].height,
+            hash_share: if online[node] && online_power > 0. {
+                config.hash_power[node] / online_power
+            } else {
+                0.
+            },
+            blocks_mined: activity[node].mined,
+            sent: activity[node].sent,
+            received: activity[node].received,
+        })
+        .collect();
+    // Every height below the best tip has exactly one main chain block
+    let height = tips.iter().zip(online).filter(|(_, o)| **o).map(|(t, _)| blocks[*t].height).max().unwrap_or(0);
+    let blocks_mined = blocks.len() - 1;
+    let mut traffic = Traffic::default();
+    for a in activity {
+        traffic.messages += a.sent.messages;
+        traffic.bytes += a.sent.bytes;
+    }
+    let network = NetworkSample {
+        at,
+        height,
+        blocks_mined,
+        orphans: blocks_mined.saturating_sub(height),
+        orphan_rate: blocks_mined.saturating_sub(height) as f64 / blocks_mined.max(1) as f64,
+        traffic,
+    };
+    metrics.record(nodes, network)
+}
+
+/// Blocks `tip` gives up when its node switches to `new_tip`, 0 if `new_tip` extends it.
+fn reorg_depth(blocks: &[ModelBlock], tip: usize, new_tip: usize) -> usize {
+    let (mut old, mut new) = (tip, ancestor(blocks, new_tip, blocks[tip].height));
+    while old != new {
+        old = blocks[old].parent;
+        new = blocks[new].parent;
+    }
+    blocks[tip].height - blocks[old].height
+}
+
+/// One run of `simulate_mining`, every kind of event has its own method.
+struct MiningSimulator<'a> {
+    config: &'a MiningConfig,
+    rng: ChaCha8Rng,
+    scheduler: Scheduler<MiningEvent>,
+    /// Mean time between blocks of every node.
+    mean_times: Vec<f64>,
+    neighbours: Vec<Vec<usize>>,
+    relays: bool,
+    wires: Wires,
+    blocks: Vec<ModelBlock>,
+    /// Children of every block, the second one is a fork.
+    children: Vec<usize>,
+    tips: Vec<usize>,
+    online: Vec<bool>,
+    /// Which of the partitions are in effect.
+    split: Vec<bool>,
+    healed: Vec<(usize, VirtualTime)>,
+    convergence_after_heal: Vec<Option<VirtualTime>>,
+    reorgs: usize,
+    max_reorg_depth: usize,
+    withholding: Vec<Option<Withholding>>,
+    metrics: Option<Metrics>,
+    activity: Vec<NodeActivity>,
+    mining: bool,
+    /// Node sending a block to its neighbours but the one in the middle,
+    /// collected while handling an event.
+    sends: Vec<(usize, usize, usize)>,
+}
+
+impl<'a> MiningSimulator<'a> {
+    fn new(config: &'a MiningConfig) -> Result<Self> {
+        let total: f64 = config.hash_power.iter().sum();
+        if config.hash_power.is_empty() || total <= 0. || config.hash_power.iter().any(|p| *p < 0.) {
+            bail!("Nodes need non-negative hash power adding up to more than zero")
+        }
+        let nodes = config.hash_power.len();
+        if config.membership.iter().any(|(_, Membership::Join(n) | Membership::Leave(n))| *n >= nodes) {
+            bail!("Only {} nodes can join or leave", nodes)
+        }
+        let genesis = ModelBlock { parent: 0, height: 0, miner: 0, mined_at: VirtualTime(0) };
+        let mut simulator = Self {
+            config,
+            rng: ChaCha8Rng::seed_from_u64(config.seed),
+            scheduler: Scheduler::default(),
+            mean_times: config.hash_power.iter().map(|p| config.block_interval.0 as f64 * total / p).collect(),
+            neighbours: (0..nodes).map(|n| config.network.topology.neighbours(n, nodes)).collect(),
+            relays: config.network.topology != Topology::FullMesh,
+            wires: Wires::default(),
+            blocks: vec![genesis],
+            children: vec![0],
+            tips: vec![0; nodes],
+            online: vec![true; nodes],
+            split: vec![false; config.network.partitions.len()],
+            healed: vec![],
+            convergence_after_heal: vec![None; config.network.partitions.len()],
+            reorgs: 0,
+            max_reorg_depth: 0,
+            withholding: vec![None; nodes],
+            metrics: config.metrics.clone().map(Metrics::new),
+            activity: vec![NodeActivity::default(); nodes],
+            mining: config.target_height > 0,
+            sends: vec![],
+        };
+        for (at, change) in &config.membership {
+            if let Membership::Join(node) = change {
+                simulator.online[*node] = false;
+            }
+            simulator.scheduler.schedule_in(*at, MiningEvent::Change(*change));
+        }
+        for node in 0..nodes {
+            let mean = simulator.mean_times[node];
+            if mean.is_finite() {
+                let next = exponential(&mut simulator.rng, mean);
+                simulator.scheduler.schedule_in(next, MiningEvent::Mine(node));
+            }
+        }
+        for (i, partition) in config.network.partitions.iter().enumerate() {
+            simulator.scheduler.schedule_in(partition.at, MiningEvent::Split(i));
+            simulator.scheduler.schedule_in(partition.heal_at, MiningEvent::Heal(i));
+        }
+        for (node, strategy) in &config.strategies {
+            let withholding = simulator
+                .withholding
+                .get_mut(*node)
+                .ok_or(anyhow!("No node {} to mine selfishly", node))?;
+            match strategy {
+                MinerStrategy::Selfish => *withholding = Some(Withholding::new(0)),
+            }
+        }
+        Ok(simulator)
+    }
+
+    fn run(mut self) -> Result<MiningReport> {
+        while let Some((now, event)) = self.scheduler.next() {
+            self.sample_until(now)?;
+            let was_mining = self.mining;
+            self.mining &= self.config.max_duration.is_none_or(|max| now < max);
+            match event {
+                MiningEvent::Mine(node) => self.mine(now, node),
+                MiningEvent::Deliver { from, node, block } => self.deliver(now, from, node, block),
+                MiningEvent::Split(i) => self.split[i] = true,
+                MiningEvent::Heal(i) => {
+                    self.split[i] = false;
+                    self.healed.push((i, now));
+                }
+                MiningEvent::Change(Membership::Join(node)) => self.join(node),
+                MiningEvent::Change(Membership::Leave(node)) => self.online[node] = false,
+            }
+            if was_mining && !self.mining {
+                self.publish_withheld();
+            }
+            self.send(now);
+            self.check_convergence(now);
+        }
+        self.report()
+    }
+
+    fn separated(&self, from: usize, to: usize) -> bool {
+        self.config
+            .network
+            .partitions
+            .iter()
+            .zip(&self.split)
+            .any(|(p, active)| *active && p.separates(from, to))
+    }
+
+    fn sample_until(&mut self, now: VirtualTime) -> Result<()> {
+        let Some(metrics) = self.metrics.as_mut() else {
+            return Ok(());
+        };
+        while let Some(at) = metrics.due(now) {
+            sample(metrics, at, self.config, &self.blocks, &self.tips, &self.online, &self.activity)?;
+        }
+        Ok(())
+    }
+
+    /// Offline nodes only wait for their next turn.
+    fn mine(&mut self, now: VirtualTime, node: usize) {
+        if !self.mining {
+            return;
+        }
+        if self.online[node] {
+            let parent = self.tips[node];
+            let height = self.blocks[parent].height + 1;
+            self.blocks.push(ModelBlock { parent, height, miner: node, mined_at: now });
+            let block = self.blocks.len() - 1;
+            self.children[parent] += 1;
+            self.children.push(0);
+            self.activity[node].mined += 1;
+            if let Some(metrics) = self.metrics.as_mut().filter(|_| self.children[parent] > 1) {
+                metrics.event(now, node, ChainEventKind::Fork, height, 0);
+            }
+            self.tips[node] = block;
+            match self.withholding[node].as_mut() {
+                Some(selfish) => self.sends.extend(selfish.mined(&self.blocks, block).map(|b| (node, node, b))),
+                None => self.sends.push((node, node, block)),
+            }
+        }
+        let next = exponential(&mut self.rng, self.mean_times[node]);
+        self.scheduler.schedule_in(next, MiningEvent::Mine(node));
+        if self.online[node] {
+            self.mining = self.blocks[self.tips[node]].height < self.config.target_height;
+        }
+    }
+
+    fn deliver(&mut self, now: VirtualTime, from: usize, node: usize, block: usize) {
+        if !self.online[node] || self.separated(from, node) {
+            return;
+        }
+        self.activity[node].received.add(self.config.block_size);
+        let adopt = match self.withholding[node].as_mut() {
+            Some(selfish) => match selfish.heard(&self.blocks, self.tips[node], block) {
+                Reaction::Adopt => true,
+                Reaction::Publish(shown) => {
+                    self.sends.push((node, node, shown));
+                    false
+                }
+                Reaction::Ignore => false,
+            },
+            None => self.blocks[block].height > self.blocks[self.tips[node]].height,
+        };
+        if !adopt {
+            return;
+        }
+        let depth = reorg_depth(&self.blocks, self.tips[node], block);
+        if depth > 0 {
+            self.reorgs += 1;
+            self.max_reorg_depth = self.max_reorg_depth.max(depth);
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.event(now, node, ChainEventKind::Reorg, self.blocks[block].height, depth);
+            }
+        }
+        self.tips[node] = block;
+        if self.relays && self.withholding[node].is_none() {
+            self.sends.push((node, from, block));
+        }
+    }
+
+    /// Syncs the longest chain of the neighbours it can reach.
+    fn join(&mut self, node: usize) {
+        let best = self.neighbours[node]
+            .iter()
+            .filter(|n| self.online[**n] && !self.separated(**n, node))
+            .map(|n| self.tips[*n])
+            .max_by_key(|t| (self.blocks[*t].height, std::cmp::Reverse(*t)));
+        self.tips[node] = best.unwrap_or(self.tips[node]);
+        self.online[node] = true;
+    }
+
+    /// Nothing is left to withhold for once mining stops.
+    fn publish_withheld(&mut self) {
+        for (node, selfish) in self.withholding.iter_mut().enumerate() {
+            if let Some(selfish) = selfish.as_mut().filter(|_| self.online[node]) {
+                self.sends.push((node, node, selfish.publish(self.tips[node])));
+            }
+        }
+    }
+
+    fn send(&mut self, now: VirtualTime) {
+        for (node, skipped, block) in std::mem::take(&mut self.sends) {
+            for other in self.neighbours[node].iter().copied() {
+                if other == skipped || self.separated(node, other) {
+                    continue;
+                }
+                self.activity[node].sent.add(self.config.block_size);
+                let link = (node, other);
+                let sent = self.wires.send(&self.config.network, &mut self.rng, now, link, self.config.block_size);
+                if let Some(delay) = sent {
+                    self.scheduler.schedule_in(delay, MiningEvent::Deliver { from: node, node: other, block });
+                }
+            }
+        }
+    }
+
+    fn check_convergence(&mut self, now: VirtualTime) {
+        let mut online_tips = self.tips.iter().zip(&self.online).filter(|(_, o)| **o).map(|(t, _)| *t);
+        let first = online_tips.next();
+        if !self.healed.is_empty() && online_tips.all(|t| Some(t) == first) {
+            for (i, at) in self.healed.drain(..) {
+                self.convergence_after_heal[i] = Some(VirtualTime(now.0 - at.0));
+            }
+        }
+    }
+
+    fn report(mut self) -> Result<MiningReport> {
+        let main_tip = self
+            .tips
+            .iter()
+            .zip(&self.online)
+            .filter(|(_, o)| **o)
+            .map(|(t, _)| *t)
+            .max_by_key(|t| (self.blocks[*t].height, std::cmp::Reverse(*t)))
+            .unwrap_or(0);
+        let mut main_chain_blocks = vec![0; self.tips.len()];
+        let mut sha256 = Sha256::new();
+        let mut main_chain = vec![];
+        let mut block = main_tip;
+        while block != 0 {
+            let b = &self.blocks[block];
+            main_chain.push((b.miner, b.mined_at));
+            main_chain_blocks[b.miner] += 1;
+            sha256.update((b.miner as u64).to_be_bytes());
+            sha256.update(b.mined_at.0.to_be_bytes());
+            block = b.parent;
+        }
+        let now = self.scheduler.now();
+        if let Some(metrics) = self.metrics.as_mut() {
+            sample(metrics, now, self.config, &self.blocks, &self.tips, &self.online, &self.activity)?;
+        }
+        let height = self.blocks[main_tip].height;
+        let blocks_mined = self.blocks.len() - 1;
+        Ok(MiningReport {
+            height,
+            blocks_mined,
+            stale_blocks: blocks_mined - height,
+            stale_rate: (blocks_mined - height) as f64 / blocks_mined.max(1) as f64,
+            reorgs: self.reorgs,
+            max_reorg_depth: self.max_reorg_depth,
+            convergence_after_heal: self.convergence_after_heal,
+            main_chain_blocks,
+            converged: self.tips.iter().zip(&self.online).all(|(t, o)| *t == main_tip || !o),
+            duration: now,
+            fingerprint: format!("{:x}", sha256.finalize()),
+            main_chain: main_chain.into_iter().rev().collect(),
+            metrics: self.metrics,
+        })
+    }
+}
+
+/// Mining without hashing. Every node finds blocks as a Poisson process with
+/// rate by its hash power, blocks reach the others over the links of `network`
+/// and nodes follow the longest chain they know, keeping the first one seen on ties.
+/// A block brings its ancestors along, as if the node synced them at once.
+/// Blocks don't cross partitions, not even the ones in flight when it splits.
+/// Offline nodes neither mine nor listen, the main chain is the longest one of online nodes.
+/// Selfish miners publish what they withheld once mining stops.
+/// With metrics, nodes and the network are sampled as they were just before each sample time.
+/// Only timing is modelled: there is no `Network`, no transaction and no proof of work,
+/// validating blocks is left to `Simulation`, which replays the main chain.
+pub fn simulate_mining(config: &MiningConfig) -> Result<MiningReport> {
+    MiningSimulator::new(config)?.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::conditions::Partition, *};
+
+    #[test]
+    fn same_seed_replays_exactly() {
+        let config = MiningConfig::uniform(42, 100, 10_000);
+
+        let report = simulate_mining(&config).unwrap();
+
+        assert_eq!(report.height, 10_000);
+        assert!(report.converged);
+        assert_eq!(report, simulate_mining(&config).unwrap());
+        let other = simulate_mining(&MiningConfig { seed: 43, ..config }).unwrap();
+        assert_ne!(report.fingerprint, other.fingerprint);
+    }
+
+    #[test]
+    fn blocks_follow_hash_power() {
+        let config = MiningConfig {
+            hash_power: vec![6., 3., 1.],
+            network: NetworkConditions::constant(VirtualTime(0)),
+            ..MiningConfig::uniform(7, 3, 5_000)
+        };
+
+        let report = simulate_mining(&config).unwrap();
+
+        assert_eq!(report.stale_blocks, 0);
+        let shares: Vec<_> = report.main_chain_blocks.iter().map(|b| *b as f64 / 5_000.).collect();
+        for (share, expected) in shares.iter().zip([0.6, 0.3, 0.1]) {
+            assert!((share - expected).abs() < 0.03, "Shares were {:?}", shares);
+        }
+        let mean_interval = report.duration.0 as f64 / 5_000.;
+        assert!((mean_interval / BLOCK_INTERVAL.0 as f64 - 1.).abs() < 0.05);
+    }
+
+    #[test]
+    fn slow_propagation_causes_forks() {
+        let fast = simulate_mining(&MiningConfig::uniform(1, 20, 2_000)).unwrap();
+        let slow = MiningConfig {
+            network: NetworkConditions::constant(VirtualTime::from_secs(30)),
+            ..MiningConfig::uniform(1, 20, 2_000)
+        };
+
+        let slow = simulate_mining(&slow).unwrap();
+
+        assert!(slow.stale_blocks > 10 * fast.stale_blocks.max(1));
+        assert!(slow.converged);
+    }
+
+    #[test]
+    fn healed_partition_reorgs_the_shorter_side() {
+        let hour = |h: u64| VirtualTime::from_secs(h * 3600);
+        let mut config = MiningConfig::uniform(3, 10, 600);
+        config.network.partitions.push(Partition {
+            at: hour(1),
+            heal_at: hour(3),
+            groups: vec![(0..5).collect()],
+        });
+
+        let report = simulate_mining(&config).unwrap();
+
+        assert!(report.converged);
+        assert!(report.max_reorg_depth > 20, "Deepest reorg was {}", report.max_reorg_depth);
+        assert!(report.stale_blocks >= report.max_reorg_depth);
+        let convergence = report.convergence_after_heal[0].unwrap();
+        assert!(convergence < hour(1), "Converged after {:?}", convergence);
+    }
+}
@@ -0,0 +1,479 @@
+mod attack;
+mod conditions;
+mod eclipse;
+mod metrics;
+mod mining;
+mod scenario;
+mod scheduler;
+mod selfish;
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use tokio::sync::Mutex;
+
+use crate::{
+    AI::{trade, AgentSpec, Strategy},
+    domain::{
+        calculate_all_wallets, create_mined_block, create_mining_reward, generate_key,
+        mineable_transactions, seal, set_peer_policy, try_adopt_network, try_adopt_pending_transactions,
+        try_add_block, try_mine_any, try_send_payment, try_start_new_network, ChainParams,
+        Block, Draft, Network, NoCoin, Node, NodeId, PeerPolicy, SignatureAlgorithm, TransactionId,
+    },
+    price::Quote,
+    transport::{MemoryTransport, Transport},
+};
+
+pub use attack::double_spend_success;
+pub use eclipse::eclipse_defenses;
+pub use mining::{simulate_mining, MiningConfig};
+pub use scenario::run_scenario_file;
+pub use selfish::selfish_revenue;
+
+/// Simulated nodes never bind their ports, ports only give them ids.
+pub const FIRST_PORT: u16 = 20_000;
+/// Blocks of simulations only have to be valid, not expensive.
+const SIMULATION_DIFFICULTY: u8 = 2;
+
+/// Step of a simulation script, applied before the block at its height is mined.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Join,
+    Leave(NodeId),
+    Pay {
+        from: NodeId,
+        to: NodeId,
+        ammount: NoCoin,
+        fee: NoCoin,
+    },
+}
+
+/// What every node agreed on at the end of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    pub height: usize,
+    pub tip: String,
+    pub balances: HashMap<NodeId, NoCoin>,
+}
+
+/// Nodes of one process, each with its own `Network`, talking over a `MemoryTransport`.
+/// Blocks are mined one at a time by the nodes in turn, so there are no forks.
+/// Agents of the nodes trade before every block.
+/// It runs outside the `Scheduler`: its blocks carry real proof of work at
+/// `SIMULATION_DIFFICULTY`, and their timing comes from `simulate_mining`.
+pub struct Simulation {
+    params: ChainParams,
+    peers: PeerPolicy,
+    transport: MemoryTransport,
+    nodes: Vec<(NodeId, Arc<Mutex<Network>>)>,
+    joined: u16,
+    agents: Vec<(NodeId, Vec<Box<dyn Strategy>>)>,
+    merchants: Vec<NodeId>,
+    quote: Option<Quote>,
+    rng: ChaCha8Rng,
+}
+
+impl Simulation {
+    pub fn new(params: ChainParams) -> Self {
+        Self {
+            params,
+            peers: PeerPolicy::default(),
+            transport: MemoryTransport::default(),
+            nodes: vec![],
+            joined: 0,
+            agents: vec![],
+            merchants: vec![],
+            quote: None,
+            rng: ChaCha8Rng::seed_from_u64(0),
+        }
+    }
+
+    /// Nodes joining from now on keep their peers this way.
+    pub fn set_peer_policy(&mut self, policy: PeerPolicy) {
+        self.peers = policy;
+    }
+
+    /// Id the `index`-th node to join gets, counting from 0.
+    pub fn node_id(index: u16) -> NodeId {
+        NodeId((FIRST_PORT + index).into())
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.nodes.iter().map(|(id, _)| id)
+    }
+
+    fn network(&self, id: &NodeId) -> Result<&Arc<Mutex<Network>>> {
+        self.nodes
+            .iter()
+            .find(|(n, _)| n == id)
+            .map(|(_, network)| network)
+            .ok_or(anyhow!("Node {:?} is not in the simulation", id))
+    }
+
+    /// First node starts the network, later ones register with the oldest node
+    /// and download its chain like `AI::start` does.
+    pub async fn join(&mut self) -> Result<NodeId> {
+        // Each of the first 256 nodes seems to come from its own address group
+        let ip = Ipv4Addr::new(10, self.joined as u8, 0, 1);
+        let addr: SocketAddr = SocketAddrV4::new(ip, FIRST_PORT + self.joined).into();
+        let (private, public) = generate_key(self.params.signature_algorithm)?;
+        let mut network = match self.nodes.first() {
+            None => try_start_new_network(addr, private, public)?,
+            Some((_, bootstrap)) => {
+                let bootstrap = bootstrap.lock().await.user.node.clone();
+                let own_node = Node { id: NodeId(addr.port().into()), addr, pub_key: public.clone() };
+                let registration = seal(own_node.id, &private, own_node)?;
+                let nodes = self.transport.register(&bootstrap, registration).await?;
+                let chain = self.transport.get_chain(&bootstrap).await?;
+                let mut network = try_adopt_network(addr, private, public, nodes, chain, self.params.clone())?;
+                let pending = self.transport.get_pending_transactions(&bootstrap).await?;
+                try_adopt_pending_transactions(&mut network, pending)?;
+                network
+            }
+        };
+        network.params = self.params.clone();
+        set_peer_policy(&mut network, self.peers);
+        let id = network.user.node.id;
+        let network = Arc::new(Mutex::new(network));
+        self.transport.attach(network.clone(), id);
+        self.nodes.push((id, network));
+        self.joined += 1;
+        info!("Simulated node {:?} joined", id);
+        Ok(id)
+    }
+
+    pub async fn leave(&mut self, id: &NodeId) -> Result<()> {
+        let network = self.network(id)?.lock().await;
+        let leaving = seal(*id, &network.user.priv_key, *id)?;
+        let others: Vec<_> = network.other_nodes().cloned().collect();
+        drop(network);
+        self.transport.announce_leave(&others, &leaving).await?;
+        self.transport.detach(id);
+        self.nodes.retain(|(n, _)| n != id);
+        info!("Simulated node {:?} left", id);
+        Ok(())
+    }
+
+    pub async fn pay(&self, from: &NodeId, to: NodeId, ammount: NoCoin, fee: NoCoin) -> Result<TransactionId> {
+        let mut network = self.network(from)?.lock().await;
+        let (id, signed) = try_send_payment(&mut network, to, ammount, fee, None)?;
+        let others: Vec<_> = network.other_nodes().cloned().collect();
+        drop(network);
+        self.transport.broadcast_transaction(&others, &signed).await?;
+        Ok(id)
+    }
+
+    /// Gives `node` more agents, the node is known as a merchant if any of them is one.
+    pub fn add_agents(&mut self, node: NodeId, agents: &[AgentSpec]) {
+        if agents.iter().any(AgentSpec::is_merchant) && !self.merchants.contains(&node) {
+            self.merchants.push(node);
+        }
+        let strategies = agents.iter().map(AgentSpec::strategy);
+        match self.agents.iter_mut().find(|(id, _)| *id == node) {
+            Some((_, existing)) => existing.extend(strategies),
+            None => self.agents.push((node, strategies.collect())),
+        }
+    }
+
+    /// Price agents see from now on.
+    pub fn set_quote(&mut self, quote: Quote) {
+        self.quote = Some(quote);
+    }
+
+    /// Agents of every node still in the simulation take their turn and their
+    /// payments are gossiped. Returns how many payments they made.
+    pub async fn trade(&mut self) -> Result<usize> {
+        let mut payments = 0;
+        for (id, strategies) in self.agents.iter_mut() {
+            let Some((_, network)) = self.nodes.iter().find(|(n, _)| n == id) else {
+                continue;
+            };
+            let mut network = network.lock().await;
+            let signed = trade(&mut network, strategies, &self.merchants, self.quote, &mut self.rng);
+            let others: Vec<_> = network.other_nodes().cloned().collect();
+            drop(network);
+            for payment in &signed {
+                self.transport.broadcast_transaction(&others, payment).await?;
+            }
+            payments += signed.len();
+        }
+        Ok(payments)
+    }
+
+    /// `miner` mines the next block with what it can take from its poll and gossips it.
+    pub async fn mine(&self, miner: &NodeId) -> Result<()> {
+        let block = self.mine_quietly(miner, *miner).await?;
+        self.announce(miner, block).await
+    }
+
+    /// `miner` mines the next block on its own chain and tells nobody, the
+    /// reward goes to `beneficiary`.
+    async fn mine_quietly(&self, miner: &NodeId, beneficiary: NodeId) -> Result<Block> {
+        let mut network = self.network(miner)?.lock().await;
+        let mut transactions = mineable_transactions(&network);
+        transactions.truncate(network.params.max_transaction_count - 1);
+        transactions.push(create_mining_reward(beneficiary));
+        let draft = Draft::on(network.blockchain.last_block());
+        let (hash, nonce) = try_mine_any(&draft, self.params.mining_difficulty, &transactions)?;
+        let included: Vec<_> = transactions.iter().collect();
+        let block = create_mined_block(draft, hash, nonce, &included, *miner);
+        try_add_block(&mut network, block.clone())?;
+        Ok(block)
+    }
+
+    async fn announce(&self, miner: &NodeId, block: Block) -> Result<()> {
+        let network = self.network(miner)?.lock().await;
+        let announcement = seal(*miner, &network.user.priv_key, block)?;
+        let others: Vec<_> = network.other_nodes().cloned().collect();
+        drop(network);
+        self.transport.broadcast_block(&others, &announcement).await?;
+        Ok(())
+    }
+
+    /// Others can't reach `id` until it shows up again, it still knows them.
+    fn hide(&self, id: &NodeId) {
+        self.transport.detach(id);
+    }
+
+    fn reveal(&self, id: &NodeId) -> Result<()> {
+        self.transport.attach(self.network(id)?.clone(), *id);
+        Ok(())
+    }
+
+    async fn apply(&mut self, event: &Event) -> Result<()> {
+        match event {
+            Event::Join => self.join().await.map(|_| ()),
+            Event::Leave(id) => self.leave(id).await,
+            Event::Pay { from, to, ammount, fee } => self.pay(from, *to, *ammount, *fee).await.map(|_| ()),
+        }
+    }
+
+    async fn height(&self) -> usize {
+        match self.nodes.first() {
+            Some((_, network)) => network.lock().await.blockchain.height(),
+            None => 0,
+        }
+    }
+
+    async fn height_of(&self, id: &NodeId) -> Result<usize> {
+        Ok(self.network(id)?.lock().await.blockchain.height())
+    }
+
+    /// Applies the script's events at each height and lets the nodes mine in
+    /// turn until the chain is `target` blocks high, then checks they agree.
+    pub async fn run_to_height(&mut self, target: usize, script: &[(usize, Event)]) -> Result<Consensus> {
+        loop {
+            let height = self.height().await;
+            for (_, event) in script.iter().filter(|(at, _)| *at == height) {
+                self.apply(event).await?;
+            }
+            if height >= target {
+                break;
+            }
+            if self.nodes.is_empty() {
+                bail!("No nodes left to mine block {}", height + 1)
+            }
+            self.trade().await?;
+            let miner = self.nodes[height % self.nodes.len()].0;
+            self.mine(&miner).await?;
+        }
+        self.converged().await
+    }
+
+    /// Every node has the same tip and sees the same balances.
+    pub async fn converged(&self) -> Result<Consensus> {
+        let mut consensus: Option<(NodeId, Consensus)> = None;
+        for (id, network) in &self.nodes {
+            let network = network.lock().await;
+            let seen = Consensus {
+                height: network.blockchain.height(),
+                tip: network.blockchain.last_block().header.hash.0.clone(),
+                balances: calculate_all_wallets(&network.blockchain),
+            };
+            match &consensus {
+                None => consensus = Some((*id, seen)),
+                Some((first, agreed)) if *agreed != seen => bail!(
+                    "Node {:?} is at height {} with tip {}, but node {:?} is at height {} with tip {}",
+                    id,
+                    seen.height,
+                    seen.tip,
+                    first,
+                    agreed.height,
+                    agreed.tip
+                ),
+                Some(_) => {}
+            }
+        }
+        consensus
+            .map(|(_, consensus)| consensus)
+            .ok_or(anyhow!("Simulation has no nodes"))
+    }
+}
+
+/// `nodes` join at once, each pays the next one every few blocks and the last
+/// node leaves halfway, until the chain is `height` high.
+pub async fn simulate(nodes: u16, height: usize) -> Result<()> {
+    let params = ChainParams {
+        mining_difficulty: SIMULATION_DIFFICULTY,
+        signature_algorithm: SignatureAlgorithm::Ed25519,
+        ..ChainParams::default()
+    };
+    let mut script: Vec<_> = (0..nodes).map(|_| (0, Event::Join)).collect();
+    for at in (nodes as usize..height).step_by(3) {
+        let payer = (at % nodes as usize) as u16;
+        script.push((
+            at,
+            Event::Pay {
+                from: Simulation::node_id(payer),
+                to: Simulation::node_id((payer + 1) % nodes),
+                ammount: NoCoin(1.),
+                fee: NoCoin(0.1),
+            },
+        ));
+    }
+    if nodes > 2 {
+        script.push((height / 2, Event::Leave(Simulation::node_id(nodes - 1))));
+        script.retain(|(at, event)| {
+            !matches!(event, Event::Pay { from, to, .. } if *at >= height / 2
+                && [from, to].contains(&&Simulation::node_id(nodes - 1)))
+        });
+    }
+    let mut simulation = Simulation::new(params);
+    let consensus = simulation.run_to_height(height, &script).await?;
+    info!(
+        "{} nodes agree on block {} with tip {}, balances: {:?}",
+        simulation.nodes().count(),
+        consensus.height,
+        consensus.tip,
+        consensus.balances
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        testing::token_contract, try_add_block, try_add_transaction, try_send_contract, ContractAction,
+    };
+
+    use super::*;
+
+    fn params() -> ChainParams {
+        ChainParams {
+            mining_difficulty: 1,
+            signature_algorithm: SignatureAlgorithm::Ed25519,
+            ..ChainParams::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_joining_and_leaving_converge() {
+        let [first, second, third, late] = [0, 1, 2, 3].map(Simulation::node_id);
+        let pay = |from, to, ammount| Event::Pay { from, to, ammount: NoCoin(ammount), fee: NoCoin(1.) };
+        let script = vec![
+            (0, Event::Join),
+            (0, Event::Join),
+            (0, Event::Join),
+            (2, pay(first, third, 4.)),
+            (3, Event::Join),
+            (4, pay(second, late, 2.)),
+            (5, Event::Leave(third)),
+            (6, pay(late, first, 1.)),
+        ];
+        let mut simulation = Simulation::new(params());
+
+        let consensus = simulation.run_to_height(9, &script).await.unwrap();
+
+        assert_eq!(consensus.height, 9);
+        assert_eq!(simulation.nodes().count(), 3);
+        assert_eq!(consensus.balances[&third], NoCoin(10. + 1. + 4.));
+        assert_eq!(consensus.balances.values().map(|c| c.0).sum::<f32>(), 9. * 10.);
+        let network = simulation.network(&late).unwrap().lock().await;
+        assert_eq!(network.nodes.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn diverged_node_is_reported() {
+        let mut simulation = Simulation::new(params());
+        let script = vec![(0, Event::Join), (0, Event::Join)];
+        simulation.run_to_height(2, &script).await.unwrap();
+
+        let second = Simulation::node_id(1);
+        let mut network = simulation.network(&second).unwrap().lock().await;
+        let transactions = vec![create_mining_reward(second)];
+        let draft = Draft::on(network.blockchain.last_block());
+        let (hash, nonce) = try_mine_any(&draft, SIMULATION_DIFFICULTY, &transactions).unwrap();
+        let included: Vec<_> = transactions.iter().collect();
+        let block: Block = create_mined_block(draft, hash, nonce, &included, second);
+        try_add_block(&mut network, block).unwrap();
+        drop(network);
+
+        assert!(simulation.converged().await.is_err());
+    }
+
+    /// Root of `id`'s contract ledger after catching up with its chain.
+    async fn synced_root(simulation: &Simulation, id: &NodeId) -> String {
+        let mut network = simulation.network(id).unwrap().lock().await;
+        let Network { blockchain, cache, .. } = &mut *network;
+        cache.contracts.sync(blockchain).unwrap();
+        cache.contracts.state_root().unwrap()
+    }
+
+    /// `from` sends the action and every other node takes it into its poll,
+    /// returns the transaction and the contract's address.
+    async fn submit(simulation: &Simulation, from: &NodeId, to: NodeId, action: ContractAction) -> (TransactionId, NodeId) {
+        let mut network = simulation.network(from).unwrap().lock().await;
+        let (id, signed) = try_send_contract(&mut network, to, action, NoCoin(0.), NoCoin(1.)).unwrap();
+        drop(network);
+        for other in simulation.nodes().filter(|id| *id != from) {
+            let mut network = simulation.network(other).unwrap().lock().await;
+            try_add_transaction(&mut network, signed.transaction.clone(), signed.proof.clone()).unwrap();
+        }
+        (id, signed.transaction.to)
+    }
+
+    #[tokio::test]
+    async fn nodes_executing_same_blocks_agree() {
+        let mut simulation = Simulation::new(params());
+        let (author, peer) = (simulation.join().await.unwrap(), simulation.join().await.unwrap());
+        simulation.mine(&author).await.unwrap();
+        let deploy = ContractAction::Deploy { code: token_contract() };
+        let (_, address) = submit(&simulation, &author, author, deploy).await;
+        simulation.mine(&peer).await.unwrap();
+        synced_root(&simulation, &peer).await;
+        let (mint, _) = submit(&simulation, &author, address, ContractAction::Call { input: vec![0, 100] }).await;
+        simulation.mine(&author).await.unwrap();
+        synced_root(&simulation, &peer).await;
+        let (transfer, _) = submit(&simulation, &author, address, ContractAction::Call { input: vec![1, 7, 30] }).await;
+        let (overdraft, _) = submit(&simulation, &author, address, ContractAction::Call { input: vec![1, 7, 500] }).await;
+        simulation.mine(&peer).await.unwrap();
+
+        let late = simulation.join().await.unwrap();
+        let roots = [
+            synced_root(&simulation, &author).await,
+            synced_root(&simulation, &peer).await,
+            synced_root(&simulation, &late).await,
+        ];
+
+        assert_eq!(roots[0], roots[1]);
+        assert_eq!(roots[0], roots[2]);
+        let network = simulation.network(&late).unwrap().lock().await;
+        let replayed = &network.cache.contracts;
+        assert_eq!(replayed.storage(&address, author.0 as i64), Some(70));
+        assert_eq!(replayed.storage(&address, 7), Some(30));
+        assert!(replayed.receipt(&mint).unwrap().success);
+        let transfer = replayed.receipt(&transfer).unwrap();
+        assert_eq!(transfer.logs, vec![vec![author.0 as i64, 7, 30]]);
+        let overdraft = replayed.receipt(&overdraft).unwrap();
+        assert!(!overdraft.success);
+        let incremental = simulation.network(&peer).unwrap().lock().await;
+        assert_eq!(incremental.cache.contracts.receipt(&overdraft.transaction), Some(overdraft));
+    }
+}
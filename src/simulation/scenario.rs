@@ -0,0 +1,596 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    domain::{calculate_wallet, ChainParams, SignatureAlgorithm},
+    price::{Model, NoCoinPrice, Peg, Quote, SyntheticFeed},
+    AI::AgentSpec,
+};
+
+use super::{
+    conditions::{Latency, Link, NetworkConditions, Partition, Topology},
+    metrics::{EconomySample, Metrics, MetricsConfig},
+    mining::{simulate_mining, Membership, MinerStrategy, MiningConfig, MiningReport, BLOCK_SIZE},
+    scheduler::VirtualTime,
+    Simulation, SIMULATION_DIFFICULTY,
+};
+
+/// One experiment, read from a TOML file. Nodes are numbered from 0 in every section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    #[serde(default)]
+    pub seed: u64,
+    pub nodes: NodesSpec,
+    #[serde(default)]
+    pub chain: ChainSpec,
+    #[serde(default)]
+    pub network: NetworkSpec,
+    #[serde(default)]
+    pub price: PriceSpec,
+    #[serde(default)]
+    pub agents: Vec<AgentsSpec>,
+    #[serde(default)]
+    pub events: Vec<TimedEvent>,
+    pub stop: StopSpec,
+    /// Where results go, relative to the scenario file.
+    pub output: Option<PathBuf>,
+    pub metrics: Option<MetricsSpec>,
+}
+
+/// Time series written as CSV and JSON next to the results.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsSpec {
+    pub interval_secs: u64,
+    /// Files are written again this often while the scenario runs, else only at its end.
+    pub flush_every_secs: Option<u64>,
+    /// Relative to the scenario file, next to the results if missing.
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodesSpec {
+    pub count: usize,
+    #[serde(default)]
+    pub hash_power: HashPowerSpec,
+    /// Nodes which don't mine honestly.
+    #[serde(default)]
+    pub miners: Vec<MinerSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MinerSpec {
+    pub node: usize,
+    pub strategy: MinerStrategy,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+pub enum HashPowerSpec {
+    #[default]
+    Uniform,
+    /// Node `i` has `1 / (i + 1)^exponent`, a few big miners and a long tail.
+    Zipf { exponent: f64 },
+    /// Share of every node, as many as there are nodes.
+    Shares { shares: Vec<f64> },
+}
+
+impl HashPowerSpec {
+    fn shares(&self, nodes: usize) -> Result<Vec<f64>> {
+        match self {
+            HashPowerSpec::Uniform => Ok(vec![1.; nodes]),
+            HashPowerSpec::Zipf { exponent } => Ok((0..nodes).map(|i| (i as f64 + 1.).powf(-exponent)).collect()),
+            HashPowerSpec::Shares { shares } if shares.len() == nodes => Ok(shares.clone()),
+            HashPowerSpec::Shares { shares } => bail!("{} shares given for {} nodes", shares.len(), nodes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainSpec {
+    pub block_interval_secs: u64,
+    pub block_size: u64,
+    /// Only matters for the economy, when there are agents.
+    pub max_transaction_count: usize,
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self {
+            block_interval_secs: 60,
+            block_size: BLOCK_SIZE,
+            max_transaction_count: ChainParams::default().max_transaction_count,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSpec {
+    pub topology: TopologySpec,
+    pub latency: LatencySpec,
+    pub loss: f64,
+    /// Bytes per second of every link.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for NetworkSpec {
+    fn default() -> Self {
+        Self {
+            topology: TopologySpec::FullMesh,
+            latency: LatencySpec::Fixed { ms: 500 },
+            loss: 0.,
+            bandwidth: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TopologySpec {
+    FullMesh,
+    Ring,
+    Random { degree: usize },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LatencySpec {
+    Fixed { ms: u64 },
+    Uniform { min_ms: u64, max_ms: u64 },
+    Exponential { min_ms: u64, mean_ms: u64 },
+}
+
+impl From<&LatencySpec> for Latency {
+    fn from(spec: &LatencySpec) -> Self {
+        match *spec {
+            LatencySpec::Fixed { ms } => Latency::Fixed(VirtualTime(ms)),
+            LatencySpec::Uniform { min_ms, max_ms } => Latency::Uniform {
+                min: VirtualTime(min_ms),
+                max: VirtualTime(max_ms),
+            },
+            LatencySpec::Exponential { min_ms, mean_ms } => Latency::Exponential {
+                min: VirtualTime(min_ms),
+                mean: VirtualTime(mean_ms),
+            },
+        }
+    }
+}
+
+/// Bitcoin's price while the scenario runs, stepping every `step_secs` of virtual time
+/// with `model`, or a geometric Brownian motion of `volatility` without one.
+/// NoCoin is worth `nocoin_ratio` of it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceSpec {
+    pub start_usd: f64,
+    pub volatility: f64,
+    pub model: Option<Model>,
+    pub step_secs: u64,
+    pub nocoin_ratio: f64,
+}
+
+impl Default for PriceSpec {
+    fn default() -> Self {
+        Self {
+            start_usd: 30_000.,
+            volatility: 0.02,
+            model: None,
+            step_secs: 60,
+            nocoin_ratio: 0.001,
+        }
+    }
+}
+
+/// Agent run by each of `nodes`, or by every node if there are none.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AgentsSpec {
+    pub nodes: Option<Vec<usize>>,
+    #[serde(flatten)]
+    pub agent: AgentSpec,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimedEvent {
+    pub at_secs: u64,
+    #[serde(flatten)]
+    pub event: ScenarioEvent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScenarioEvent {
+    /// Nodes are offline until they join.
+    Join { nodes: Vec<usize> },
+    Leave { nodes: Vec<usize> },
+    /// Groups can't reach each other until it heals, nodes of no group make one more group.
+    Partition { groups: Vec<Vec<usize>>, heal_after_secs: u64 },
+    /// Bitcoin's price is multiplied by `factor` from then on.
+    PriceShock { factor: f64 },
+}
+
+/// Mining stops at whichever comes first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StopSpec {
+    pub height: Option<usize>,
+    pub time_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PricePoint {
+    pub at: VirtualTime,
+    pub bitcoin_usd: f64,
+}
+
+/// Outcome of the agents' trading on the main chain.
+#[derive(Debug, Clone, Serialize)]
+pub struct EconomyReport {
+    /// Confirmed payments, mining rewards aside.
+    pub payments: usize,
+    pub fees: f32,
+    /// Payments still waiting in the poll at the end.
+    pub pending: usize,
+    /// Final balance of every node, in node order.
+    pub balances: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub seed: u64,
+    pub mining: MiningReport,
+    pub prices: Vec<PricePoint>,
+    pub economy: Option<EconomyReport>,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read scenario {:?}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Invalid scenario {:?}: {}", path, e))
+    }
+
+    fn mining_config(&self) -> Result<MiningConfig> {
+        let nodes = self.nodes.count;
+        if self.stop.height.is_none() && self.stop.time_secs.is_none() {
+            bail!("Scenario {} never stops, give it a height or a time", self.name)
+        }
+        let out_of_range = |ids: &[usize]| ids.iter().any(|n| *n >= nodes);
+        let mut network = NetworkConditions {
+            default_link: Link {
+                latency: (&self.network.latency).into(),
+                loss: self.network.loss,
+                bandwidth: self.network.bandwidth,
+            },
+            ..NetworkConditions::constant(VirtualTime(0))
+        };
+        // Topology draws from its own generator, so changing it doesn't change who mines when
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed.wrapping_add(1));
+        network.topology = match self.network.topology {
+            TopologySpec::FullMesh => Topology::FullMesh,
+            TopologySpec::Ring => Topology::ring(nodes),
+            TopologySpec::Random { degree } => Topology::random(nodes, degree, &mut rng),
+        };
+        if let Some(agents) = self.agents.iter().find(|a| a.nodes.as_deref().is_some_and(&out_of_range)) {
+            bail!("Agents {:?} run on nodes outside of the {} nodes", agents.agent, nodes)
+        }
+        let mut membership = vec![];
+        for TimedEvent { at_secs, event } in &self.events {
+            let at = VirtualTime::from_secs(*at_secs);
+            match event {
+                ScenarioEvent::Join { nodes } | ScenarioEvent::Leave { nodes } if out_of_range(nodes) => {
+                    bail!("Event at {}s names nodes outside of the {} nodes", at_secs, self.nodes.count)
+                }
+                ScenarioEvent::Partition { groups, .. } if groups.iter().any(|g| out_of_range(g)) => {
+                    bail!("Partition at {}s names nodes outside of the {} nodes", at_secs, self.nodes.count)
+                }
+                ScenarioEvent::Join { nodes } => membership.extend(nodes.iter().map(|n| (at, Membership::Join(*n)))),
+                ScenarioEvent::Leave { nodes } => membership.extend(nodes.iter().map(|n| (at, Membership::Leave(*n)))),
+                ScenarioEvent::Partition { groups, heal_after_secs } => network.partitions.push(Partition {
+                    at,
+                    heal_at: at + VirtualTime::from_secs(*heal_after_secs),
+                    groups: groups.clone(),
+                }),
+                ScenarioEvent::PriceShock { .. } => {}
+            }
+        }
+        Ok(MiningConfig {
+            seed: self.seed,
+            hash_power: self.nodes.hash_power.shares(nodes)?,
+            block_interval: VirtualTime::from_secs(self.chain.block_interval_secs),
+            network,
+            block_size: self.chain.block_size,
+            membership,
+            target_height: self.stop.height.unwrap_or(usize::MAX),
+            max_duration: self.stop.time_secs.map(VirtualTime::from_secs),
+            strategies: self.nodes.miners.iter().map(|m| (m.node, m.strategy)).collect(),
+            metrics: self.metrics.as_ref().map(|m| MetricsConfig {
+                interval: VirtualTime::from_secs(m.interval_secs),
+                directory: m.directory.clone(),
+                flush_every: m.flush_every_secs.map(VirtualTime::from_secs),
+            }),
+        })
+    }
+
+    /// Price every step until `until`, shocks apply from the first step at or after them.
+    fn prices(&self, until: VirtualTime) -> Vec<PricePoint> {
+        let model = self
+            .price
+            .model
+            .unwrap_or(Model::Gbm { drift: 0., volatility: self.price.volatility });
+        let feed = SyntheticFeed::new(self.seed, self.price.start_usd, model);
+        let step = VirtualTime::from_secs(self.price.step_secs.max(1));
+        let mut shocks: Vec<_> = self
+            .events
+            .iter()
+            .filter_map(|e| match e.event {
+                ScenarioEvent::PriceShock { factor } => Some((VirtualTime::from_secs(e.at_secs), factor)),
+                _ => None,
+            })
+            .collect();
+        shocks.sort_by_key(|(at, _)| *at);
+        let mut shocks = shocks.into_iter().peekable();
+        let (mut at, mut price, mut multiplier) = (VirtualTime(0), self.price.start_usd, 1.);
+        let mut points = vec![];
+        loop {
+            while let Some((_, factor)) = shocks.next_if(|(shock_at, _)| *shock_at <= at) {
+                multiplier *= factor;
+            }
+            points.push(PricePoint { at, bitcoin_usd: price * multiplier });
+            if at >= until {
+                return points;
+            }
+            at = at + step;
+            price = feed.next_price();
+        }
+    }
+
+    /// Real nodes replay the main chain of the model, each block mined by the
+    /// node which found it there, and their agents trade before every block.
+    /// All nodes take part from the start, joins, leaves and partitions only
+    /// shape who mines. Every node is sampled after each block for the metrics.
+    async fn run_economy(
+        &self,
+        main_chain: &[(usize, VirtualTime)],
+        prices: &[PricePoint],
+        mut metrics: Option<&mut Metrics>,
+    ) -> Result<EconomyReport> {
+        let params = ChainParams {
+            mining_difficulty: SIMULATION_DIFFICULTY,
+            signature_algorithm: SignatureAlgorithm::Ed25519,
+            max_transaction_count: self.chain.max_transaction_count,
+            ..ChainParams::default()
+        };
+        let mut simulation = Simulation::new(params);
+        let mut ids = vec![];
+        for _ in 0..self.nodes.count {
+            ids.push(simulation.join().await?);
+        }
+        for AgentsSpec { nodes, agent } in &self.agents {
+            for node in nodes.clone().unwrap_or_else(|| (0..ids.len()).collect()) {
+                simulation.add_agents(ids[node], std::slice::from_ref(agent));
+            }
+        }
+        let mut nocoin = NoCoinPrice::new(self.seed, Peg::Fixed { ratio: self.price.nocoin_ratio });
+        let mut fees_paid = vec![0.; ids.len()];
+        for (miner, mined_at) in main_chain {
+            let bitcoin_usd = prices
+                .iter()
+                .take_while(|p| p.at <= *mined_at)
+                .last()
+                .map_or(self.price.start_usd, |p| p.bitcoin_usd);
+            simulation.set_quote(Quote { bitcoin_usd, nocoin_usd: nocoin.update(bitcoin_usd) });
+            simulation.trade().await?;
+            simulation.mine(&ids[*miner]).await?;
+            let Some(metrics) = metrics.as_deref_mut() else {
+                continue;
+            };
+            let block = simulation.network(&ids[*miner])?.lock().await.blockchain.last_block().clone();
+            for t in block.transactions.0.iter().map(|t| &t.transaction.0) {
+                if let Some(payer) = t.from.and_then(|from| ids.iter().position(|id| *id == from)) {
+                    fees_paid[payer] += t.fee.0;
+                }
+            }
+            let mut samples = vec![];
+            for (node, id) in ids.iter().enumerate() {
+                let network = simulation.network(id)?.lock().await;
+                samples.push(EconomySample {
+                    at: *mined_at,
+                    height: network.blockchain.height(),
+                    node,
+                    mempool: network.transactions_poll.len(),
+                    balance: calculate_wallet(id, &network.blockchain).0,
+                    fees_paid: fees_paid[node],
+                });
+            }
+            metrics.record_economy(samples)?;
+        }
+        let consensus = simulation.converged().await?;
+        let network = simulation.network(&ids[0])?.lock().await;
+        let payments: Vec<_> = network
+            .blockchain
+            .0
+            .iter()
+            .flat_map(|b| &b.transactions.0)
+            .map(|t| &t.transaction.0)
+            .filter(|t| t.from.is_some())
+            .collect();
+        Ok(EconomyReport {
+            payments: payments.len(),
+            fees: payments.iter().map(|t| t.fee.0).sum(),
+            pending: network.transactions_poll.len(),
+            balances: ids
+                .iter()
+                .map(|id| consensus.balances.get(id).map_or(0., |b| b.0))
+                .collect(),
+        })
+    }
+
+    pub async fn run(&self) -> Result<ScenarioReport> {
+        let mut mining = simulate_mining(&self.mining_config()?)?;
+        let prices = self.prices(mining.duration);
+        let economy = if self.agents.is_empty() {
+            None
+        } else {
+            Some(self.run_economy(&mining.main_chain, &prices, mining.metrics.as_mut()).await?)
+        };
+        if let Some(metrics) = &mining.metrics {
+            metrics.write()?;
+        }
+        Ok(ScenarioReport {
+            name: self.name.clone(),
+            seed: self.seed,
+            mining,
+            prices,
+            economy,
+        })
+    }
+}
+
+/// Runs the scenario of `path` and writes its report as JSON to `output`,
+/// to the scenario's own `output`, or next to it. Metrics go to their own
+/// directory, or to one named like the report.
+pub async fn run_scenario_file(path: &Path, output: Option<PathBuf>) -> Result<PathBuf> {
+    let mut scenario = Scenario::load(path)?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let output = output
+        .or_else(|| scenario.output.as_ref().map(|o| directory.join(o)))
+        .unwrap_or_else(|| path.with_extension("results.json"));
+    if let Some(metrics) = scenario.metrics.as_mut() {
+        let metrics_directory = metrics.directory.as_ref().map(|d| directory.join(d));
+        metrics.directory = Some(metrics_directory.unwrap_or_else(|| output.with_extension("metrics")));
+    }
+    info!("Running scenario {} from {:?}", scenario.name, path);
+    let report = scenario.run().await?;
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&output, serde_json::to_string_pretty(&report)?)?;
+    info!(
+        "Scenario {} reached height {} in {:?} with {} stale blocks, results in {:?}",
+        report.name, report.mining.height, report.mining.duration, report.mining.stale_blocks, output
+    );
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTITION: &str = include_str!("../../scenarios/partition.toml");
+    const ECONOMY: &str = include_str!("../../scenarios/economy.toml");
+    const SELFISH: &str = include_str!("../../scenarios/selfish.toml");
+
+    #[tokio::test]
+    async fn example_scenario_runs() {
+        let scenario: Scenario = toml::from_str(PARTITION).unwrap();
+
+        let report = scenario.run().await.unwrap();
+
+        assert_eq!(report.mining.height, scenario.stop.height.unwrap());
+        assert_eq!(report.mining.convergence_after_heal.len(), 1);
+        assert!(report.mining.reorgs > 0);
+        let after_shock = report.prices.iter().find(|p| p.at >= VirtualTime::from_secs(6 * 3600)).unwrap();
+        let before_shock = report.prices.iter().rev().find(|p| p.at < VirtualTime::from_secs(6 * 3600)).unwrap();
+        assert!(after_shock.bitcoin_usd < before_shock.bitcoin_usd * 0.7);
+        assert_eq!(report.mining, scenario.run().await.unwrap().mining);
+    }
+
+    #[tokio::test]
+    async fn late_nodes_join_with_the_chain() {
+        let scenario: Scenario = toml::from_str(
+            r#"
+            name = "late joiners"
+            nodes = { count = 6 }
+            network = { topology = { kind = "ring" } }
+            events = [
+                { at_secs = 3600, kind = "join", nodes = [4, 5] },
+                { at_secs = 5400, kind = "leave", nodes = [0] },
+            ]
+            stop = { time_secs = 7200 }
+            "#,
+        )
+        .unwrap();
+
+        let report = scenario.run().await.unwrap();
+
+        assert!(report.mining.height > 80);
+        assert!(report.mining.main_chain_blocks[4] + report.mining.main_chain_blocks[5] > 5);
+        assert!(report.mining.stale_rate < 0.1, "Stale rate was {}", report.mining.stale_rate);
+        assert!(report.mining.duration >= VirtualTime::from_secs(7200));
+    }
+
+    #[test]
+    fn price_model_is_selectable() {
+        let scenario: Scenario = toml::from_str(
+            r#"
+            name = "flat prices"
+            nodes = { count = 1 }
+            price = { start_usd = 100.0, model = { kind = "random_walk", step = 0.0 } }
+            stop = { height = 1 }
+            "#,
+        )
+        .unwrap();
+
+        let prices = scenario.prices(VirtualTime::from_secs(3600));
+
+        assert_eq!(scenario.price.model, Some(Model::RandomWalk { step: 0. }));
+        assert!(prices.len() > 1);
+        assert!(prices.iter().all(|p| p.bitcoin_usd == 100.));
+    }
+
+    #[tokio::test]
+    async fn agents_trade_on_the_main_chain() {
+        let scenario: Scenario = toml::from_str(ECONOMY).unwrap();
+
+        let report = scenario.run().await.unwrap();
+
+        let economy = report.economy.unwrap();
+        assert!(economy.payments > 20, "Only {} payments", economy.payments);
+        assert!(economy.fees > 0.);
+        let total: f32 = economy.balances.iter().sum();
+        assert!((total - 10. * report.mining.height as f32).abs() < 0.1);
+        assert!(economy.balances[0] > economy.balances[1]);
+        let metrics = report.mining.metrics.unwrap();
+        assert_eq!(metrics.economy.len(), 6 * report.mining.height);
+        let last = &metrics.economy[metrics.economy.len() - 6..];
+        assert!(last.iter().zip(&economy.balances).all(|(s, b)| (s.balance - b).abs() < 0.01));
+        let fees_paid: f32 = last.iter().map(|s| s.fees_paid).sum();
+        assert!((fees_paid - economy.fees).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn selfish_miner_earns_more_than_its_share() {
+        let scenario: Scenario = toml::from_str(SELFISH).unwrap();
+
+        let report = scenario.run().await.unwrap();
+
+        let revenue = report.mining.main_chain_blocks[0] as f64 / report.mining.height as f64;
+        assert!(revenue > 0.45, "Selfish miner got {}", revenue);
+        assert!(report.mining.converged);
+    }
+
+    #[test]
+    fn rejects_bad_scenarios() {
+        let parse = |text: &str| toml::from_str::<Scenario>(text).map_err(anyhow::Error::from).and_then(|s| s.mining_config());
+
+        assert!(parse("name = \"endless\"\nnodes = { count = 3 }\nstop = {}").is_err());
+        assert!(parse("name = \"typo\"\nnodes = { count = 3, hashpower = 2 }\nstop = { height = 5 }").is_err());
+        let outside = "name = \"outside\"\nnodes = { count = 3 }\nstop = { height = 5 }\n\
+                       events = [{ at_secs = 0, kind = \"leave\", nodes = [3] }]";
+        assert!(parse(outside).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{cmp::Ordering, collections::BinaryHeap, ops::Add};
+
+use rand::Rng;
+use serde::Serialize;
+
+/// Milliseconds since the simulation started, no simulation ever waits for real time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
+pub struct VirtualTime(pub u64);
+
+impl VirtualTime {
+    pub const fn from_secs(secs: u64) -> Self {
+        Self(secs * 1000)
+    }
+}
+
+impl Add for VirtualTime {
+    type Output = VirtualTime;
+    fn add(self, rhs: Self) -> Self::Output {
+        VirtualTime(self.0 + rhs.0)
+    }
+}
+
+/// Waiting time of a Poisson process with this mean, rounded to whole milliseconds.
+pub fn exponential(rng: &mut impl Rng, mean: f64) -> VirtualTime {
+    let u: f64 = rng.gen();
+    VirtualTime((-mean * (1. - u).ln()).round() as u64)
+}
+
+struct Scheduled<E> {
+    at: VirtualTime,
+    seq: u64,
+    event: E,
+}
+
+// Events at the same time run in the order they were scheduled, which keeps
+// runs with the same seed identical.
+impl<E> Ord for Scheduled<E> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl<E> PartialOrd for Scheduled<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> PartialEq for Scheduled<E> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<E> Eq for Scheduled<E> {}
+
+/// Queue of future events, the clock jumps straight to the next one.
+pub struct Scheduler<E> {
+    now: VirtualTime,
+    queue: BinaryHeap<Scheduled<E>>,
+    seq: u64,
+}
+
+impl<E> Default for Scheduler<E> {
+    fn default() -> Self {
+        Self {
+            now: VirtualTime::default(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+}
+
+impl<E> Scheduler<E> {
+    pub fn now(&self) -> VirtualTime {
+        self.now
+    }
+
+    pub fn schedule_in(&mut self, delay: VirtualTime, event: E) {
+        self.queue.push(Scheduled {
+            at: self.now + delay,
+            seq: self.seq,
+            event,
+        });
+        self.seq += 1;
+    }
+
+    /// Earliest event, the clock is moved to its time.
+    pub fn next(&mut self) -> Option<(VirtualTime, E)> {
+        let scheduled = self.queue.pop()?;
+        self.now = scheduled.at;
+        Some((scheduled.at, scheduled.event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_run_in_time_then_schedule_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule_in(VirtualTime(30), "late");
+        scheduler.schedule_in(VirtualTime(10), "first");
+        scheduler.schedule_in(VirtualTime(10), "second");
+        let (_, first) = scheduler.next().unwrap();
+        scheduler.schedule_in(VirtualTime(5), "nested");
+
+        let rest: Vec<_> = std::iter::from_fn(|| scheduler.next()).collect();
+
+        assert_eq!(first, "first");
+        assert_eq!(
+            rest,
+            vec![(VirtualTime(10), "second"), (VirtualTime(15), "nested"), (VirtualTime(30), "late")]
+        );
+        assert_eq!(scheduler.now(), VirtualTime(30));
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use super::mining::{ancestor, simulate_mining, MinerStrategy, MiningConfig, ModelBlock};
+
+/// What a selfish miner keeps track of besides its private tip, it follows
+/// the strategy of Eyal and Sirer's "Majority is not enough".
+#[derive(Debug, Clone, Copy)]
+pub struct Withholding {
+    /// Best block it heard of from the others.
+    public: usize,
+    /// Highest of its own blocks it showed to the others.
+    published: usize,
+}
+
+/// How a selfish miner answers a block of the others.
+#[derive(Debug, PartialEq)]
+pub enum Reaction {
+    Ignore,
+    /// Its private chain lost, it mines on the public one from now on.
+    Adopt,
+    Publish(usize),
+}
+
+impl Withholding {
+    pub fn new(genesis: usize) -> Self {
+        Self { public: genesis, published: genesis }
+    }
+
+    /// It found `block` on top of its private tip. Only in a race, when it
+    /// showed a block as high as the public tip, it publishes right away to win.
+    pub fn mined(&mut self, blocks: &[ModelBlock], block: usize) -> Option<usize> {
+        let parent = blocks[block].parent;
+        let racing = parent == self.published
+            && parent != self.public
+            && blocks[parent].height == blocks[self.public].height;
+        racing.then(|| self.publish(block))
+    }
+
+    /// The others told it about `block`, `private` is its own tip.
+    pub fn heard(&mut self, blocks: &[ModelBlock], private: usize, block: usize) -> Reaction {
+        let height = blocks[block].height;
+        if height <= blocks[self.public].height {
+            return Reaction::Ignore;
+        }
+        self.public = block;
+        let private_height = blocks[private].height;
+        if private_height < height {
+            self.published = block;
+            return Reaction::Adopt;
+        }
+        if ancestor(blocks, private, height) == block {
+            // Its own block came back from the others
+            return Reaction::Ignore;
+        }
+        let shown = match private_height - height {
+            // Even or one ahead, it shows everything, in a race or to win
+            0 | 1 => private,
+            // Further ahead, it matches the public chain and keeps the rest
+            _ => ancestor(blocks, private, height),
+        };
+        if shown == self.published {
+            return Reaction::Ignore;
+        }
+        Reaction::Publish(self.publish(shown))
+    }
+
+    pub fn publish(&mut self, block: usize) -> usize {
+        self.published = block;
+        block
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevenuePoint {
+    pub hash_share: f64,
+    /// Share of the main chain the selfish miner got, averaged over the runs.
+    pub revenue_share: f64,
+    pub lowest: f64,
+    pub highest: f64,
+    pub runs: usize,
+}
+
+/// Node 0 mines selfishly with `hash_share` of all hash power, the others are
+/// honest and split the rest evenly. Each share is run `runs` times with different
+/// seeds, all blocks are sent to everybody at once.
+pub fn selfish_revenue(nodes: usize, hash_shares: &[f64], runs: usize, height: usize, seed: u64) -> Result<Vec<RevenuePoint>> {
+    if nodes == 0 {
+        bail!("Selfish mining needs at least the selfish node")
+    }
+    if runs == 0 {
+        bail!("Each hash share needs at least one run")
+    }
+    if height == 0 {
+        bail!("Revenue needs at least one block")
+    }
+    if let Some(share) = hash_shares.iter().find(|s| !(0. ..=1.).contains(*s)) {
+        bail!("Hash share {} isn't between 0 and 1", share)
+    }
+    hash_shares
+        .iter()
+        .map(|share| {
+            let honest = (1. - share) / (nodes - 1).max(1) as f64;
+            let revenues = (0..runs as u64)
+                .map(|run| {
+                    let mut config = MiningConfig::uniform(seed.wrapping_add(run), nodes, height);
+                    config.hash_power = std::iter::once(*share).chain(vec![honest; nodes - 1]).collect();
+                    config.strategies.push((0, MinerStrategy::Selfish));
+                    let report = simulate_mining(&config)?;
+                    Ok(report.main_chain_blocks[0] as f64 / report.height as f64)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(RevenuePoint {
+                hash_share: *share,
+                revenue_share: revenues.iter().sum::<f64>() / runs as f64,
+                lowest: revenues.iter().copied().fold(f64::INFINITY, f64::min),
+                highest: revenues.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                runs,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::scheduler::VirtualTime, *};
+
+    fn chain(blocks: &mut Vec<ModelBlock>, parent: usize, miner: usize, length: usize) -> usize {
+        (0..length).fold(parent, |parent, _| {
+            let height = blocks[parent].height + 1;
+            blocks.push(ModelBlock { parent, height, miner, mined_at: VirtualTime(0) });
+            blocks.len() - 1
+        })
+    }
+
+    #[test]
+    fn publishes_by_its_lead() {
+        let mut blocks = vec![ModelBlock { parent: 0, height: 0, miner: 0, mined_at: VirtualTime(0) }];
+        let private = chain(&mut blocks, 0, 0, 3);
+        let mut selfish = Withholding::new(0);
+        let first_honest = chain(&mut blocks, 0, 1, 1);
+        let second_honest = chain(&mut blocks, first_honest, 1, 1);
+        let third_honest = chain(&mut blocks, second_honest, 1, 1);
+
+        assert_eq!(selfish.heard(&blocks, private, first_honest), Reaction::Publish(1));
+        assert_eq!(selfish.heard(&blocks, private, first_honest), Reaction::Ignore);
+        assert_eq!(selfish.heard(&blocks, private, second_honest), Reaction::Publish(private));
+        assert_eq!(selfish.heard(&blocks, private, 1), Reaction::Ignore);
+        assert_eq!(selfish.heard(&blocks, private, third_honest), Reaction::Ignore);
+        let fourth_honest = chain(&mut blocks, third_honest, 1, 1);
+        assert_eq!(selfish.heard(&blocks, private, fourth_honest), Reaction::Adopt);
+    }
+
+    #[test]
+    fn wins_races_with_its_next_block() {
+        let mut blocks = vec![ModelBlock { parent: 0, height: 0, miner: 0, mined_at: VirtualTime(0) }];
+        let private = chain(&mut blocks, 0, 0, 1);
+        let mut selfish = Withholding::new(0);
+        let honest = chain(&mut blocks, 0, 1, 1);
+
+        assert_eq!(selfish.heard(&blocks, private, honest), Reaction::Publish(private));
+        let next = chain(&mut blocks, private, 0, 1);
+        assert_eq!(selfish.mined(&blocks, next), Some(next));
+        let after = chain(&mut blocks, next, 0, 1);
+        assert_eq!(selfish.mined(&blocks, after), None);
+    }
+
+    #[test]
+    fn pays_off_only_for_big_miners() {
+        let points = selfish_revenue(10, &[0.1, 0.4], 4, 2_000, 0).unwrap();
+
+        assert!(points[0].revenue_share < 0.08, "Small miner got {:?}", points[0]);
+        assert!(points[1].revenue_share > 0.45, "Big miner got {:?}", points[1]);
+    }
+
+    #[test]
+    fn needs_a_node_and_a_run() {
+        assert!(selfish_revenue(0, &[0.3], 4, 100, 0).is_err());
+        assert!(selfish_revenue(10, &[0.3], 0, 100, 0).is_err());
+    }
+
+    #[test]
+    fn needs_a_block_and_shares_of_the_whole() {
+        assert!(selfish_revenue(10, &[0.3], 4, 0, 0).is_err());
+        assert!(selfish_revenue(10, &[0.3, 1.5], 4, 100, 0).is_err());
+        assert!(selfish_revenue(10, &[-0.1], 4, 100, 0).is_err());
+        assert!(selfish_revenue(10, &[f64::NAN], 4, 100, 0).is_err());
+    }
+}
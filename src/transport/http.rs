@@ -0,0 +1,49 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    domain::{Block, Blockchain, Envelope, Node, NodeId, ProvenTransaction, SignedTransaction},
+    web::{
+        get_chain, get_pending_transactions, send_leave, send_new_block, send_new_transaction,
+        send_ping,
+    },
+};
+
+use super::Transport;
+
+pub struct HttpTransport {
+    client: reqwest::Client,
+}
+
+impl HttpTransport {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn broadcast_block(&self, recipients: &[Node], block: &Envelope<Block>) -> Result<Vec<NodeId>> {
+        send_new_block(self.client.clone(), recipients.to_vec(), block).await
+    }
+
+    async fn broadcast_transaction(&self, recipients: &[Node], transaction: &SignedTransaction) -> Result<()> {
+        send_new_transaction(&self.client, transaction, recipients).await
+    }
+
+    async fn announce_leave(&self, recipients: &[Node], leaving: &Envelope<NodeId>) -> Result<()> {
+        send_leave(&self.client, leaving, recipients).await
+    }
+
+    async fn ping(&self, node: &Node) -> Result<()> {
+        send_ping(&self.client, node).await
+    }
+
+    async fn get_chain(&self, node: &Node) -> Result<Blockchain> {
+        get_chain(node).await
+    }
+
+    async fn get_pending_transactions(&self, node: &Node) -> Result<Vec<ProvenTransaction>> {
+        get_pending_transactions(node).await
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::info;
+use tokio::sync::Mutex;
+
+use crate::domain::{
+    open, remove_node, try_acknowledge_node, try_add_block, try_add_transaction,
+    try_register_node, Block, Blockchain, Envelope, Network, Node, NodeId, ProvenTransaction,
+    SignedTransaction,
+};
+
+use super::Transport;
+
+type Networks = HashMap<NodeId, Arc<Mutex<Network>>>;
+
+/// Nodes of one process handing messages straight to each other's `Network`.
+/// One transport is shared by all of them, nodes are reachable while attached.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    networks: Arc<std::sync::Mutex<Networks>>,
+}
+
+impl MemoryTransport {
+    pub fn attach(&self, network: Arc<Mutex<Network>>, id: NodeId) {
+        self.networks.lock().unwrap().insert(id, network);
+    }
+
+    pub fn detach(&self, id: &NodeId) {
+        self.networks.lock().unwrap().remove(id);
+    }
+
+    fn network(&self, id: &NodeId) -> Result<Arc<Mutex<Network>>> {
+        self.networks
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or(anyhow!("Node {:?} is not reachable", id))
+    }
+
+    /// What the `register` endpoint does, `bootstrap` adds the node and tells
+    /// the others about it. Returns all nodes the new one should know.
+    pub async fn register(&self, bootstrap: &Node, registration: Envelope<Node>) -> Result<Vec<Node>> {
+        let network = self.network(&bootstrap.id)?;
+        let mut network = network.lock().await;
+        let node = try_register_node(&mut network, registration.clone())?.clone();
+        let nodes = network.nodes.clone();
+        drop(network);
+        for other in nodes.iter().filter(|n| n.id != node.id && n.id != bootstrap.id) {
+            let result = match self.network(&other.id) {
+                Ok(network) => {
+                    let mut network = network.lock().await;
+                    try_acknowledge_node(&mut network, registration.clone())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                info!("Couldn't acknowledge {:?} to {:?}: {}", node.id, other.id, e);
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn broadcast_block(&self, recipients: &[Node], block: &Envelope<Block>) -> Result<Vec<NodeId>> {
+        let mut unreachable = vec![];
+        for node in recipients {
+            let Ok(network) = self.network(&node.id) else {
+                unreachable.push(node.id);
+                continue;
+            };
+            let mut network = network.lock().await;
+            if let Err(e) = open(&mut network, block.clone(), None)
+                .and_then(|block| try_add_block(&mut network, block))
+            {
+                info!("Node {:?} rejected block: {}", node.id, e);
+            }
+        }
+        Ok(unreachable)
+    }
+
+    async fn broadcast_transaction(&self, recipients: &[Node], transaction: &SignedTransaction) -> Result<()> {
+        for node in recipients {
+            let network = match self.network(&node.id) {
+                Ok(network) => network,
+                Err(e) => {
+                    info!("Couldn't send transaction: {}", e);
+                    continue;
+                }
+            };
+            let mut network = network.lock().await;
+            let SignedTransaction { transaction, proof } = transaction.clone();
+            if let Err(e) = try_add_transaction(&mut network, transaction, proof) {
+                info!("Node {:?} rejected transaction: {}", node.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn announce_leave(&self, recipients: &[Node], leaving: &Envelope<NodeId>) -> Result<()> {
+        for node in recipients {
+            let Ok(network) = self.network(&node.id) else {
+                continue;
+            };
+            let mut network = network.lock().await;
+            let sender = leaving.sender;
+            let result = open(&mut network, leaving.clone(), None).and_then(|id| {
+                if id != sender {
+                    bail!("Node {:?} can't announce leave of {:?}", sender, id)
+                }
+                remove_node(&mut network, &id)
+            });
+            if let Err(e) = result {
+                info!("Node {:?} rejected leave: {}", node.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&self, node: &Node) -> Result<()> {
+        self.network(&node.id).map(|_| ())
+    }
+
+    async fn get_chain(&self, node: &Node) -> Result<Blockchain> {
+        Ok(self.network(&node.id)?.lock().await.blockchain.clone())
+    }
+
+    async fn get_pending_transactions(&self, node: &Node) -> Result<Vec<ProvenTransaction>> {
+        Ok(self.network(&node.id)?.lock().await.transactions_poll.clone())
+    }
+}
@@ -0,0 +1,32 @@
+mod http;
+mod memory;
+mod tcp;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::domain::{
+    Block, Blockchain, Envelope, Node, NodeId, ProvenTransaction, SignedTransaction,
+};
+
+pub use http::HttpTransport;
+pub use memory::MemoryTransport;
+pub use tcp::TcpTransport;
+
+pub enum PeerTransport {
+    Http,
+    Tcp,
+}
+
+/// How a node reaches its peers. Registration always goes over http,
+/// everything after that can use any transport.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Returns ids of the recipients which couldn't be reached.
+    async fn broadcast_block(&self, recipients: &[Node], block: &Envelope<Block>) -> Result<Vec<NodeId>>;
+    async fn broadcast_transaction(&self, recipients: &[Node], transaction: &SignedTransaction) -> Result<()>;
+    async fn announce_leave(&self, recipients: &[Node], leaving: &Envelope<NodeId>) -> Result<()>;
+    async fn ping(&self, node: &Node) -> Result<()>;
+    async fn get_chain(&self, node: &Node) -> Result<Blockchain>;
+    async fn get_pending_transactions(&self, node: &Node) -> Result<Vec<ProvenTransaction>>;
+}
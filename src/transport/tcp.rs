@@ -0,0 +1,637 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, Mutex},
+};
+
+use crate::domain::{
+    decode, encode, frame_len, open, remove_node, seal, try_add_block, try_add_transaction, Block,
+    Blockchain, Envelope, Misbehavior, Network, Node, NodeId, Proof, ProvenTransaction, Reputation,
+    SignedTransaction, Transaction, FRAME_HEADER_LEN, WIRE_VERSION,
+};
+
+use super::Transport;
+
+/// Tcp listener of a node sits this many ports above its http server.
+pub const TCP_PORT_OFFSET: u16 = 1000;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn tcp_addr_of(addr: &SocketAddr) -> Result<SocketAddr> {
+    let port = addr
+        .port()
+        .checked_add(TCP_PORT_OFFSET)
+        .ok_or(anyhow!("Node at {} has no tcp port above its http one", addr))?;
+    let mut copy = *addr;
+    copy.set_port(port);
+    Ok(copy)
+}
+
+/// First message on every connection, in both directions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+    pub version: u8,
+    pub params_hash: [u8; 32],
+    pub best_height: usize,
+    pub node_id: NodeId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum PeerMessage {
+    Handshake(Envelope<Handshake>),
+    Block(Envelope<Block>),
+    Transaction { transaction: Transaction, proof: Proof },
+    GetChain,
+    Chain(Blockchain),
+    GetPendingTransactions,
+    PendingTransactions(Vec<ProvenTransaction>),
+    Ping,
+    Pong(NodeId),
+    Leave(Envelope<NodeId>),
+}
+
+/// Unit of multiplexing: replies carry the id of the request they answer.
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet {
+    id: u64,
+    reply_to: Option<u64>,
+    message: PeerMessage,
+}
+
+type PendingReplies = std::sync::Mutex<HashMap<u64, oneshot::Sender<PeerMessage>>>;
+
+struct Connection {
+    outgoing: mpsc::UnboundedSender<Packet>,
+    pending: PendingReplies,
+    next_id: AtomicU64,
+}
+
+impl Connection {
+    fn send(&self, message: PeerMessage, reply_to: Option<u64>) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.outgoing
+            .send(Packet {
+                id,
+                reply_to,
+                message,
+            })
+            .map_err(|_| anyhow!("Connection is closed"))?;
+        Ok(id)
+    }
+
+    async fn request(&self, message: PeerMessage) -> Result<PeerMessage> {
+        let (sender, receiver) = oneshot::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(id, sender);
+        self.outgoing
+            .send(Packet {
+                id,
+                reply_to: None,
+                message,
+            })
+            .map_err(|_| anyhow!("Connection is closed"))?;
+        let reply = tokio::time::timeout(REQUEST_TIMEOUT, receiver).await;
+        self.pending.lock().unwrap().remove(&id);
+        Ok(reply??)
+    }
+}
+
+/// Persistent connections to peers, opened lazily and kept until either side drops.
+/// Peers are scored in the same reputation as the http server's.
+#[derive(Clone)]
+pub struct TcpTransport {
+    network: Arc<Mutex<Network>>,
+    reputation: Arc<std::sync::Mutex<Reputation>>,
+    connections: Arc<std::sync::Mutex<HashMap<NodeId, Arc<Connection>>>>,
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut frame = vec![0; FRAME_HEADER_LEN];
+    reader.read_exact(&mut frame).await?;
+    let len = frame_len(&frame).ok_or(anyhow!("Incomplete frame header"))?;
+    if len > MAX_FRAME_LEN {
+        bail!("Frame of {} bytes exceeds limit of {}", len, MAX_FRAME_LEN)
+    }
+    frame.resize(len, 0);
+    reader.read_exact(&mut frame[FRAME_HEADER_LEN..]).await?;
+    Ok(frame)
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet> {
+    decode(&read_frame(reader).await?)
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
+    stream.write_all(&encode(packet)?).await?;
+    Ok(())
+}
+
+impl TcpTransport {
+    pub fn new(network: Arc<Mutex<Network>>, reputation: Arc<std::sync::Mutex<Reputation>>) -> Self {
+        Self {
+            network,
+            reputation,
+            connections: Default::default(),
+        }
+    }
+
+    pub async fn listen(&self, addr: SocketAddr) -> Result<()> {
+        self.serve(TcpListener::bind(tcp_addr_of(&addr)?).await?).await
+    }
+
+    async fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("Listening for tcp peers on {:?}", listener.local_addr()?);
+        loop {
+            let (stream, remote) = listener.accept().await?;
+            let transport = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = transport.clone().accept(stream).await {
+                    transport.reject(remote, &e, Misbehavior::MalformedPayload);
+                    info!("Rejected tcp peer {}: {}", remote, e);
+                }
+            });
+        }
+    }
+
+    fn penalize(&self, peer: SocketAddr, misbehavior: Misbehavior) {
+        let mut reputation = self.reputation.lock().unwrap();
+        if reputation.penalize(peer, misbehavior) {
+            info!("Banned tcp peer {} after {:?}", peer, misbehavior);
+        } else {
+            info!(
+                "Penalized tcp peer {} for {:?}, now at {} points",
+                peer,
+                misbehavior,
+                reputation.penalty_of(&peer)
+            );
+        }
+    }
+
+    /// Penalizes the peer for `e` unless an honest peer could have caused it.
+    fn reject(&self, peer: SocketAddr, e: &anyhow::Error, otherwise: Misbehavior) {
+        if let Some(misbehavior) = Misbehavior::of(e, otherwise) {
+            self.penalize(peer, misbehavior);
+        }
+    }
+
+    fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.reputation.lock().unwrap().is_banned(&peer)
+    }
+
+    async fn own_handshake(&self) -> Result<Envelope<Handshake>> {
+        let network = self.network.lock().await;
+        let handshake = Handshake {
+            version: WIRE_VERSION,
+            params_hash: network.params.hash(),
+            best_height: network.blockchain.height(),
+            node_id: network.user.node.id,
+        };
+        seal(network.user.node.id, &network.user.priv_key, handshake)
+    }
+
+    /// Peers are scored under the address of their node, the one they connect
+    /// from changes with every connection.
+    async fn verify_handshake(&self, packet: Packet) -> Result<(Handshake, SocketAddr)> {
+        let envelope = match packet.message {
+            PeerMessage::Handshake(envelope) => envelope,
+            other => bail!("Expected handshake, received {:?}", other),
+        };
+        let sender = envelope.sender;
+        let mut network = self.network.lock().await;
+        let handshake = open(&mut network, envelope, None)?;
+        if handshake.version != WIRE_VERSION {
+            bail!(
+                "Peer speaks version {}, expected {}",
+                handshake.version,
+                WIRE_VERSION
+            )
+        }
+        if handshake.params_hash != network.params.hash() {
+            bail!("Peer {:?} runs a chain with different params", sender)
+        }
+        if handshake.node_id != sender {
+            bail!(
+                "Handshake for {:?} signed by {:?}",
+                handshake.node_id,
+                sender
+            )
+        }
+        info!(
+            "Peer {:?} connected at height {}, we are at {}",
+            sender,
+            handshake.best_height,
+            network.blockchain.height()
+        );
+        let remote = network
+            .nodes
+            .iter()
+            .find(|n| n.id == sender)
+            .map(|n| n.addr)
+            .ok_or(anyhow!("Unknown peer {:?}", sender))?;
+        if self.is_banned(remote) {
+            bail!("Peer {:?} is banned", sender)
+        }
+        Ok((handshake, remote))
+    }
+
+    async fn accept(self, mut stream: TcpStream) -> Result<()> {
+        let packet = read_packet(&mut stream).await?;
+        let (handshake, remote) = self.verify_handshake(packet).await?;
+        let reply = Packet {
+            id: 0,
+            reply_to: None,
+            message: PeerMessage::Handshake(self.own_handshake().await?),
+        };
+        write_packet(&mut stream, &reply).await?;
+        let connection = self.register(handshake.node_id, remote, stream);
+        self.catch_up_if_behind(&handshake, remote, connection).await;
+        Ok(())
+    }
+
+    async fn connect(&self, node: &Node) -> Result<Arc<Connection>> {
+        if let Some(connection) = self.connections.lock().unwrap().get(&node.id) {
+            return Ok(connection.clone());
+        }
+        let mut stream = TcpStream::connect(tcp_addr_of(&node.addr)?).await?;
+        let hello = Packet {
+            id: 0,
+            reply_to: None,
+            message: PeerMessage::Handshake(self.own_handshake().await?),
+        };
+        write_packet(&mut stream, &hello).await?;
+        let packet = read_packet(&mut stream).await?;
+        let (handshake, remote) = self.verify_handshake(packet).await?;
+        if handshake.node_id != node.id {
+            bail!(
+                "Connected to {:?} but {:?} answered",
+                node.id,
+                handshake.node_id
+            )
+        }
+        let connection = self.register(node.id, remote, stream);
+        self.catch_up_if_behind(&handshake, remote, connection.clone()).await;
+        Ok(connection)
+    }
+
+    /// Fetches the chain of a peer which is ahead in the background, its blocks
+    /// go through the same checks as gossiped ones.
+    async fn catch_up_if_behind(&self, handshake: &Handshake, remote: SocketAddr, connection: Arc<Connection>) {
+        if handshake.best_height <= self.network.lock().await.blockchain.height() {
+            return;
+        }
+        let transport = self.clone();
+        let peer = handshake.node_id;
+        tokio::spawn(async move {
+            if let Err(e) = transport.catch_up(peer, &connection).await {
+                transport.reject(remote, &e, Misbehavior::InvalidBlock);
+                info!("Couldn't catch up with tcp peer {:?}: {}", peer, e);
+            }
+        });
+    }
+
+    async fn catch_up(&self, peer: NodeId, connection: &Connection) -> Result<()> {
+        let chain = match connection.request(PeerMessage::GetChain).await? {
+            PeerMessage::Chain(chain) => chain,
+            other => bail!("Expected chain from {:?}, received {:?}", peer, other),
+        };
+        let mut network = self.network.lock().await;
+        let known: HashSet<_> = network.blockchain.0.iter().map(|b| b.header.hash.0.clone()).collect();
+        for block in chain.0.into_iter().filter(|b| !known.contains(&b.header.hash.0)) {
+            try_add_block(&mut network, block)?;
+        }
+        info!("Caught up with {:?}, now at height {}", peer, network.blockchain.height());
+        Ok(())
+    }
+
+    fn register(&self, peer: NodeId, remote: SocketAddr, stream: TcpStream) -> Arc<Connection> {
+        let (mut reader, mut writer) = stream.into_split();
+        let (outgoing, mut to_send) = mpsc::unbounded_channel::<Packet>();
+        let connection = Arc::new(Connection {
+            outgoing,
+            pending: Default::default(),
+            next_id: AtomicU64::new(1),
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(peer, connection.clone());
+
+        tokio::spawn(async move {
+            while let Some(packet) = to_send.recv().await {
+                let written = match encode(&packet) {
+                    Ok(frame) => writer.write_all(&frame).await.map_err(|e| e.into()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    info!("Couldn't write to tcp peer {:?}: {}", peer, e);
+                    break;
+                }
+            }
+        });
+
+        let transport = self.clone();
+        let receiving = connection.clone();
+        tokio::spawn(async move {
+            loop {
+                if transport.is_banned(remote) {
+                    info!("Dropping banned tcp peer {:?}", peer);
+                    break;
+                }
+                let frame = match read_frame(&mut reader).await {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        info!("Tcp peer {:?} disconnected: {}", peer, e);
+                        break;
+                    }
+                };
+                match decode(&frame) {
+                    Ok(packet) => transport.dispatch(peer, remote, &receiving, packet).await,
+                    Err(e) => {
+                        transport.penalize(remote, Misbehavior::MalformedPayload);
+                        info!("Malformed packet from tcp peer {:?}: {}", peer, e);
+                    }
+                }
+            }
+            receiving.pending.lock().unwrap().clear();
+            let mut connections = transport.connections.lock().unwrap();
+            if connections
+                .get(&peer)
+                .map(|c| Arc::ptr_eq(c, &receiving))
+                .unwrap_or(false)
+            {
+                connections.remove(&peer);
+            }
+        });
+        connection
+    }
+
+    async fn dispatch(&self, peer: NodeId, remote: SocketAddr, connection: &Connection, packet: Packet) {
+        if let Some(request) = packet.reply_to {
+            if let Some(waiting) = connection.pending.lock().unwrap().remove(&request) {
+                let _ = waiting.send(packet.message);
+            }
+            return;
+        }
+        let rejection = match packet.message {
+            PeerMessage::Block(_) => Misbehavior::InvalidBlock,
+            PeerMessage::Transaction { .. } => Misbehavior::InvalidTransaction,
+            _ => Misbehavior::MalformedPayload,
+        };
+        match self.handle(peer, packet.message).await {
+            Ok(Some(reply)) => {
+                let _ = connection.send(reply, Some(packet.id));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.reject(remote, &e, rejection);
+                info!("Rejected message from tcp peer {:?}: {}", peer, e);
+            }
+        }
+    }
+
+    async fn handle(&self, peer: NodeId, message: PeerMessage) -> Result<Option<PeerMessage>> {
+        let mut network = self.network.lock().await;
+        match message {
+            PeerMessage::Block(block) => {
+                let block = open(&mut network, block, None)?;
+                try_add_block(&mut network, block)?;
+                Ok(None)
+            }
+            PeerMessage::Transaction { transaction, proof } => {
+                let id = try_add_transaction(&mut network, transaction, proof)?;
+                info!("Added transaction {:?} from tcp peer {:?}", id, peer);
+                Ok(None)
+            }
+            PeerMessage::GetChain => Ok(Some(PeerMessage::Chain(network.blockchain.clone()))),
+            PeerMessage::GetPendingTransactions => Ok(Some(PeerMessage::PendingTransactions(
+                network.transactions_poll.clone(),
+            ))),
+            PeerMessage::Ping => Ok(Some(PeerMessage::Pong(network.user.node.id))),
+            PeerMessage::Leave(leaving) => {
+                let id = open(&mut network, leaving, None)?;
+                if id != peer {
+                    bail!("Node {:?} can't announce leave of {:?}", peer, id)
+                }
+                remove_node(&mut network, &id)?;
+                info!("Node {:?} left the network", id);
+                self.connections.lock().unwrap().remove(&id);
+                Ok(None)
+            }
+            unexpected => bail!("Unexpected message {:?}", unexpected),
+        }
+    }
+
+    async fn send(&self, node: &Node, message: PeerMessage) -> Result<()> {
+        self.connect(node).await?.send(message, None).map(|_| ())
+    }
+
+    async fn request(&self, node: &Node, message: PeerMessage) -> Result<PeerMessage> {
+        self.connect(node).await?.request(message).await
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn broadcast_block(&self, recipients: &[Node], block: &Envelope<Block>) -> Result<Vec<NodeId>> {
+        let mut unreachable = vec![];
+        for node in recipients {
+            if let Err(e) = self.send(node, PeerMessage::Block(block.clone())).await {
+                info!("Couldn't send block to {:?}: {}", node.id, e);
+                unreachable.push(node.id);
+            }
+        }
+        Ok(unreachable)
+    }
+
+    async fn broadcast_transaction(&self, recipients: &[Node], transaction: &SignedTransaction) -> Result<()> {
+        for node in recipients {
+            let message = PeerMessage::Transaction {
+                transaction: transaction.transaction.clone(),
+                proof: transaction.proof.clone(),
+            };
+            if let Err(e) = self.send(node, message).await {
+                info!("Couldn't send transaction to {:?}: {}", node.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn announce_leave(&self, recipients: &[Node], leaving: &Envelope<NodeId>) -> Result<()> {
+        for node in recipients {
+            if let Err(e) = self.send(node, PeerMessage::Leave(leaving.clone())).await {
+                info!("Couldn't announce leave to {:?}: {}", node.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&self, node: &Node) -> Result<()> {
+        match self.request(node, PeerMessage::Ping).await? {
+            PeerMessage::Pong(id) if id == node.id => Ok(()),
+            other => bail!("Expected pong from {:?}, received {:?}", node.id, other),
+        }
+    }
+
+    async fn get_chain(&self, node: &Node) -> Result<Blockchain> {
+        match self.request(node, PeerMessage::GetChain).await? {
+            PeerMessage::Chain(chain) => Ok(chain),
+            other => bail!("Expected chain from {:?}, received {:?}", node.id, other),
+        }
+    }
+
+    async fn get_pending_transactions(&self, node: &Node) -> Result<Vec<ProvenTransaction>> {
+        match self.request(node, PeerMessage::GetPendingTransactions).await? {
+            PeerMessage::PendingTransactions(transactions) => Ok(transactions),
+            other => bail!(
+                "Expected pending transactions from {:?}, received {:?}",
+                node.id,
+                other
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{
+        create_mined_block, create_mining_reward, generate_key, try_adopt_network, try_mine_any,
+        try_start_new_network, ChainParams, Draft, ReputationConfig,
+    };
+
+    use super::*;
+
+    /// Port of a node whose tcp port was free a moment ago, so nobody listens there.
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        listener.local_addr().unwrap().port() - TCP_PORT_OFFSET
+    }
+
+    fn transport(port: u16) -> (TcpTransport, Node) {
+        let (private, public) = generate_key(ChainParams::default().signature_algorithm).unwrap();
+        let network = try_start_new_network(SocketAddr::from(([127, 0, 0, 1], port)), private, public).unwrap();
+        let node = network.user.node.clone();
+        (TcpTransport::new(Arc::new(Mutex::new(network)), reputation()), node)
+    }
+
+    fn reputation() -> Arc<std::sync::Mutex<Reputation>> {
+        Arc::new(std::sync::Mutex::new(Reputation::new(ReputationConfig::default())))
+    }
+
+    fn mine(network: &mut Network) {
+        let reward = [create_mining_reward(network.user.node.id)];
+        let draft = Draft::on(network.blockchain.last_block());
+        let (hash, nonce) = try_mine_any(&draft, network.params.mining_difficulty, &reward).unwrap();
+        let block = create_mined_block(draft, hash, nonce, &reward.iter().collect::<Vec<_>>(), network.user.node.id);
+        try_add_block(network, block).unwrap();
+    }
+
+    /// Bound before it's returned, so peers can connect right away.
+    async fn listening() -> (TcpTransport, Node) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let (transport, node) = transport(listener.local_addr().unwrap().port() - TCP_PORT_OFFSET);
+        let serving = transport.clone();
+        tokio::spawn(async move { serving.serve(listener).await });
+        (transport, node)
+    }
+
+    #[tokio::test]
+    async fn peers_talk_over_one_connection() {
+        let (first, first_node) = transport(free_port());
+        let (second, second_node) = listening().await;
+        first.network.lock().await.nodes.push(second_node.clone());
+        second.network.lock().await.nodes.push(first_node.clone());
+
+        first.ping(&second_node).await.unwrap();
+        let chain = first.get_chain(&second_node).await.unwrap();
+
+        assert_eq!(chain.height(), second.network.lock().await.blockchain.height());
+        assert_eq!(first.connections.lock().unwrap().len(), 1);
+        assert!(first.ping(&first_node).await.is_err(), "Nobody listens there");
+    }
+
+    #[test]
+    fn tcp_port_must_fit() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], u16::MAX - TCP_PORT_OFFSET));
+
+        let tcp = tcp_addr_of(&addr).unwrap();
+
+        assert_eq!(tcp.port(), u16::MAX);
+        assert!(tcp_addr_of(&SocketAddr::from(([127, 0, 0, 1], u16::MAX))).is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_sending_forged_blocks_is_banned() {
+        let (first, first_node) = transport(free_port());
+        let (second, second_node) = listening().await;
+        first.network.lock().await.nodes.push(second_node.clone());
+        second.network.lock().await.nodes.push(first_node.clone());
+        let forged = |network: &Network| {
+            let mut block = network.blockchain.last_block().clone();
+            block.transactions.0.push(create_mining_reward(network.user.node.id));
+            seal(network.user.node.id, &network.user.priv_key, block).unwrap()
+        };
+
+        for _ in 0..2 {
+            let block = forged(&*first.network.lock().await);
+            first.broadcast_block(std::slice::from_ref(&second_node), &block).await.unwrap();
+        }
+
+        for _ in 0..100 {
+            if second.is_banned(first_node.addr) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(second.is_banned(first_node.addr));
+        assert!(first.ping(&second_node).await.is_err(), "Banned peer is still served");
+    }
+
+    #[tokio::test]
+    async fn node_behind_catches_up_after_handshake() {
+        let (ahead, ahead_node) = listening().await;
+        let mut network = ahead.network.lock().await;
+        network.params.mining_difficulty = 1;
+        let (private, public) = generate_key(network.params.signature_algorithm).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let behind = try_adopt_network(
+            addr,
+            private,
+            public,
+            network.nodes.clone(),
+            network.blockchain.clone(),
+            network.params.clone(),
+        )
+        .unwrap();
+        network.nodes.push(behind.user.node.clone());
+        for _ in 0..2 {
+            mine(&mut network);
+        }
+        let height = network.blockchain.height();
+        drop(network);
+        let behind = TcpTransport::new(Arc::new(Mutex::new(behind)), reputation());
+
+        behind.ping(&ahead_node).await.unwrap();
+
+        for _ in 0..100 {
+            if behind.network.lock().await.blockchain.height() == height {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(behind.network.lock().await.blockchain.height(), height);
+    }
+}
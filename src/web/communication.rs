@@ -1,31 +1,72 @@
 use std::net::SocketAddr;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use futures::{stream::FuturesUnordered, StreamExt};
 use log::info;
 
-use crate::domain::{Blockchain, Node, PubKey, Transaction, User, Block};
+use crate::domain::{
+    encode, Block, Blockchain, Envelope, Node, NodeId, ProvenTransaction, SignedTransaction,
+};
 
-use self::toolkit::url_for;
+use self::toolkit::{post_frame, read_wire, url_for};
 
 use super::server::ROUTES;
 
 mod toolkit {
     use anyhow::Result;
     use log::info;
+    use reqwest::header::{ACCEPT, CONTENT_TYPE};
     use serde::de::DeserializeOwned;
     use std::{fmt::Debug, net::SocketAddr};
 
+    use crate::domain::{decode, BINARY_CONTENT_TYPE};
+
     async fn get_req<T>(url: String) -> Result<T>
     where
         T: DeserializeOwned,
     {
-        let response = reqwest::get(url).await?.json().await?;
-        Ok(response)
+        let response = reqwest::Client::new()
+            .get(url)
+            .header(ACCEPT, BINARY_CONTENT_TYPE)
+            .send()
+            .await?;
+        read_wire(response).await
+    }
+
+    /// Nodes talk to each other in the binary wire format, `frame` is already encoded.
+    pub(super) fn post_frame(
+        client: &reqwest::Client,
+        url: String,
+        frame: Vec<u8>,
+    ) -> reqwest::RequestBuilder {
+        client
+            .post(url)
+            .header(CONTENT_TYPE, BINARY_CONTENT_TYPE)
+            .header(ACCEPT, BINARY_CONTENT_TYPE)
+            .body(frame)
+    }
+
+    pub(super) async fn read_wire<T>(response: reqwest::Response) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let response = response.error_for_status()?;
+        let binary = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.contains(BINARY_CONTENT_TYPE))
+            .unwrap_or(false);
+        let bytes = response.bytes().await?;
+        if binary {
+            decode(&bytes)
+        } else {
+            Ok(serde_json::from_slice(&bytes)?)
+        }
     }
 
     pub(super) fn url_for(addr: &SocketAddr, endpoint: &'static str) -> String {
-        format!("http://{}/{}", addr.to_string(), endpoint)
+        format!("http://{}/{}", addr, endpoint)
     }
 
     pub(super) async fn get_data<T>(addr: &SocketAddr, endpoint: &'static str) -> Result<T>
@@ -40,108 +81,132 @@ mod toolkit {
     }
 }
 
-pub async fn get_bitcoin_value_usd() -> Result<f32> {
-    let response = reqwest::get("https://blockchain.info/ticker")
-        .await?
-        .text()
-        .await?;
-    let map: serde_json::Map<_, _> = serde_json::from_str(&response)?;
-    let usd = map
-        .get("USD")
-        .ok_or(anyhow!("Didn't find USD node in response."))?;
-    let last = usd.get("last").ok_or(anyhow!(
-        "Didn't find 'last' in USD node. Node was: {:?}",
-        usd
-    ))?;
-    match last {
-        serde_json::Value::Number(n) => {
-            let value = n
-                .as_f64()
-                .ok_or(anyhow!("Last number value is not a float."))?;
-            Ok(value as f32)
-        }
-        _ => bail!("Last has invalid type. Expected number, was: {:?}", last),
-    }
-}
-
+/// Forwards the registration the new node signed itself, so every member checks its key.
 pub async fn send_acknowledge_new_node(
-    user: &User,
     client: &reqwest::Client,
-    node: &Node,
+    registration: &Envelope<Node>,
+    own_node: &Node,
     all_nodes: &[Node],
 ) -> Result<()> {
+    let acknowledge = encode(registration)?;
     let mut tasks: FuturesUnordered<_> = all_nodes
         .iter()
-        .filter(|n| n.id != node.id && n.id != user.node.id)
+        .filter(|n| n.id != registration.sender && n.id != own_node.id)
         .map(|n| {
-            client
-                .post(toolkit::url_for(&n.addr, ROUTES.acknowledge_new_node))
-                .json(node)
-                .send()
+            post_frame(
+                client,
+                url_for(&n.addr, ROUTES.acknowledge_new_node),
+                acknowledge.clone(),
+            )
+            .send()
         })
         .collect();
-    while let Some(Err(e)) = tasks.next().await {
-        info!("Received error sending acknowledge to node: {}", e);
+    while let Some(r) = tasks.next().await {
+        if let Err(e) = r.and_then(|r| r.error_for_status()) {
+            info!("Received error sending acknowledge to node: {}", e);
+        }
     }
     Ok(())
 }
 
 fn address_of_previous_node(addr: &SocketAddr) -> SocketAddr {
-    let mut copy = addr.clone();
+    let mut copy = *addr;
     copy.set_port(copy.port() - 1);
     copy
 }
 
-pub async fn register_node(client: reqwest::Client, addr: &SocketAddr, pub_key: &PubKey) -> Result<Vec<Node>> {
-    let register_address = address_of_previous_node(addr);
-    Ok(client
-        .post(url_for(&register_address, ROUTES.register))
-        .json(pub_key)
-        .send()
-        .await?
-        .json()
-        .await?)
+pub async fn register_node(client: reqwest::Client, registration: &Envelope<Node>) -> Result<Vec<Node>> {
+    let register_address = address_of_previous_node(&registration.payload.addr);
+    let response = post_frame(
+        &client,
+        url_for(&register_address, ROUTES.register),
+        encode(registration)?,
+    )
+    .send()
+    .await?;
+    read_wire(response).await
 }
 
 pub async fn get_chain(node: &Node) -> Result<Blockchain> {
     toolkit::get_data(&node.addr, ROUTES.get_chain).await
 }
 
-pub async fn get_pending_transactions(node: &Node) -> Result<Vec<(Transaction, Vec<u8>)>> {
+pub async fn get_pending_transactions(node: &Node) -> Result<Vec<ProvenTransaction>> {
     toolkit::get_data(&node.addr, ROUTES.get_pending_transactions).await
 }
 
-pub async fn send_new_block(client: reqwest::Client, recipients: Vec<Node>, block: &Block) 
+/// Returns ids of the recipients which couldn't be reached.
+pub async fn send_new_block(client: reqwest::Client, recipients: Vec<Node>, block: &Envelope<Block>) -> Result<Vec<NodeId>>
 {
     info!("Sending block to nodes {:?}", recipients.iter().map(|x| x.addr).collect::<Vec<_>>());
+    let client = &client;
+    let frame = &encode(block)?;
     let mut tasks: FuturesUnordered<_> = recipients.iter()
-        .map(|r| url_for(&r.addr, ROUTES.new_block))
-        .map(|url| client
-            .post(url)
-            .json(block)
-            .send())
+        .map(|r| (r.id, url_for(&r.addr, ROUTES.new_block)))
+        .map(|(id, url)| async move {
+            post_frame(client, url, frame.clone())
+                .send()
+                .await
+                .map_err(|e| (id, e))
+        })
         .collect();
+    let mut unreachable = vec![];
     while let Some(r) = tasks.next().await {
-        if let Err(e) = r {
+        if let Err((id, e)) = r {
             info!("Received error sending new block {:?}", e);
+            unreachable.push(id);
         }
     }
     info!("Finished sending");
+    Ok(unreachable)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[tokio::test]
-    async fn test_bitcoin_api() {
-        let value = get_bitcoin_value_usd().await;
+pub async fn send_ping(client: &reqwest::Client, node: &Node) -> Result<()> {
+    let response = client
+        .get(url_for(&node.addr, ROUTES.ping))
+        .header(reqwest::header::ACCEPT, crate::domain::BINARY_CONTENT_TYPE)
+        .send()
+        .await?;
+    let responder: NodeId = read_wire(response).await?;
+    if responder != node.id {
+        bail!(
+            "Node at {} responded as {:?}, expected {:?}",
+            node.addr,
+            responder,
+            node.id
+        )
+    }
+    Ok(())
+}
 
-        assert!(value.is_ok(), "Failed with err {:?}", value);
+pub async fn send_new_transaction(
+    client: &reqwest::Client,
+    transaction: &SignedTransaction,
+    recipients: &[Node],
+) -> Result<()> {
+    let frame = encode(transaction)?;
+    let mut tasks: FuturesUnordered<_> = recipients
+        .iter()
+        .map(|n| post_frame(client, url_for(&n.addr, ROUTES.new_transaction), frame.clone()).send())
+        .collect();
+    while let Some(r) = tasks.next().await {
+        if let Err(e) = r.and_then(|r| r.error_for_status()) {
+            info!("Received error sending transaction: {}", e);
+        }
+    }
+    Ok(())
+}
 
-        assert!(
-            value.unwrap() > 1000.,
-            "Bitcoin cheaper than 1k USD holy cow."
-        ); //i guess it will always be more expensive than this
+pub async fn send_leave(client: &reqwest::Client, leaving: &Envelope<NodeId>, recipients: &[Node]) -> Result<()> {
+    let frame = encode(leaving)?;
+    let mut tasks: FuturesUnordered<_> = recipients
+        .iter()
+        .map(|n| post_frame(client, url_for(&n.addr, ROUTES.leave), frame.clone()).send())
+        .collect();
+    while let Some(r) = tasks.next().await {
+        if let Err(e) = r {
+            info!("Received error announcing leave: {}", e);
+        }
     }
+    Ok(())
 }
@@ -1,8 +1,9 @@
 mod communication;
 mod server;
+mod wire;
 
 pub use communication::{
-    get_bitcoin_value_usd, get_chain, get_pending_transactions, register_node,
-    send_new_block,
+    get_chain, get_pending_transactions, register_node, send_leave,
+    send_new_block, send_new_transaction, send_ping,
 };
-pub use server::run;
+pub use server::{run, AdminToken};
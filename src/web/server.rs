@@ -1,22 +1,43 @@
-use log::{debug, info};
-use serde::Deserialize;
-use std::{fmt::Display, net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
+use futures::{
+    future::{ready, Either},
+    TryFutureExt,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::sync::{Mutex, MutexGuard};
 
 use actix_web::{
+    dev::{Service, ServiceResponse},
+    error::JsonPayloadError,
+    http::header::AUTHORIZATION,
     get, middleware, route,
     web::{self, Data},
     HttpRequest, HttpResponse, Responder,
 };
 
-use anyhow::anyhow;
-
 use crate::{
     domain::{
-        acknowledge_node, try_add_block, try_add_transaction, try_create_node, Block,
-        Network as DomainNetwork, Node, PubKey, Transaction,
+        add_cosignature, cosign_as_node, open, partial_transaction, propose_multisig,
+        remove_node, statement_csv, try_add_block, try_add_transaction, try_register_multisig,
+        try_acknowledge_node, try_register_node, try_register_script, try_send_contract, try_pay,
+        try_send_token, calculate_token_balances, TokenAction,
+        pending_payments, ContractAction, Instr, Block, Cosignature, Cosigned,
+        Envelope, HistoryEntry, Lock, Misbehavior, MultisigPolicy,
+        Network as DomainNetwork, NoCoin, Node, NodeId, Reputation, Script, ScriptTemplate,
+        SignedTransaction, Transaction, TransactionId,
+    },
+    price::PriceOracle,
+    transport::Transport,
+    web::{
+        communication::send_acknowledge_new_node,
+        wire::Wire,
     },
-    web::communication::send_acknowledge_new_node,
 };
 
 #[derive(Debug)]
@@ -37,6 +58,49 @@ impl From<anyhow::Error> for ErrResponse {
 impl actix_web::error::ResponseError for ErrResponse {}
 
 type SNetwork = Data<Mutex<DomainNetwork>>;
+type STransport = Data<dyn Transport>;
+pub(super) type SReputation = Data<std::sync::Mutex<Reputation>>;
+
+/// Peers are told apart by their socket address, nodes of one machine share the ip.
+fn peer_of(req: &HttpRequest) -> Option<SocketAddr> {
+    req.peer_addr()
+}
+
+pub(super) fn penalize(reputation: &SReputation, req: &HttpRequest, misbehavior: Misbehavior) {
+    if let Some(peer) = peer_of(req) {
+        let mut reputation = reputation.lock().unwrap();
+        if reputation.penalize(peer, misbehavior) {
+            info!("Banned peer {} after {:?}", peer, misbehavior);
+        } else {
+            info!(
+                "Penalized peer {} for {:?}, now at {} points",
+                peer,
+                misbehavior,
+                reputation.penalty_of(&peer)
+            );
+        }
+    }
+}
+
+/// Penalizes the peer for `e` unless an honest peer could have caused it.
+fn reject(reputation: &SReputation, req: &HttpRequest, e: &anyhow::Error, otherwise: Misbehavior) {
+    if let Some(misbehavior) = Misbehavior::of(e, otherwise) {
+        penalize(reputation, req, misbehavior);
+    }
+}
+
+fn is_banned(reputation: &SReputation, peer: Option<SocketAddr>) -> bool {
+    peer
+        .map(|peer| reputation.lock().unwrap().is_banned(&peer))
+        .unwrap_or(false)
+}
+
+fn malformed_payload(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    if let Some(reputation) = req.app_data::<SReputation>() {
+        penalize(reputation, req, Misbehavior::MalformedPayload);
+    }
+    err.into()
+}
 
 pub struct Routes {
     pub new_block: &'static str,
@@ -45,6 +109,8 @@ pub struct Routes {
     pub acknowledge_new_node: &'static str,
     pub register: &'static str,
     pub get_pending_transactions: &'static str,
+    pub ping: &'static str,
+    pub leave: &'static str,
 }
 
 pub const ROUTES: Routes = Routes {
@@ -54,80 +120,669 @@ pub const ROUTES: Routes = Routes {
     acknowledge_new_node: "acknowledge_new_node",
     register: "register",
     get_pending_transactions: "get_pending_transactions",
+    ping: "ping",
+    leave: "leave",
 };
 
 #[route("new_block", method = "POST")]
 async fn new_block(
+    req: HttpRequest,
     network: SNetwork,
-    block: web::Json<Block>,
+    reputation: SReputation,
+    block: Wire<Envelope<Block>>,
 ) -> Result<impl Responder, ErrResponse> {
     let mut network = network.lock().await;
-    try_add_block(&mut network, block.0)?;
+    open(&mut network, block.0, None)
+        .and_then(|block| try_add_block(&mut network, block))
+        .inspect_err(|e| {
+            reject(&reputation, &req, e, Misbehavior::InvalidBlock)
+        })?;
     Ok(HttpResponse::Ok())
 }
 
 #[route("get_chain", method = "GET")]
 async fn get_chain(network: SNetwork) -> impl Responder {
     let network = network.lock().await;
-    serde_json::to_string(&network.blockchain)
+    Wire(network.blockchain.clone())
 }
 
 #[get("get_pending_transactions")]
 async fn get_pending_transactions(network: SNetwork) -> impl Responder {
     let network = network.lock().await;
-    serde_json::to_string(&network.transactions_poll)
+    Wire(network.transactions_poll.clone())
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Deserialize)]
+struct Page {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct StatementPage {
+    address: NodeId,
+    total: usize,
+    page: usize,
+    per_page: usize,
+    entries: Vec<HistoryEntry>,
+}
+
+async fn statement_of(network: &SNetwork, address: NodeId) -> Vec<HistoryEntry> {
+    let mut network = network.lock().await;
+    let network = &mut *network;
+    network.cache.history.sync(&network.blockchain);
+    network.cache.history.statement(&address, &network.blockchain)
+}
+
+/// Payments to `address` still in the poll, including locked ones.
+#[get("pending/{address}")]
+async fn pending(address: web::Path<usize>, network: SNetwork) -> Result<impl Responder, ErrResponse> {
+    let network = network.lock().await;
+    Ok(Wire(pending_payments(&network, &NodeId(address.into_inner()))?))
+}
+
+/// Oldest entries first, `page` counts from 0.
+#[get("history/{address}")]
+async fn history(
+    address: web::Path<usize>,
+    page: web::Query<Page>,
+    network: SNetwork,
+) -> impl Responder {
+    let address = NodeId(address.into_inner());
+    let statement = statement_of(&network, address).await;
+    let per_page = page.per_page.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let page = page.page.unwrap_or(0);
+    Wire(StatementPage {
+        address,
+        total: statement.len(),
+        page,
+        per_page,
+        entries: statement
+            .into_iter()
+            .skip(page.saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    })
+}
+
+#[get("history/{address}/csv")]
+async fn history_csv(address: web::Path<usize>, network: SNetwork) -> impl Responder {
+    let address = address.into_inner();
+    let statement = statement_of(&network, NodeId(address)).await;
+    HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"statement-{}.csv\"", address),
+        ))
+        .body(statement_csv(&statement))
+}
+
+#[derive(Serialize)]
+struct Submitted {
+    id: TransactionId,
 }
 
 #[route("new_transaction", method = "POST")]
 async fn new_transaction(
+    submission: Wire<SignedTransaction>,
+    req: HttpRequest,
+    network: SNetwork,
+    reputation: SReputation,
+) -> Result<impl Responder, ErrResponse> {
+    let SignedTransaction { transaction, proof } = submission.0;
+    let mut network = network.lock().await;
+    let id = try_add_transaction(&mut network, transaction, proof).inspect_err(|e| {
+        reject(&reputation, &req, e, Misbehavior::InvalidTransaction)
+    })?;
+    Ok(Wire(Submitted { id }))
+}
+
+/// Token guarding the endpoints which spend the node's own coins.
+pub struct AdminToken(pub String);
+
+/// Only callers on this machine presenting the token as `Authorization: Bearer <token>`.
+fn is_admin(req: &HttpRequest, token: &AdminToken) -> bool {
+    let local = req.peer_addr().map(|a| a.ip().is_loopback()).unwrap_or(false);
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    local
+        && presented
+            .map(|p| bool::from(p.as_bytes().ct_eq(token.0.as_bytes())))
+            .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+struct PaymentOrder {
+    to: NodeId,
+    ammount: NoCoin,
+    fee: NoCoin,
+    lock: Option<Lock>,
+}
+
+/// A wallet pays with one transaction per account it draws from.
+#[derive(Serialize)]
+struct Paid {
+    ids: Vec<TransactionId>,
+}
+
+#[route("send", method = "POST")]
+async fn send(
+    req: HttpRequest,
+    order: web::Json<PaymentOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let mut network = network.lock().await;
+    let (ids, signed): (Vec<_>, Vec<_>) =
+        try_pay(&mut network, order.to, order.ammount, order.fee, order.lock)?.into_iter().unzip();
+    info!("Sending payments {:?}", ids);
+    gossip(network, &transport, &signed).await?;
+    Ok(HttpResponse::Ok().json(Paid { ids }))
+}
+
+#[derive(Deserialize)]
+struct MultisigOrder {
+    policy: MultisigPolicy,
+    ammount: NoCoin,
+    fee: NoCoin,
+}
+
+#[derive(Serialize)]
+struct Registered {
+    address: NodeId,
+    id: TransactionId,
+}
+
+#[derive(Serialize)]
+struct CosignStatus {
+    id: TransactionId,
+    collected: usize,
+    threshold: u8,
+    complete: bool,
+}
+
+/// Network is unlocked before anything goes out.
+async fn gossip(
+    network: MutexGuard<'_, DomainNetwork>,
+    transport: &STransport,
+    signed: &[SignedTransaction],
+) -> anyhow::Result<()> {
+    let other_nodes: Vec<_> = network.other_nodes().cloned().collect();
+    drop(network);
+    info!("Sending {} transactions to {} nodes", signed.len(), other_nodes.len());
+    for transaction in signed {
+        transport.broadcast_transaction(&other_nodes, transaction).await?;
+    }
+    Ok(())
+}
+
+/// Node funds the new account, so registering is as protected as sending.
+#[route("multisig/register", method = "POST")]
+async fn register_multisig(
+    req: HttpRequest,
+    order: web::Json<MultisigOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let MultisigOrder { policy, ammount, fee } = order.into_inner();
+    let mut network = network.lock().await;
+    let (address, id, signed) = try_register_multisig(&mut network, policy, ammount, fee)?;
+    info!("Registering multisig account {:?} in {:?}", address, id);
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Registered { address, id }))
+}
+
+/// Either a script of its own or one of the standard ones.
+#[derive(Deserialize)]
+struct ScriptOrder {
+    script: Option<Script>,
+    template: Option<ScriptTemplate>,
+    ammount: NoCoin,
+    fee: NoCoin,
+}
+
+#[route("script/register", method = "POST")]
+async fn register_script(
+    req: HttpRequest,
+    order: web::Json<ScriptOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let ScriptOrder { script, template, ammount, fee } = order.into_inner();
+    let script = match (script, template) {
+        (Some(script), None) => script,
+        (None, Some(template)) => template.build()?,
+        _ => return Ok(HttpResponse::BadRequest().body("Give either a script or a template")),
+    };
+    let mut network = network.lock().await;
+    let (address, id, signed) = try_register_script(&mut network, script, ammount, fee)?;
+    info!("Registering script account {:?} in {:?}", address, id);
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Registered { address, id }))
+}
+
+#[derive(Deserialize)]
+struct DeployOrder {
+    code: Vec<Instr>,
+    fee: NoCoin,
+}
+
+#[derive(Deserialize)]
+struct CallOrder {
+    input: Vec<i64>,
+    ammount: NoCoin,
+    fee: NoCoin,
+}
+
+#[route("contract/deploy", method = "POST")]
+async fn deploy_contract(
+    req: HttpRequest,
+    order: web::Json<DeployOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let DeployOrder { code, fee } = order.into_inner();
+    let mut network = network.lock().await;
+    let self_id = network.user.node.id;
+    let action = ContractAction::Deploy { code };
+    let (id, signed) = try_send_contract(&mut network, self_id, action, NoCoin(0.), fee)?;
+    let address = signed.transaction.to;
+    info!("Deploying contract {:?} in {:?}", address, id);
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Registered { address, id }))
+}
+
+#[route("contract/{address}/call", method = "POST")]
+async fn call_contract(
+    req: HttpRequest,
+    address: web::Path<usize>,
+    order: web::Json<CallOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let CallOrder { input, ammount, fee } = order.into_inner();
+    let mut network = network.lock().await;
+    let action = ContractAction::Call { input };
+    let (id, signed) = try_send_contract(&mut network, NodeId(address.into_inner()), action, ammount, fee)?;
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Submitted { id }))
+}
+
+#[get("contract/{address}/storage/{key}")]
+async fn contract_storage(
+    path: web::Path<(usize, i64)>,
+    network: SNetwork,
+) -> Result<HttpResponse, ErrResponse> {
+    let (address, key) = path.into_inner();
+    let mut network = network.lock().await;
+    let network = &mut *network;
+    network.cache.contracts.sync(&network.blockchain)?;
+    Ok(match network.cache.contracts.storage(&NodeId(address), key) {
+        Some(value) => HttpResponse::Ok().json(value),
+        None => HttpResponse::NotFound().body("No contract at this address"),
+    })
+}
+
+#[derive(Serialize)]
+struct ContractState {
+    height: usize,
+    state_root: String,
+}
+
+/// Nodes at the same height agree on the state root unless their contracts diverged.
+#[get("contracts/state")]
+async fn contract_state(network: SNetwork) -> Result<HttpResponse, ErrResponse> {
+    let mut network = network.lock().await;
+    let network = &mut *network;
+    network.cache.contracts.sync(&network.blockchain)?;
+    Ok(HttpResponse::Ok().json(ContractState {
+        height: network.blockchain.height(),
+        state_root: network.cache.contracts.state_root()?,
+    }))
+}
+
+/// Exists once the contract transaction is in a block.
+#[get("receipt/{id}")]
+async fn receipt(id: web::Path<String>, network: SNetwork) -> Result<HttpResponse, ErrResponse> {
+    let mut network = network.lock().await;
+    let network = &mut *network;
+    network.cache.contracts.sync(&network.blockchain)?;
+    Ok(match network.cache.contracts.receipt(&TransactionId(id.into_inner())) {
+        Some(receipt) => HttpResponse::Ok().json(receipt),
+        None => HttpResponse::NotFound().body("No receipt for this transaction"),
+    })
+}
+
+#[derive(Deserialize)]
+struct IssueOrder {
+    symbol: String,
+    name: String,
+    decimals: u8,
+    supply: u64,
+    fee: NoCoin,
+}
+
+#[derive(Deserialize)]
+struct TokenOrder {
+    to: Option<NodeId>,
+    ammount: u64,
+    fee: NoCoin,
+}
+
+#[route("token/issue", method = "POST")]
+async fn issue_token(
+    req: HttpRequest,
+    order: web::Json<IssueOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let IssueOrder { symbol, name, decimals, supply, fee } = order.into_inner();
+    let mut network = network.lock().await;
+    let self_id = network.user.node.id;
+    let action = TokenAction::Issue { symbol, name, decimals, supply };
+    let (id, signed) = try_send_token(&mut network, self_id, action, fee)?;
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Submitted { id }))
+}
+
+/// Transfers to `to`, or burns the node's own units when `to` is missing.
+#[route("token/{symbol}/send", method = "POST")]
+async fn send_token(
+    req: HttpRequest,
+    symbol: web::Path<String>,
+    order: web::Json<TokenOrder>,
+    network: SNetwork,
+    transport: STransport,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let TokenOrder { to, ammount, fee } = order.into_inner();
+    let symbol = symbol.into_inner();
+    let mut network = network.lock().await;
+    let (to, action) = match to {
+        Some(to) => (to, TokenAction::Transfer { symbol, ammount }),
+        None => (network.user.node.id, TokenAction::Burn { symbol, ammount }),
+    };
+    let (id, signed) = try_send_token(&mut network, to, action, fee)?;
+    gossip(network, &transport, &[signed]).await?;
+    Ok(HttpResponse::Ok().json(Submitted { id }))
+}
+
+#[get("token/{symbol}")]
+async fn token_info(symbol: web::Path<String>, network: SNetwork) -> impl Responder {
+    let network = network.lock().await;
+    match calculate_token_balances(&network.blockchain).token(&symbol) {
+        Some(token) => HttpResponse::Ok().json(token),
+        None => HttpResponse::NotFound().body("No token with this symbol"),
+    }
+}
+
+#[derive(Serialize)]
+struct TokenBalance {
+    symbol: String,
+    units: u64,
+    /// Units with the token's decimal point.
+    balance: String,
+}
+
+#[get("tokens/{address}")]
+async fn tokens(address: web::Path<usize>, network: SNetwork) -> impl Responder {
+    let network = network.lock().await;
+    let balances = calculate_token_balances(&network.blockchain);
+    let held: Vec<_> = balances
+        .of(&NodeId(address.into_inner()))
+        .into_iter()
+        .filter_map(|(symbol, units)| {
+            let balance = balances.token(&symbol)?.format(units);
+            Some(TokenBalance { symbol, units, balance })
+        })
+        .collect();
+    HttpResponse::Ok().json(held)
+}
+
+/// Bitcoin's price from the node's feed and NoCoin's simulated value with it.
+#[get("price")]
+async fn price(prices: Data<PriceOracle>) -> Result<HttpResponse, ErrResponse> {
+    Ok(HttpResponse::Ok().json(prices.quote().await?))
+}
+
+#[route("multisig/propose", method = "POST")]
+async fn propose(
+    req: HttpRequest,
+    transaction: web::Json<Transaction>,
+    network: SNetwork,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let mut network = network.lock().await;
+    let id = propose_multisig(&mut network, transaction.into_inner())?;
+    Ok(HttpResponse::Ok().json(Submitted { id }))
+}
+
+#[get("multisig/{id}")]
+async fn proposal(id: web::Path<String>, network: SNetwork) -> HttpResponse {
+    let network = network.lock().await;
+    match partial_transaction(&network, &TransactionId(id.into_inner())) {
+        Some(partial) => HttpResponse::Ok().json(partial),
+        None => HttpResponse::NotFound().body("No transaction waits for signatures"),
+    }
+}
+
+/// Node signs a proposal fetched from the proposer's node with its own key,
+/// the co-signature goes back to the proposer's `multisig/{id}/cosign`.
+#[route("multisig/sign", method = "POST")]
+async fn sign_proposal(
+    req: HttpRequest,
     transaction: web::Json<Transaction>,
-    proof: web::Json<Vec<u8>>,
     network: SNetwork,
+    admin: Data<AdminToken>,
+) -> Result<HttpResponse, ErrResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().body("Admin token required"));
+    }
+    let network = network.lock().await;
+    Ok(HttpResponse::Ok().json(cosign_as_node(&network, &transaction)?))
+}
+
+/// Co-signatures prove themselves, anyone may deliver them. The transaction
+/// is gossiped as soon as the last one needed arrives.
+#[route("multisig/{id}/cosign", method = "POST")]
+async fn add_cosign(
+    id: web::Path<String>,
+    cosignature: web::Json<Cosignature>,
+    network: SNetwork,
+    transport: STransport,
 ) -> Result<impl Responder, ErrResponse> {
+    let id = TransactionId(id.into_inner());
     let mut network = network.lock().await;
-    try_add_transaction(&mut network, transaction.0, proof.0)?;
-    Ok(HttpResponse::Ok())
+    let threshold = partial_transaction(&network, &id).map(|p| p.threshold).unwrap_or(0);
+    let status = match add_cosignature(&mut network, &id, cosignature.into_inner())? {
+        Cosigned::Pending(partial) => CosignStatus {
+            id,
+            collected: partial.cosignatures.len(),
+            threshold,
+            complete: false,
+        },
+        Cosigned::Complete(id, signed) => {
+            info!("Collected all signatures of {:?}", id);
+            gossip(network, &transport, &[signed]).await?;
+            CosignStatus {
+                id,
+                collected: threshold as usize,
+                threshold,
+                complete: true,
+            }
+        }
+    };
+    Ok(web::Json(status))
 }
 
 #[route("acknowledge_new_node", method = "POST")]
 async fn acknowledge_new_node(
-    node: web::Json<Node>,
+    req: HttpRequest,
+    registration: Wire<Envelope<Node>>,
     network: SNetwork,
+    reputation: SReputation,
 ) -> Result<impl Responder, ErrResponse> {
     let mut network = network.lock().await;
-    acknowledge_node(&mut network, node.0)?;
+    try_acknowledge_node(&mut network, registration.0)
+        .inspect_err(|e| {
+            reject(&reputation, &req, e, Misbehavior::MalformedPayload)
+        })?;
     Ok(HttpResponse::Ok())
 }
 
 #[route("register", method = "POST")]
 async fn register(
-    key: web::Json<PubKey>,
+    req: HttpRequest,
+    registration: Wire<Envelope<Node>>,
     network: SNetwork,
     client: Data<reqwest::Client>,
+    reputation: SReputation,
 ) -> Result<impl Responder, ErrResponse> {
     let mut network = network.lock().await;
-    let address = SocketAddr::from(([127, 0, 0, 1], 8101));
-    let node = try_create_node(&mut network, address, key.0)?.clone();
+    let registration = registration.0;
+    let node = try_register_node(&mut network, registration.clone())
+        .inspect_err(|e| {
+            reject(&reputation, &req, e, Misbehavior::MalformedPayload)
+        })?
+        .clone();
     info!("Created node with id: {:?}", node.id);
-    info!("Sending acknowledges: {:?}", send_acknowledge_new_node(&network.user, client.as_ref(), &node, &network.nodes).await);
-    info!("Sending back {:?}", network.nodes.len());
-    Ok(web::Json(network.nodes.clone()))
+    let (own_node, nodes) = (network.user.node.clone(), network.nodes.clone());
+    drop(network);
+    info!("Sending acknowledges: {:?}", send_acknowledge_new_node(client.as_ref(), &registration, &own_node, &nodes).await);
+    info!("Sending back {:?}", nodes.len());
+    Ok(Wire(nodes))
+}
+
+#[get("ping")]
+async fn ping(network: SNetwork) -> impl Responder {
+    let network = network.lock().await;
+    Wire(network.user.node.id)
+}
+
+#[route("leave", method = "POST")]
+async fn leave(
+    req: HttpRequest,
+    leaving: Wire<Envelope<NodeId>>,
+    network: SNetwork,
+    reputation: SReputation,
+) -> Result<impl Responder, ErrResponse> {
+    let mut network = network.lock().await;
+    let sender = leaving.0.sender;
+    let node = open(&mut network, leaving.0, None)
+        .and_then(|id| {
+            if id != sender {
+                Err(anyhow::anyhow!("Node {:?} can't announce leave of {:?}", sender, id))
+            } else {
+                remove_node(&mut network, &id)
+            }
+        })
+        .inspect_err(|e| {
+            reject(&reputation, &req, e, Misbehavior::MalformedPayload)
+        })?;
+    info!("Node {:?} left the network", node.id);
+    Ok(HttpResponse::Ok())
 }
 
-pub async fn run(addr: SocketAddr, network: Arc<Mutex<DomainNetwork>>) -> anyhow::Result<()> {
+pub async fn run(
+    addr: SocketAddr,
+    network: Arc<Mutex<DomainNetwork>>,
+    reputation: Arc<std::sync::Mutex<Reputation>>,
+    transport: Arc<dyn Transport>,
+    admin: AdminToken,
+    prices: Arc<PriceOracle>,
+) -> anyhow::Result<()> {
     log::info!("Starting server on {:?}", addr);
     let network = Data::from(network);
+    let transport: STransport = Data::from(transport);
+    let admin = Data::new(admin);
+    let prices = Data::from(prices);
     let client = Data::new(reqwest::Client::new());
+    let reputation: SReputation = Data::from(reputation);
     actix_web::HttpServer::new(move || {
+        let banned_check = reputation.clone();
         actix_web::App::new()
             .app_data(network.clone())
             .app_data(client.clone())
+            .app_data(reputation.clone())
+            .app_data(transport.clone())
+            .app_data(admin.clone())
+            .app_data(prices.clone())
+            .app_data(web::JsonConfig::default().error_handler(malformed_payload))
             .service(new_transaction)
+            .service(self::send)
+            .service(self::register_multisig)
+            .service(self::register_script)
+            .service(self::deploy_contract)
+            .service(self::call_contract)
+            .service(self::contract_storage)
+            .service(self::contract_state)
+            .service(self::receipt)
+            .service(self::issue_token)
+            .service(self::send_token)
+            .service(self::token_info)
+            .service(self::tokens)
+            .service(self::price)
+            .service(self::propose)
+            .service(self::proposal)
+            .service(self::sign_proposal)
+            .service(self::add_cosign)
             .service(register)
             .service(acknowledge_new_node)
             .service(self::new_block)
             .service(self::get_chain)
             .service(self::get_pending_transactions)
+            .service(self::ping)
+            .service(self::leave)
+            .service(self::history)
+            .service(self::pending)
+            .service(self::history_csv)
+            .wrap_fn(move |req, srv| {
+                if is_banned(&banned_check, req.peer_addr()) {
+                    let response = HttpResponse::Forbidden()
+                        .body("Peer is banned")
+                        .map_into_right_body();
+                    Either::Left(ready(Ok(req.into_response(response))))
+                } else {
+                    Either::Right(srv.call(req).map_ok(ServiceResponse::map_into_left_body))
+                }
+            })
             .wrap(middleware::Logger::default())
     })
     .bind(addr)?
@@ -0,0 +1,72 @@
+use actix_web::{
+    body::BoxBody,
+    dev::Payload,
+    error::ErrorBadRequest,
+    http::header::{HeaderMap, HeaderName, ACCEPT, CONTENT_TYPE},
+    web, FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::future::LocalBoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::domain::{decode, encode, Misbehavior, BINARY_CONTENT_TYPE};
+
+use super::server::{penalize, SReputation};
+
+/// Body which is read and written either as JSON or as the binary wire format,
+/// depending on `Content-Type` of the request and `Accept` of the caller.
+pub struct Wire<T>(pub T);
+
+fn is_binary(headers: &HeaderMap, header: HeaderName) -> bool {
+    headers
+        .get(header)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.contains(BINARY_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+impl<T> FromRequest for Wire<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if is_binary(req.headers(), CONTENT_TYPE) {
+            let bytes = web::Bytes::from_request(req, payload);
+            let req = req.clone();
+            Box::pin(async move {
+                let bytes = bytes.await?;
+                decode(&bytes).map(Wire).map_err(|e| {
+                    if let Some(reputation) = req.app_data::<SReputation>() {
+                        penalize(reputation, &req, Misbehavior::MalformedPayload);
+                    }
+                    ErrorBadRequest(e)
+                })
+            })
+        } else {
+            let json = web::Json::<T>::from_request(req, payload);
+            Box::pin(async move { json.await.map(|json| Wire(json.0)) })
+        }
+    }
+}
+
+impl<T> Responder for Wire<T>
+where
+    T: Serialize,
+{
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        if is_binary(req.headers(), ACCEPT) {
+            match encode(&self.0) {
+                Ok(bytes) => HttpResponse::Ok()
+                    .content_type(BINARY_CONTENT_TYPE)
+                    .body(bytes),
+                Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+            }
+        } else {
+            HttpResponse::Ok().json(&self.0)
+        }
+    }
+}